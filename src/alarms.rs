@@ -0,0 +1,1020 @@
+use crate::{Link, LinkStatus, TagIndex, TagRef, TagStatus};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+pub const ALARMS_CONFIG_PATH: &str = "./CurrentConfig/alarms_config.json";
+// How often the alarm task checks the tags.
+pub const ALARM_SCAN_MS: u64 = 500;
+// Events a slow subscriber can fall behind by before it starts missing them.
+pub const ALARM_EVENT_CAPACITY: usize = 1024;
+pub const ALARM_JOURNAL_DIR: &str = "./Alarms";
+pub const ALARM_JOURNAL_MAX_FILE_BYTES: u64 = 10 * 1024 * 1024;
+pub const ALARM_JOURNAL_MAX_FILES: usize = 10;
+// Longest an operator can shelve an alarm for.
+pub const ALARM_MAX_SHELVE_MINUTES: f64 = 24.0 * 60.0;
+// Unknown tag keys retry the lookup at most this often.
+const ALARM_INDEX_RETRY: Duration = Duration::from_secs(10);
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum AlarmPriority {
+    Low,
+    #[default]
+    Medium,
+    High,
+    Critical,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AlarmKind {
+    HighHigh,
+    High,
+    Low,
+    LowLow,
+    Discrete,
+    Deviation,
+    Rate,
+    Stale,
+}
+
+// Condition of a discrete alarm on a bit tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiscreteAlarm {
+    OnTrue,
+    OnFalse,
+    // Active for one scan after every change, stays listed until acknowledged.
+    OnChange,
+}
+
+// Alarm when the tag is `limit` or more away from the value of `setpoint_tk`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviationAlarm {
+    pub setpoint_tk: String,
+    pub limit: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RatePeriod {
+    #[default]
+    Second,
+    Minute,
+}
+
+// Alarm when the value changes by `limit` or more per period, either way.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RateAlarm {
+    pub limit: f64,
+    #[serde(default)]
+    pub per: RatePeriod,
+}
+
+// Alarms of one tag.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AlarmDefinition {
+    pub tk: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub hh: Option<f64>,
+    #[serde(default)]
+    pub h: Option<f64>,
+    #[serde(default)]
+    pub l: Option<f64>,
+    #[serde(default)]
+    pub ll: Option<f64>,
+    #[serde(default)]
+    pub discrete: Option<DiscreteAlarm>,
+    #[serde(default)]
+    pub deviation: Option<DeviationAlarm>,
+    #[serde(default)]
+    pub rate: Option<RateAlarm>,
+    // Alarm when the value hasn't changed, or the link hasn't polled, for this long.
+    #[serde(default)]
+    pub stale_minutes: Option<f64>,
+    // How far back past a limit or deviation the value has to go before the alarm clears.
+    #[serde(default)]
+    pub deadband: f64,
+    // How long the condition has to hold before the alarm activates or clears.
+    #[serde(default)]
+    pub on_delay_ms: u64,
+    #[serde(default)]
+    pub off_delay_ms: u64,
+    #[serde(default)]
+    pub priority: AlarmPriority,
+    // Plant area, used to route notifications.
+    #[serde(default)]
+    pub area: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AlarmConfig {
+    pub alarms: Vec<AlarmDefinition>,
+    // Links under maintenance, their tags don't raise alarms.
+    #[serde(default)]
+    pub maintenance_links: Vec<usize>,
+    // Hold the alarms of a device link's tags while the link is in Error.
+    #[serde(default = "default_enabled")]
+    pub suppress_on_link_error: bool,
+}
+
+impl Default for AlarmConfig {
+    fn default() -> Self {
+        Self {
+            alarms: Vec::new(),
+            maintenance_links: Vec::new(),
+            suppress_on_link_error: true,
+        }
+    }
+}
+
+// State of one alarm condition, e.g. the HH limit of a tag.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    pub id: String,
+    pub tk: String,
+    pub link_id: usize,
+    pub tag_id: usize,
+    pub kind: AlarmKind,
+    pub priority: AlarmPriority,
+    pub area: String,
+    pub message: String,
+    pub limit: f64,
+    // What was checked against the limit: the value, the deviation, the rate
+    // or the minutes since the last change.
+    pub value: f64,
+    pub active: bool,
+    pub acked: bool,
+    pub activated_at: Option<DateTime<Utc>>,
+    pub cleared_at: Option<DateTime<Utc>>,
+    pub acked_at: Option<DateTime<Utc>>,
+    pub acked_by: Option<String>,
+    // Shelved alarms are not evaluated or listed until the time passes.
+    pub shelved_until: Option<DateTime<Utc>>,
+    pub shelved_by: Option<String>,
+    // The link is under maintenance or in Error.
+    pub suppressed: bool,
+    // Since when the condition differs from `active`, for the on/off delays.
+    #[serde(skip)]
+    pending_since: Option<Instant>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AlarmTransition {
+    Activated,
+    Cleared,
+    Acknowledged,
+    Shelved,
+    Unshelved,
+    Suppressed,
+    Unsuppressed,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AlarmEvent {
+    pub timestamp: DateTime<Utc>,
+    pub transition: AlarmTransition,
+    pub user: Option<String>,
+    // The alarm after the transition.
+    pub alarm: Alarm,
+}
+
+// Filter for the alarm journal. Every field is optional.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AlarmHistoryFilter {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub alarm_id: Option<String>,
+    pub tk: Option<String>,
+    pub area: Option<String>,
+    pub transition: Option<AlarmTransition>,
+    pub min_priority: Option<AlarmPriority>,
+    pub user: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl AlarmHistoryFilter {
+    pub fn matches(&self, event: &AlarmEvent) -> bool {
+        let alarm = &event.alarm;
+        self.from.is_none_or(|from| event.timestamp >= from)
+            && self.to.is_none_or(|to| event.timestamp < to)
+            && self.alarm_id.as_ref().is_none_or(|id| alarm.id == *id)
+            && self.tk.as_ref().is_none_or(|tk| alarm.tk == *tk)
+            && self.area.as_ref().is_none_or(|area| alarm.area == *area)
+            && self
+                .transition
+                .is_none_or(|transition| event.transition == transition)
+            && self
+                .min_priority
+                .is_none_or(|priority| alarm.priority >= priority)
+            && self
+                .user
+                .as_ref()
+                .is_none_or(|user| event.user.as_ref() == Some(user))
+    }
+}
+
+/*
+ * Evaluates the alarm definitions against the tag values and keeps the alarm
+ * states. Sets the tag status of alarmed tags to Alarm (HH, LL, discrete) or
+ * Warn (the others), unless the tag is in Error.
+ */
+#[derive(Debug, Default)]
+pub struct AlarmEngine {
+    config: AlarmConfig,
+    // By alarm id.
+    alarms: BTreeMap<String, Alarm>,
+    index: TagIndex,
+    index_built: Option<Instant>,
+    // Value of each tag at the last scan, for discrete changes and rates.
+    samples: HashMap<String, (f64, Instant)>,
+    // Tags that never changed count as changed at this time for stale alarms.
+    started: Option<DateTime<Utc>>,
+    // Tags whose status the engine set to Warn or Alarm.
+    flagged: HashSet<String>,
+    // Flagged tags that lost their definition and need their status reset.
+    unflag: Vec<String>,
+}
+
+impl AlarmKind {
+    pub const ALL: [AlarmKind; 8] = [
+        AlarmKind::HighHigh,
+        AlarmKind::High,
+        AlarmKind::Low,
+        AlarmKind::LowLow,
+        AlarmKind::Discrete,
+        AlarmKind::Deviation,
+        AlarmKind::Rate,
+        AlarmKind::Stale,
+    ];
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AlarmKind::HighHigh => "HH",
+            AlarmKind::High => "H",
+            AlarmKind::Low => "L",
+            AlarmKind::LowLow => "LL",
+            AlarmKind::Discrete => "DI",
+            AlarmKind::Deviation => "DEV",
+            AlarmKind::Rate => "ROC",
+            AlarmKind::Stale => "STALE",
+        }
+    }
+
+    // Tag status while the alarm is active.
+    pub fn status(&self) -> TagStatus {
+        match self {
+            AlarmKind::HighHigh | AlarmKind::LowLow | AlarmKind::Discrete => TagStatus::Alarm,
+            _ => TagStatus::Warn,
+        }
+    }
+}
+
+impl AlarmDefinition {
+    // The limit of an alarm kind, None when the tag doesn't have that alarm.
+    pub fn limit(&self, kind: AlarmKind) -> Option<f64> {
+        match kind {
+            AlarmKind::HighHigh => self.hh,
+            AlarmKind::High => self.h,
+            AlarmKind::Low => self.l,
+            AlarmKind::LowLow => self.ll,
+            AlarmKind::Discrete => self.discrete.map(|_| 0.0),
+            AlarmKind::Deviation => self.deviation.as_ref().map(|deviation| deviation.limit),
+            AlarmKind::Rate => self.rate.as_ref().map(|rate| rate.limit),
+            AlarmKind::Stale => self.stale_minutes,
+        }
+    }
+}
+
+// Over the limit, or still within the deadband of it while active.
+fn exceeds(value: f64, limit: f64, deadband: f64, active: bool) -> bool {
+    if active {
+        value > limit - deadband
+    } else {
+        value >= limit
+    }
+}
+
+// What the alarm engine reads of a tag in one scan.
+struct Sample {
+    value: f64,
+    previous: Option<(f64, Instant)>,
+    setpoint: Option<f64>,
+    // Minutes since the value changed or the link polled, whichever is longer.
+    stale_minutes: f64,
+}
+
+pub fn alarm_id(tk: &str, kind: AlarmKind) -> String {
+    format!("{tk}:{}", kind.code())
+}
+
+impl Alarm {
+    fn new(definition: &AlarmDefinition, kind: AlarmKind, limit: f64) -> Self {
+        Self {
+            id: alarm_id(&definition.tk, kind),
+            tk: definition.tk.clone(),
+            link_id: 0,
+            tag_id: 0,
+            kind,
+            priority: definition.priority,
+            area: definition.area.clone(),
+            message: definition.message.clone(),
+            limit,
+            value: 0.0,
+            active: false,
+            acked: true,
+            activated_at: None,
+            cleared_at: None,
+            acked_at: None,
+            acked_by: None,
+            shelved_until: None,
+            shelved_by: None,
+            suppressed: false,
+            pending_since: None,
+        }
+    }
+
+    // Shown in the alarm list until it is both cleared and acknowledged.
+    pub fn is_open(&self) -> bool {
+        self.active || !self.acked
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.suppressed || self.shelved_until.is_some()
+    }
+
+    // The checked value and whether the condition holds, None to hold the state.
+    fn check(
+        &self,
+        definition: &AlarmDefinition,
+        sample: &Sample,
+        now: Instant,
+    ) -> Option<(f64, bool)> {
+        let deadband = definition.deadband.abs();
+        let value = sample.value;
+        let check = match self.kind {
+            AlarmKind::HighHigh | AlarmKind::High => {
+                (value, exceeds(value, self.limit, deadband, self.active))
+            }
+            AlarmKind::Low | AlarmKind::LowLow => {
+                (value, exceeds(-value, -self.limit, deadband, self.active))
+            }
+            AlarmKind::Discrete => {
+                let condition = match definition.discrete? {
+                    DiscreteAlarm::OnTrue => value != 0.0,
+                    DiscreteAlarm::OnFalse => value == 0.0,
+                    DiscreteAlarm::OnChange => sample
+                        .previous
+                        .is_some_and(|(previous, _)| previous != value),
+                };
+                (value, condition)
+            }
+            AlarmKind::Deviation => {
+                let deviation = (value - sample.setpoint?).abs();
+                (
+                    deviation,
+                    exceeds(deviation, self.limit, deadband, self.active),
+                )
+            }
+            AlarmKind::Rate => {
+                let (previous, time) = sample.previous?;
+                let seconds = now.duration_since(time).as_secs_f64();
+                if seconds <= 0.0 {
+                    return None;
+                }
+                let per = match definition.rate.as_ref()?.per {
+                    RatePeriod::Second => 1.0,
+                    RatePeriod::Minute => 60.0,
+                };
+                let rate = (value - previous).abs() / seconds * per;
+                (rate, rate >= self.limit)
+            }
+            AlarmKind::Stale => (sample.stale_minutes, sample.stale_minutes >= self.limit),
+        };
+        Some(check)
+    }
+
+    fn update(
+        &mut self,
+        value: f64,
+        condition: bool,
+        definition: &AlarmDefinition,
+        now: Instant,
+    ) -> Option<AlarmTransition> {
+        self.value = value;
+        if condition == self.active {
+            self.pending_since = None;
+            return None;
+        }
+        let since = *self.pending_since.get_or_insert(now);
+        let delay = if condition {
+            definition.on_delay_ms
+        } else {
+            definition.off_delay_ms
+        };
+        if now.duration_since(since) < Duration::from_millis(delay) {
+            return None;
+        }
+        self.pending_since = None;
+        self.active = condition;
+        if condition {
+            self.activated_at = Some(Utc::now());
+            self.cleared_at = None;
+            self.acked = false;
+            self.acked_at = None;
+            self.acked_by = None;
+            Some(AlarmTransition::Activated)
+        } else {
+            self.cleared_at = Some(Utc::now());
+            Some(AlarmTransition::Cleared)
+        }
+    }
+
+    fn event(&self, transition: AlarmTransition, user: Option<String>) -> AlarmEvent {
+        AlarmEvent {
+            timestamp: Utc::now(),
+            transition,
+            user,
+            alarm: self.clone(),
+        }
+    }
+}
+
+impl AlarmEngine {
+    pub fn config(&self) -> AlarmConfig {
+        self.config.clone()
+    }
+
+    /*
+     * Replace the alarm definitions. Alarms of limits that still exist keep
+     * their state, the others are dropped.
+     */
+    pub fn set_config(&mut self, config: AlarmConfig) {
+        let mut defined = HashSet::new();
+        let mut defined_tks = HashSet::new();
+        for definition in config.alarms.iter().filter(|d| d.enabled) {
+            defined_tks.insert(definition.tk.clone());
+            for kind in AlarmKind::ALL {
+                let Some(limit) = definition.limit(kind) else {
+                    continue;
+                };
+                let id = alarm_id(&definition.tk, kind);
+                if let Some(alarm) = self.alarms.get_mut(&id) {
+                    alarm.priority = definition.priority;
+                    alarm.area = definition.area.clone();
+                    alarm.message = definition.message.clone();
+                    alarm.limit = limit;
+                }
+                defined.insert(id);
+            }
+        }
+        self.alarms.retain(|id, _| defined.contains(id));
+        let unflag: Vec<String> = self
+            .flagged
+            .iter()
+            .filter(|tk| !defined_tks.contains(*tk))
+            .cloned()
+            .collect();
+        for tk in unflag.iter() {
+            self.flagged.remove(tk);
+        }
+        self.unflag.extend(unflag);
+        self.config = config;
+    }
+
+    // Active and unacknowledged alarms, most urgent first. Shelved and suppressed ones are left out.
+    pub fn alarms(&self) -> Vec<Alarm> {
+        let mut alarms: Vec<Alarm> = self
+            .alarms
+            .values()
+            .filter(|alarm| alarm.is_open() && !alarm.is_hidden())
+            .cloned()
+            .collect();
+        alarms.sort_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then(b.activated_at.cmp(&a.activated_at))
+        });
+        alarms
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Alarm> {
+        self.alarms.get(id)
+    }
+
+    // Acknowledge the given alarms. Already acknowledged ones are skipped.
+    pub fn acknowledge(&mut self, ids: &[String], user: Option<String>) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        for id in ids {
+            let Some(alarm) = self.alarms.get_mut(id) else {
+                continue;
+            };
+            if alarm.acked {
+                continue;
+            }
+            alarm.acked = true;
+            alarm.acked_at = Some(Utc::now());
+            alarm.acked_by = user.clone();
+            events.push(alarm.event(AlarmTransition::Acknowledged, user.clone()));
+        }
+        events
+    }
+
+    pub fn shelved(&self) -> Vec<Alarm> {
+        self.alarms
+            .values()
+            .filter(|alarm| alarm.shelved_until.is_some())
+            .cloned()
+            .collect()
+    }
+
+    // Shelve alarms for a while, or longer if they are already shelved.
+    pub fn shelve(
+        &mut self,
+        ids: &[String],
+        minutes: f64,
+        user: Option<String>,
+    ) -> Vec<AlarmEvent> {
+        let until = Utc::now() + chrono::Duration::milliseconds((minutes * 60_000.0) as i64);
+        let mut events = Vec::new();
+        for id in ids {
+            let Some(alarm) = self.alarms.get_mut(id) else {
+                continue;
+            };
+            alarm.shelved_until = alarm.shelved_until.max(Some(until));
+            alarm.shelved_by = user.clone();
+            alarm.pending_since = None;
+            events.push(alarm.event(AlarmTransition::Shelved, user.clone()));
+        }
+        events
+    }
+
+    pub fn unshelve(&mut self, ids: &[String], user: Option<String>) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        for id in ids {
+            let Some(alarm) = self.alarms.get_mut(id) else {
+                continue;
+            };
+            if alarm.shelved_until.is_none() {
+                continue;
+            }
+            alarm.shelved_until = None;
+            alarm.shelved_by = None;
+            events.push(alarm.event(AlarmTransition::Unshelved, user.clone()));
+        }
+        events
+    }
+
+    /*
+     * Check every enabled definition against its tag's value and update the
+     * tag statuses. Tags that are disabled or in Error hold their alarms, as
+     * do shelved alarms and the alarms of suppressed links.
+     */
+    pub fn evaluate(&mut self, links: &mut [Link], now: Instant) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        for tk in std::mem::take(&mut self.unflag) {
+            if let Some(tag_ref) = self.locate(links, &tk, now) {
+                set_status(links, tag_ref, TagStatus::Normal);
+            }
+        }
+
+        let config = std::mem::take(&mut self.config);
+        for definition in config.alarms.iter().filter(|d| d.enabled) {
+            let Some(tag_ref) = self.locate(links, &definition.tk, now) else {
+                continue;
+            };
+            let suppressed = is_suppressed(&config, &links[tag_ref.link_index]);
+            for kind in AlarmKind::ALL {
+                let Some(limit) = definition.limit(kind) else {
+                    continue;
+                };
+                let alarm = self
+                    .alarms
+                    .entry(alarm_id(&definition.tk, kind))
+                    .or_insert_with(|| Alarm::new(definition, kind, limit));
+                if alarm.shelved_until.is_some_and(|until| until <= Utc::now()) {
+                    alarm.shelved_until = None;
+                    alarm.shelved_by = None;
+                    events.push(alarm.event(AlarmTransition::Unshelved, None));
+                }
+                if alarm.suppressed != suppressed {
+                    alarm.suppressed = suppressed;
+                    alarm.pending_since = None;
+                    // Only alarms that were or will be listed are worth a journal entry.
+                    if alarm.is_open() {
+                        let transition = if suppressed {
+                            AlarmTransition::Suppressed
+                        } else {
+                            AlarmTransition::Unsuppressed
+                        };
+                        events.push(alarm.event(transition, None));
+                    }
+                }
+            }
+            if suppressed {
+                if self.flagged.remove(&definition.tk) {
+                    set_status(links, tag_ref, TagStatus::Normal);
+                }
+                continue;
+            }
+
+            let Some(tag) = tag_ref.get(links) else {
+                continue;
+            };
+            if !tag.enabled() || matches!(tag.status(), TagStatus::Error(_)) {
+                continue;
+            }
+            let value = tag.value().as_f64();
+            let changed_at = tag.changed_at();
+            let (link_id, tag_id) = tag_ref.ids(links).unwrap_or_default();
+            let setpoint = definition
+                .deviation
+                .as_ref()
+                .and_then(|deviation| self.locate(links, &deviation.setpoint_tk, now))
+                .and_then(|setpoint| setpoint.get(links))
+                .map(|setpoint| setpoint.value().as_f64());
+            let sample = Sample {
+                value,
+                previous: self.samples.insert(definition.tk.clone(), (value, now)),
+                setpoint,
+                stale_minutes: self.stale_minutes(&links[tag_ref.link_index], changed_at),
+            };
+
+            let mut status = TagStatus::Normal;
+            for kind in AlarmKind::ALL {
+                let Some(limit) = definition.limit(kind) else {
+                    continue;
+                };
+                let alarm = self
+                    .alarms
+                    .entry(alarm_id(&definition.tk, kind))
+                    .or_insert_with(|| Alarm::new(definition, kind, limit));
+                alarm.link_id = link_id;
+                alarm.tag_id = tag_id;
+                if alarm.shelved_until.is_some() {
+                    continue;
+                }
+                let Some((checked, condition)) = alarm.check(definition, &sample, now) else {
+                    continue;
+                };
+                if let Some(transition) = alarm.update(checked, condition, definition, now) {
+                    events.push(alarm.event(transition, None));
+                }
+                if alarm.active && status != TagStatus::Alarm {
+                    status = kind.status();
+                }
+            }
+
+            if status == TagStatus::Normal {
+                if self.flagged.remove(&definition.tk) {
+                    set_status(links, tag_ref, status);
+                }
+            } else {
+                self.flagged.insert(definition.tk.clone());
+                set_status(links, tag_ref, status);
+            }
+        }
+        self.config = config;
+        events
+    }
+
+    fn stale_minutes(&mut self, link: &Link, changed_at: Option<DateTime<Utc>>) -> f64 {
+        let now = Utc::now();
+        let started = *self.started.get_or_insert(now);
+        let mut age = now - changed_at.unwrap_or(started);
+        if let Link::Device(link) = link
+            && link.last_poll_time != NaiveDateTime::default()
+        {
+            age = age.max(chrono::Local::now().naive_local() - link.last_poll_time);
+        }
+        age.num_milliseconds() as f64 / 60_000.0
+    }
+
+    // Position of the tag, rebuilding the index when it is stale.
+    fn locate(&mut self, links: &[Link], tk: &str, now: Instant) -> Option<TagRef> {
+        let tag_ref = self.index.by_tk(tk);
+        if tag_ref.is_some_and(|tag_ref| tag_ref.get(links).is_some_and(|tag| tag.tk() == tk)) {
+            return tag_ref;
+        }
+        // Moved tags rebuild right away, unknown keys only once in a while.
+        let retry = self
+            .index_built
+            .is_none_or(|built| now.duration_since(built) >= ALARM_INDEX_RETRY);
+        if tag_ref.is_none() && !retry {
+            return None;
+        }
+        self.index = TagIndex::build(links);
+        self.index_built = Some(now);
+        self.index.by_tk(tk)
+    }
+}
+
+// Errors come from the link and win over alarm statuses.
+fn set_status(links: &mut [Link], tag_ref: TagRef, status: TagStatus) {
+    let current = links
+        .get_mut(tag_ref.link_index)
+        .and_then(|link| link.tag_status_mut(tag_ref.tag_index));
+    if let Some(current) = current
+        && !matches!(current, TagStatus::Error(_))
+    {
+        *current = status;
+    }
+}
+
+fn is_suppressed(config: &AlarmConfig, link: &Link) -> bool {
+    let in_error = match link {
+        Link::Device(link) => matches!(link.status, LinkStatus::Error(_)),
+        _ => false,
+    };
+    link.id()
+        .is_some_and(|id| config.maintenance_links.contains(&id))
+        || (config.suppress_on_link_error && in_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_links;
+    use crate::{TagValue, write_tag};
+    use serde_json::json;
+
+    fn engine(config: serde_json::Value) -> AlarmEngine {
+        let mut engine = AlarmEngine::default();
+        engine.set_config(serde_json::from_value(config).unwrap());
+        engine
+    }
+
+    fn set(links: &mut [Link], input: usize, value: f32) {
+        write_tag(links, 1, input, TagValue::Real(value));
+    }
+
+    fn status(links: &[Link], input: usize) -> TagStatus {
+        let Link::Inputs(inputs) = &links[1] else {
+            unreachable!()
+        };
+        inputs.tags[input].status.clone()
+    }
+
+    fn transitions(events: &[AlarmEvent]) -> Vec<(String, AlarmTransition)> {
+        events
+            .iter()
+            .map(|event| (event.alarm.id.clone(), event.transition))
+            .collect()
+    }
+
+    #[test]
+    fn limit_alarms_clear_outside_the_deadband() {
+        let mut links = test_links();
+        let mut engine = engine(json!({
+            "alarms": [{ "tk": "IN:0", "hh": 100.0, "h": 80.0, "l": 10.0, "deadband": 5.0 }]
+        }));
+        let now = Instant::now();
+
+        set(&mut links, 0, 50.0);
+        assert!(engine.evaluate(&mut links, now).is_empty());
+        set(&mut links, 0, 85.0);
+        let events = engine.evaluate(&mut links, now);
+        assert_eq!(
+            transitions(&events),
+            [("IN:0:H".to_owned(), AlarmTransition::Activated)]
+        );
+        assert_eq!(status(&links, 0), TagStatus::Warn);
+
+        // Back under the limit but within the deadband holds the alarm.
+        set(&mut links, 0, 77.0);
+        assert!(engine.evaluate(&mut links, now).is_empty());
+        set(&mut links, 0, 74.0);
+        let events = engine.evaluate(&mut links, now);
+        assert_eq!(
+            transitions(&events),
+            [("IN:0:H".to_owned(), AlarmTransition::Cleared)]
+        );
+        assert_eq!(status(&links, 0), TagStatus::Normal);
+        // Cleared but unacknowledged alarms stay listed.
+        assert_eq!(engine.alarms().len(), 1);
+
+        set(&mut links, 0, 120.0);
+        let events = engine.evaluate(&mut links, now);
+        assert_eq!(events.len(), 2);
+        assert_eq!(status(&links, 0), TagStatus::Alarm);
+        assert!(engine.get("IN:0:HH").unwrap().active);
+
+        set(&mut links, 0, 5.0);
+        engine.evaluate(&mut links, now);
+        assert!(engine.get("IN:0:L").unwrap().active);
+        assert!(!engine.get("IN:0:H").unwrap().active);
+        assert!(!engine.get("IN:0:HH").unwrap().active);
+    }
+
+    #[test]
+    fn on_and_off_delays_hold_the_transition() {
+        let mut links = test_links();
+        let mut engine = engine(json!({
+            "alarms": [{ "tk": "IN:0", "h": 80.0, "on_delay_ms": 1000, "off_delay_ms": 500 }]
+        }));
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        set(&mut links, 0, 90.0);
+        assert!(engine.evaluate(&mut links, at(0)).is_empty());
+        assert!(engine.evaluate(&mut links, at(900)).is_empty());
+        assert_eq!(engine.evaluate(&mut links, at(1000)).len(), 1);
+
+        // A dip shorter than the off delay doesn't clear it, and restarts the delay.
+        set(&mut links, 0, 50.0);
+        assert!(engine.evaluate(&mut links, at(1100)).is_empty());
+        set(&mut links, 0, 90.0);
+        assert!(engine.evaluate(&mut links, at(1500)).is_empty());
+        set(&mut links, 0, 50.0);
+        assert!(engine.evaluate(&mut links, at(1700)).is_empty());
+        let events = engine.evaluate(&mut links, at(2200));
+        assert_eq!(
+            transitions(&events),
+            [("IN:0:H".to_owned(), AlarmTransition::Cleared)]
+        );
+    }
+
+    #[test]
+    fn discrete_alarms() {
+        let mut links = test_links();
+        let mut engine = engine(json!({
+            "alarms": [
+                { "tk": "IN:0", "discrete": "OnTrue" },
+                { "tk": "IN:1", "discrete": "OnChange" }
+            ]
+        }));
+        let now = Instant::now();
+
+        assert!(engine.evaluate(&mut links, now).is_empty());
+        set(&mut links, 0, 1.0);
+        set(&mut links, 1, 3.0);
+        let events = engine.evaluate(&mut links, now);
+        assert_eq!(events.len(), 2);
+        assert_eq!(status(&links, 0), TagStatus::Alarm);
+        assert_eq!(status(&links, 1), TagStatus::Alarm);
+
+        // A change is only active for one scan but stays listed until acknowledged.
+        let events = engine.evaluate(&mut links, now);
+        assert_eq!(
+            transitions(&events),
+            [("IN:1:DI".to_owned(), AlarmTransition::Cleared)]
+        );
+        assert_eq!(status(&links, 1), TagStatus::Normal);
+        assert!(engine.alarms().iter().any(|alarm| alarm.id == "IN:1:DI"));
+        engine.acknowledge(&["IN:1:DI".to_owned()], Some("op".to_owned()));
+        assert!(!engine.alarms().iter().any(|alarm| alarm.id == "IN:1:DI"));
+
+        set(&mut links, 0, 0.0);
+        engine.evaluate(&mut links, now);
+        assert!(!engine.get("IN:0:DI").unwrap().active);
+    }
+
+    #[test]
+    fn deviation_from_the_setpoint_tag() {
+        let mut links = test_links();
+        let mut engine = engine(json!({
+            "alarms": [{ "tk": "IN:0", "deviation": { "setpoint_tk": "IN:1", "limit": 5.0 } }]
+        }));
+        let now = Instant::now();
+
+        set(&mut links, 0, 54.0);
+        set(&mut links, 1, 50.0);
+        assert!(engine.evaluate(&mut links, now).is_empty());
+        set(&mut links, 1, 60.0);
+        let events = engine.evaluate(&mut links, now);
+        assert_eq!(
+            transitions(&events),
+            [("IN:0:DEV".to_owned(), AlarmTransition::Activated)]
+        );
+        assert_eq!(events[0].alarm.value, 6.0);
+        assert_eq!(status(&links, 0), TagStatus::Warn);
+    }
+
+    #[test]
+    fn rate_of_change_per_period() {
+        let mut links = test_links();
+        let mut engine = engine(json!({
+            "alarms": [
+                { "tk": "IN:0", "rate": { "limit": 10.0 } },
+                { "tk": "IN:1", "rate": { "limit": 10.0, "per": "Minute" } }
+            ]
+        }));
+        let start = Instant::now();
+
+        engine.evaluate(&mut links, start);
+        // 5 per second: under the per second limit, 300 per minute.
+        set(&mut links, 0, 10.0);
+        set(&mut links, 1, 10.0);
+        let events = engine.evaluate(&mut links, start + Duration::from_secs(2));
+        assert_eq!(
+            transitions(&events),
+            [("IN:1:ROC".to_owned(), AlarmTransition::Activated)]
+        );
+        set(&mut links, 0, 40.0);
+        let events = engine.evaluate(&mut links, start + Duration::from_secs(4));
+        assert!(
+            transitions(&events).contains(&("IN:0:ROC".to_owned(), AlarmTransition::Activated))
+        );
+    }
+
+    #[test]
+    fn shelved_alarms_are_hidden_and_held() {
+        let mut links = test_links();
+        let mut engine = engine(json!({ "alarms": [{ "tk": "IN:0", "h": 80.0 }] }));
+        let now = Instant::now();
+        let ids = ["IN:0:H".to_owned()];
+
+        set(&mut links, 0, 90.0);
+        engine.evaluate(&mut links, now);
+        let events = engine.shelve(&ids, 10.0, Some("op".to_owned()));
+        assert_eq!(events[0].transition, AlarmTransition::Shelved);
+        assert!(engine.alarms().is_empty());
+        assert_eq!(engine.shelved()[0].shelved_by.as_deref(), Some("op"));
+
+        // A shelved alarm doesn't clear.
+        set(&mut links, 0, 50.0);
+        assert!(engine.evaluate(&mut links, now).is_empty());
+        assert!(engine.get("IN:0:H").unwrap().active);
+
+        let events = engine.unshelve(&ids, Some("op".to_owned()));
+        assert_eq!(events[0].transition, AlarmTransition::Unshelved);
+        assert!(engine.unshelve(&ids, None).is_empty());
+        let events = engine.evaluate(&mut links, now);
+        assert_eq!(events[0].transition, AlarmTransition::Cleared);
+    }
+
+    #[test]
+    fn shelving_again_never_shortens_the_shelve() {
+        let mut links = test_links();
+        let mut engine = engine(json!({ "alarms": [{ "tk": "IN:0", "h": 80.0 }] }));
+        let ids = ["IN:0:H".to_owned()];
+        set(&mut links, 0, 90.0);
+        engine.evaluate(&mut links, Instant::now());
+
+        engine.shelve(&ids, 60.0, None);
+        let until = engine.get("IN:0:H").unwrap().shelved_until;
+        engine.shelve(&ids, 5.0, Some("op".to_owned()));
+        let alarm = engine.get("IN:0:H").unwrap();
+        assert_eq!(alarm.shelved_until, until);
+        assert_eq!(alarm.shelved_by.as_deref(), Some("op"));
+
+        engine.shelve(&ids, 120.0, None);
+        assert!(engine.get("IN:0:H").unwrap().shelved_until > until);
+    }
+
+    #[test]
+    fn maintenance_links_suppress_their_alarms() {
+        let mut links = test_links();
+        let mut config = json!({ "alarms": [{ "tk": "IN:0", "h": 80.0 }] });
+        let mut engine = engine(config.clone());
+        let now = Instant::now();
+
+        set(&mut links, 0, 90.0);
+        engine.evaluate(&mut links, now);
+        assert_eq!(status(&links, 0), TagStatus::Warn);
+
+        config["maintenance_links"] = json!([1]);
+        engine.set_config(serde_json::from_value(config.clone()).unwrap());
+        let events = engine.evaluate(&mut links, now);
+        assert_eq!(events[0].transition, AlarmTransition::Suppressed);
+        assert!(engine.alarms().is_empty());
+        assert_eq!(status(&links, 0), TagStatus::Normal);
+
+        config["maintenance_links"] = json!([]);
+        engine.set_config(serde_json::from_value(config).unwrap());
+        let events = engine.evaluate(&mut links, now);
+        assert_eq!(events[0].transition, AlarmTransition::Unsuppressed);
+        assert_eq!(status(&links, 0), TagStatus::Warn);
+    }
+
+    #[test]
+    fn new_config_keeps_the_state_of_remaining_limits() {
+        let mut links = test_links();
+        let mut engine = engine(json!({ "alarms": [{ "tk": "IN:0", "h": 80.0, "l": 10.0 }] }));
+        let now = Instant::now();
+
+        set(&mut links, 0, 90.0);
+        engine.evaluate(&mut links, now);
+        engine.acknowledge(&["IN:0:H".to_owned()], Some("op".to_owned()));
+        engine.set_config(
+            serde_json::from_value(json!({ "alarms": [{ "tk": "IN:0", "h": 85.0 }] })).unwrap(),
+        );
+        let alarm = engine.get("IN:0:H").unwrap();
+        assert!(alarm.active && alarm.acked);
+        assert_eq!(alarm.limit, 85.0);
+        assert!(engine.get("IN:0:L").is_none());
+
+        // Dropping the definition resets the status the engine set.
+        engine.set_config(serde_json::from_value(json!({ "alarms": [] })).unwrap());
+        engine.evaluate(&mut links, now);
+        assert_eq!(status(&links, 0), TagStatus::Normal);
+    }
+}
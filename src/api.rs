@@ -1,7 +1,17 @@
 use crate::state::GlobalState;
-use crate::{DeviceLink, link::Link};
-use crate::{Eval, MAX_NUM_LINKS, ModbusSerialConfig, ModbusTcpConfig, Protocol, Tag, TagValue};
-use axum::extract::rejection::JsonRejection;
+use crate::{ALARM_MAX_SHELVE_MINUTES, AlarmEvent, AlarmHistoryFilter};
+use crate::{ALARMS_CONFIG_PATH, AlarmConfig, SCRIPT_MODULES_PATH, ScriptModule};
+use crate::{AccessScope, AuthConfig, AuthUser, Role, TokenKind, User, has_enabled_admin};
+use crate::{ApiError, ApiJson, ErrorCode};
+use crate::{AuditEntry, AuditFilter, ConfigChange, EvalGraph, Requester, config_diff};
+use crate::{DUMMY_PASSWORD_HASH, generate_secret, save_auth_config, verify_password};
+use crate::{DataBase, HistoryQuery, LoggerLink, MAX_HISTORY_BUCKETS, aggregate, locate_tk};
+use crate::{DeviceLink, EvalRuntime, FormulaRequest, link::Link};
+use crate::{Eval, MAX_NUM_LINKS, MAX_NUM_LOGGER_LINKS, ModbusSerialConfig, ModbusTcpConfig};
+use crate::{METRICS_CONFIG_PATH, MetricsConfig, Task, TaskType};
+use crate::{NOTIFICATIONS_CONFIG_PATH, NotificationConfig};
+use crate::{Protocol, Tag, TagValue};
+use crate::{TagIndex, TagKey, TagReading, read_tag};
 use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
 use log::info;
 use serde::{Deserialize, Serialize};
@@ -38,70 +48,264 @@ pub struct EvalReconfigData {
     pub tag_info: TagIdQuery,
     pub tag_data: Eval,
 }
+#[derive(Deserialize, Debug)]
+pub struct ScriptModuleName {
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AlarmIds {
+    pub ids: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AlarmShelve {
+    pub ids: Vec<String>,
+    pub minutes: f64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LinkMaintenance {
+    pub link_id: usize,
+    pub maintenance: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TagBatchRead {
+    pub keys: Vec<TagKey>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TagReadResult {
+    pub key: TagKey,
+    pub reading: Option<TagReading>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TagBatchReading {
+    // When the values were read.
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub results: Vec<TagReadResult>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TagWriteItem {
+    pub key: TagKey,
+    pub value: TagValue,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TagBatchWrite {
+    pub writes: Vec<TagWriteItem>,
+    // Write nothing unless every item is valid.
+    #[serde(default)]
+    pub atomic: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub enum TagWriteStatus {
+    // Input tag, the value is set.
+    Written,
+    // Device tag, the value is written on the next poll.
+    Queued,
+    Rejected(String),
+    // Valid, but not written because the atomic batch had rejected items.
+    Skipped,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TagWriteResult {
+    pub key: TagKey,
+    pub link_id: Option<usize>,
+    pub tag_id: Option<usize>,
+    pub status: TagWriteStatus,
+}
+
+#[derive(Deserialize)]
+pub struct LoginData {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshData {
+    pub refresh_token: String,
+}
+
+// Creates the user when it doesn't exist, then a password is required.
+#[derive(Deserialize)]
+pub struct UserData {
+    pub username: String,
+    pub password: Option<String>,
+    pub enabled: Option<bool>,
+    // Only used for new users, see UserPermissions.
+    pub role: Option<Role>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct UserPermissions {
+    pub username: String,
+    pub role: Role,
+    #[serde(default)]
+    pub scope: Option<AccessScope>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserName {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct UserInfo {
+    pub username: String,
+    pub enabled: bool,
+    pub role: Role,
+    pub scope: Option<AccessScope>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AuthSettings {
+    pub access_token_minutes: i64,
+    pub refresh_token_minutes: i64,
+    // A new signing key logs everyone out.
+    #[serde(default)]
+    pub rotate_signing_key: bool,
+}
+
 #[derive(Deserialize)]
 pub struct TagWriteData {
     pub tag_info: TagIdQuery,
     pub tag_value: TagValue,
 }
 
+// Append to the audit journal. A failing journal must not block plant changes.
+fn audit(state: &GlobalState, entry: AuditEntry) {
+    if let Err(e) = state.audit.append(&entry) {
+        info!("Could not write audit entry: {e}");
+    }
+}
+
+/*
+ * The links as the user may see them. Links outside the scope only keep the
+ * tags granted by tk, and are left out when there are none.
+ */
+fn scoped_links(user: &AuthUser, links: &[Link]) -> Vec<Link> {
+    links
+        .iter()
+        .filter_map(|link| {
+            let Some(link_id) = link.id() else {
+                return user.unscoped().then(|| link.clone());
+            };
+            if user.allows_link(link_id) {
+                return Some(link.clone());
+            }
+            let mut link = link.clone();
+            link.retain_tags(|tk| user.allows_tag(link_id, tk));
+            (link.tag_count() > 0).then_some(link)
+        })
+        .collect()
+}
+
+// Whether the user may see the tag with this tk. Unknown tks only unscoped.
+fn allows_tk(user: &AuthUser, links: &[Link], tk: &str) -> bool {
+    match locate_tk(links, tk) {
+        Some((link_id, _)) => user.allows_tag(link_id, tk),
+        None => user.unscoped(),
+    }
+}
+
 pub async fn get_links_config(
     State(state): State<GlobalState>,
-) -> Result<impl IntoResponse, StatusCode> {
+    user: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
     let locked_state = state.state_db.lock().await;
-    Ok(Json(locked_state.clone()))
+    Ok(Json(scoped_links(&user, &locked_state)))
+}
+
+// Logger links are limited separately from the other links.
+fn check_link_counts<'a>(links: impl Iterator<Item = &'a Link>) -> Result<(), ApiError> {
+    let (num_loggers, num_links) = links.fold((0, 0), |(loggers, others), link| match link {
+        Link::Logger(_) => (loggers + 1, others),
+        _ => (loggers, others + 1),
+    });
+    if num_links > MAX_NUM_LINKS {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!("At most {MAX_NUM_LINKS} links, got {num_links}."),
+        )
+        .details(serde_json::json!({ "max": MAX_NUM_LINKS, "count": num_links })));
+    }
+    if num_loggers > MAX_NUM_LOGGER_LINKS {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!("At most {MAX_NUM_LOGGER_LINKS} logger links, got {num_loggers}."),
+        )
+        .details(serde_json::json!({ "max": MAX_NUM_LOGGER_LINKS, "count": num_loggers })));
+    }
+    Ok(())
 }
 
 pub async fn reconfig_links(
     State(state): State<GlobalState>,
-    Json(mut links): Json<Vec<Link>>,
-) -> Result<impl IntoResponse, StatusCode> {
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(mut links): ApiJson<Vec<Link>>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require_unscoped(Role::Engineer)?;
     let mut locked_state = state.state_db.lock().await;
 
-    if links.len() > MAX_NUM_LINKS {
-        return Err(StatusCode::NOT_FOUND);
-    } else {
-        for link in links.iter_mut() {
-            match link {
-                Link::Device(link) => {
-                    link.status = crate::LinkStatus::NeedsToReconnect;
-                }
-                Link::Eval(link) => {
-                    link.status = crate::LinkStatus::PendingTagReconfig;
-                }
-                _ => {}
+    check_link_counts(links.iter())?;
+    for link in links.iter_mut() {
+        match link {
+            Link::Device(link) => {
+                link.status = crate::LinkStatus::NeedsToReconnect;
+            }
+            Link::Eval(link) => {
+                link.status = crate::LinkStatus::PendingTagReconfig;
+            }
+            Link::Logger(link) => {
+                link.status = crate::LinkStatus::PendingTagReconfig;
             }
+            _ => {}
         }
+    }
 
-        *locked_state = links.clone();
+    let mut entry = AuditEntry::new(&requester, "/api/reconfig_links");
+    entry.changes = config_diff(&*locked_state, &links);
+    audit(&state, entry);
 
-        let file = File::create("./CurrentConfig/current_config.json");
-        if let Ok(file) = file {
-            let mut writer = BufWriter::new(file);
-            if let Ok(_) = serde_json::to_writer_pretty(&mut writer, &links) {
-                info!("Success");
-            } else {
-                info!("error");
-            }
+    *locked_state = links.clone();
+
+    let file = File::create("./CurrentConfig/current_config.json");
+    if let Ok(file) = file {
+        let mut writer = BufWriter::new(file);
+        if serde_json::to_writer_pretty(&mut writer, &links).is_ok() {
+            info!("Success");
         } else {
-            info!("Could not create file");
+            info!("error");
         }
-        return Ok(StatusCode::OK);
+    } else {
+        info!("Could not create file");
     }
+    Ok(StatusCode::OK)
 }
 // Return the whole config and data of the link device
 // specified by the link_id
 pub async fn get_device_link_config(
     State(state): State<GlobalState>,
-    Json(link_id): Json<LinkIdQuery>,
-) -> Result<impl IntoResponse, StatusCode> {
+    user: AuthUser,
+    ApiJson(link_id): ApiJson<LinkIdQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
     let locked_state = state.state_db.lock().await;
 
-    for link in locked_state.iter() {
+    for link in scoped_links(&user, &locked_state) {
         match link {
             Link::Device(link) => {
                 if link.id as u32 == link_id.link_id {
                     info!("Found device");
-                    return Ok(Json(Link::Device(link.clone())));
+                    return Ok(Json(Link::Device(link)));
                 }
             }
             _ => {
@@ -109,36 +313,85 @@ pub async fn get_device_link_config(
             }
         }
     }
-    Err(StatusCode::NOT_FOUND)
+    Err(ApiError::link_not_found(link_id.link_id))
 }
 
-// Return the whole config and data of the link device
-// specified by the link_id
+// Return the whole config and data of the device, input or eval tag
+// specified by the link_id and tag_id
 pub async fn get_tag_config(
     State(state): State<GlobalState>,
-    Json(tag_id): Json<TagIdQuery>,
-) -> Result<impl IntoResponse, StatusCode> {
+    user: AuthUser,
+    ApiJson(tag_id): ApiJson<TagIdQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
     let locked_state = state.state_db.lock().await;
 
-    for link in locked_state.iter() {
-        match link {
-            Link::Device(link) => {
-                if link.id as u32 == tag_id.link_id {
-                    for tag in link.tags.iter() {
-                        if tag.id as u32 == tag_id.tag_id {
-                            info!("Found tag");
-                            return Ok(Json(tag.clone()));
-                        }
-                    }
-                }
-            }
-            _ => {
-                // TODO check for other types of tags.
-                continue;
-            }
+    let link_id = tag_id.link_id as usize;
+    let tag = locked_state
+        .iter()
+        .filter(|link| link.id() == Some(link_id))
+        .find_map(|link| link.tag(tag_id.tag_id as usize))
+        .filter(|tag| user.allows_tag(link_id, tag.tk()));
+    match tag {
+        Some(tag) => {
+            info!("Found tag");
+            Ok(Json(
+                serde_json::to_value(tag).map_err(|e| ApiError::internal(e.to_string()))?,
+            ))
         }
+        None => Err(ApiError::tag_not_found(tag_id)),
     }
-    Err(StatusCode::NOT_FOUND)
+}
+
+// Current value, quality and change time of one tag, by tk or by ids.
+pub async fn read_tag_value(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    ApiJson(key): ApiJson<TagKey>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
+    let locked_state = state.state_db.lock().await;
+    let reading = read_tag(&locked_state, &key)
+        .filter(|reading| user.allows_tag(reading.link_id, &reading.tk));
+    match reading {
+        Some(reading) => Ok(Json(reading)),
+        None => {
+            info!("Unknown tag: {key:?}");
+            Err(ApiError::tag_not_found(key))
+        }
+    }
+}
+
+/*
+ * Read many tags in one go. Results come back in the order of the keys,
+ * unknown keys and tags outside the user's scope get no reading instead of
+ * failing the whole batch.
+ */
+pub async fn read_tag_values(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    ApiJson(request): ApiJson<TagBatchRead>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
+    let locked_state = state.state_db.lock().await;
+    let index = TagIndex::build(&locked_state);
+    let results = request
+        .keys
+        .into_iter()
+        .map(|key| {
+            let reading = index.by_key(&key).and_then(|tag_ref| {
+                let (link_id, _) = tag_ref.ids(&locked_state)?;
+                let tag = tag_ref.get(&locked_state)?;
+                user.allows_tag(link_id, tag.tk())
+                    .then(|| tag.reading(link_id))
+            });
+            TagReadResult { key, reading }
+        })
+        .collect();
+    Ok(Json(TagBatchReading {
+        timestamp: chrono::Utc::now(),
+        results,
+    }))
 }
 
 /*
@@ -147,8 +400,11 @@ This is a post request.
 */
 pub async fn reconfig_device_link(
     State(state): State<GlobalState>,
-    Json(config): Json<DeviceLink>,
-) -> Result<impl IntoResponse, StatusCode> {
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(config): ApiJson<DeviceLink>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require_link(Role::Engineer, config.id)?;
     let mut locked_state = state.state_db.lock().await;
 
     for (_, link) in locked_state.iter_mut().enumerate() {
@@ -156,6 +412,10 @@ pub async fn reconfig_device_link(
             Link::Device(link) => {
                 if link.id == config.id {
                     info!("Reconfigured device: {}.", link.id);
+                    let mut entry = AuditEntry::new(&requester, "/api/reconfigure_device_link");
+                    entry.link_id = Some(link.id);
+                    entry.changes = config_diff(&*link, &config);
+                    audit(&state, entry);
                     link.reconfigure(config);
                     //locked_state[i] = Link::Device(config);
                     return Ok(StatusCode::OK);
@@ -166,14 +426,23 @@ pub async fn reconfig_device_link(
             }
         }
     }
-    Err(StatusCode::NOT_FOUND)
+    Err(ApiError::link_not_found(config.id))
+}
+
+// The protocol string is modbus:tcp:ip:port:slave or modbus:rtu:com:baudrate:slave.
+fn invalid_protocol(message: &str) -> ApiError {
+    info!("{message}");
+    ApiError::new(ErrorCode::InvalidValue, message).field("protocol")
 }
 
 // Using a protocol details string to reconfigure the device.
 pub async fn reconfig_device_protocol(
     State(state): State<GlobalState>,
-    Json(config): Json<LinkProtocolReconfig>,
-) -> Result<impl IntoResponse, StatusCode> {
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(config): ApiJson<LinkProtocolReconfig>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require_link(Role::Engineer, config.link_id as usize)?;
     let fields: Vec<&str> = config.protocol.split(':').collect();
 
     if fields.len() == 5 {
@@ -190,7 +459,9 @@ pub async fn reconfig_device_protocol(
                             match link {
                                 Link::Device(link) => {
                                     if link.id == config.link_id as usize {
-                                        link.protocol = Protocol::ModbusTcp(tcp_config);
+                                        let new_protocol = Protocol::ModbusTcp(tcp_config);
+                                        audit_protocol(&state, &requester, link, &new_protocol);
+                                        link.protocol = new_protocol;
                                         link.status = crate::LinkStatus::NeedsToReconnect;
                                         return Ok(StatusCode::OK);
                                     }
@@ -200,9 +471,9 @@ pub async fn reconfig_device_protocol(
                                 }
                             }
                         }
+                        return Err(ApiError::link_not_found(config.link_id));
                     } else {
-                        info!("Could not parse port or slave.");
-                        return Err(StatusCode::NOT_FOUND);
+                        return Err(invalid_protocol("Could not parse port or slave."));
                     }
                 } else if fields[1] == "rtu" {
                     let com = fields[2].to_string();
@@ -221,7 +492,9 @@ pub async fn reconfig_device_protocol(
                             match link {
                                 Link::Device(link) => {
                                     if link.id == config.link_id as usize {
-                                        link.protocol = Protocol::ModbusSerial(serial_config);
+                                        let new_protocol = Protocol::ModbusSerial(serial_config);
+                                        audit_protocol(&state, &requester, link, &new_protocol);
+                                        link.protocol = new_protocol;
                                         link.status = crate::LinkStatus::NeedsToReconnect;
                                         return Ok(StatusCode::OK);
                                     }
@@ -231,34 +504,68 @@ pub async fn reconfig_device_protocol(
                                 }
                             }
                         }
+                        return Err(ApiError::link_not_found(config.link_id));
                     } else {
-                        info!("Could not parse baudrate or slave.");
-                        return Err(StatusCode::NOT_FOUND);
+                        return Err(invalid_protocol("Could not parse baudrate or slave."));
                     }
                 } else {
-                    info!("Only TCP or RTU.");
-                    return Err(StatusCode::NOT_FOUND);
+                    return Err(invalid_protocol("Only TCP or RTU."));
                 }
             }
             _ => {
-                info!("Only Modbus.");
-                return Err(StatusCode::NOT_FOUND);
+                return Err(invalid_protocol("Only Modbus."));
             }
         }
     }
-    info!("Wrong number of fields.");
-    Err(StatusCode::NOT_FOUND)
+    Err(invalid_protocol("Wrong number of fields."))
+}
+
+fn audit_protocol(
+    state: &GlobalState,
+    requester: &Requester,
+    link: &DeviceLink,
+    new_protocol: &Protocol,
+) {
+    let mut entry = AuditEntry::new(requester, "/api/reconfigure_device_protocol");
+    entry.link_id = Some(link.id);
+    entry.old_value = serde_json::to_value(&link.protocol).ok();
+    entry.new_value = serde_json::to_value(new_protocol).ok();
+    audit(state, entry);
+}
+
+fn mark_eval_links_pending(links: &mut [Link]) {
+    for link in links.iter_mut() {
+        if let Link::Eval(link) = link {
+            link.status = crate::LinkStatus::PendingTagReconfig;
+        }
+    }
 }
 
 pub async fn reconfig_eval(
     State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
     //Json(config): Json<TagReconfigData>,
-    payload: Result<Json<EvalReconfigData>, JsonRejection>,
-) -> Result<impl IntoResponse, StatusCode> {
+    payload: Result<ApiJson<EvalReconfigData>, ApiError>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Engineer)?;
+    const ENDPOINT: &str = "/api/reconfigure_eval";
     let mut locked_state = state.state_db.lock().await;
 
     match payload {
         Ok(config) => {
+            config
+                .tag_data
+                .check_writable_tags(&locked_state)
+                .map_err(|e| {
+                    ApiError::new(ErrorCode::ValidationFailed, e).field("writable_tags")
+                })?;
+            // The eval writes with the engineer's permission, so the targets must be in scope.
+            for tk in config.tag_data.writable_tags.iter() {
+                if let Some((link_id, _)) = locate_tk(&locked_state, tk) {
+                    user.require_tag(Role::Engineer, link_id, tk)?;
+                }
+            }
             for link in locked_state.iter_mut() {
                 match link {
                     Link::Eval(link) => {
@@ -266,9 +573,19 @@ pub async fn reconfig_eval(
                             for tag in link.tags.iter_mut() {
                                 if tag.id as u32 == config.tag_info.tag_id {
                                     info!("Found tag to reconfigure.");
+                                    user.require_tag(Role::Engineer, link.id, &tag.tk)?;
+                                    let mut entry = AuditEntry::new(&requester, ENDPOINT);
+                                    entry.link_id = Some(link.id);
+                                    entry.tag_id = Some(tag.id);
+                                    entry.changes = config_diff(&*tag, &config.tag_data);
+                                    audit(&state, entry);
                                     *tag = config.tag_data.clone();
                                     link.status = crate::LinkStatus::PendingTagReconfig;
-                                    return Ok(Json(tag.clone()));
+                                    let tag = tag.clone();
+                                    // Dependencies may have changed, so every eval link
+                                    // has to reorder its evaluation.
+                                    mark_eval_links_pending(&mut locked_state);
+                                    return Ok(Json(tag));
                                 }
                             }
                         }
@@ -279,14 +596,14 @@ pub async fn reconfig_eval(
                     }
                 }
             }
+            info!("Could not find tag to reconfigure.");
+            Err(ApiError::tag_not_found(&config.tag_info))
         }
         Err(e) => {
-            info!("{}", e);
-            return Err(StatusCode::NOT_FOUND);
+            info!("{}", e.message);
+            Err(e)
         }
     }
-    info!("Could not find tag to reconfigure.");
-    Err(StatusCode::NOT_FOUND)
 }
 
 /*
@@ -295,9 +612,13 @@ pub async fn reconfig_eval(
  */
 pub async fn reconfig_device_tag(
     State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
     //Json(config): Json<TagReconfigData>,
-    payload: Result<Json<TagReconfigData>, JsonRejection>,
-) -> Result<impl IntoResponse, StatusCode> {
+    payload: Result<ApiJson<TagReconfigData>, ApiError>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Engineer)?;
+    const ENDPOINT: &str = "/api/reconfigure_device_tag";
     let mut locked_state = state.state_db.lock().await;
 
     match payload {
@@ -309,6 +630,12 @@ pub async fn reconfig_device_tag(
                             for tag in link.tags.iter_mut() {
                                 if tag.id as u32 == config.tag_info.tag_id {
                                     info!("Found tag to reconfigure.");
+                                    user.require_tag(Role::Engineer, link.id, &tag.tk)?;
+                                    let mut entry = AuditEntry::new(&requester, ENDPOINT);
+                                    entry.link_id = Some(link.id);
+                                    entry.tag_id = Some(tag.id);
+                                    entry.changes = config_diff(&*tag, &config.tag_data);
+                                    audit(&state, entry);
                                     *tag = config.tag_data.clone();
                                     link.status = crate::LinkStatus::PendingTagReconfig;
                                     return Ok(Json(tag.clone()));
@@ -322,55 +649,1729 @@ pub async fn reconfig_device_tag(
                     }
                 }
             }
+            info!("Could not find tag to reconfigure.");
+            Err(ApiError::tag_not_found(&config.tag_info))
         }
         Err(e) => {
-            info!("{}", e);
-            return Err(StatusCode::NOT_FOUND);
+            info!("{}", e.message);
+            Err(e)
         }
     }
-    info!("Could not find tag to reconfigure.");
-    Err(StatusCode::NOT_FOUND)
+}
+
+fn audit_write(
+    state: &GlobalState,
+    requester: &Requester,
+    endpoint: &str,
+    (link_id, tag_id): (usize, usize),
+    old_value: &TagValue,
+    new_value: &TagValue,
+) {
+    let mut entry = AuditEntry::new(requester, endpoint);
+    entry.link_id = Some(link_id);
+    entry.tag_id = Some(tag_id);
+    entry.old_value = serde_json::to_value(old_value).ok();
+    entry.new_value = serde_json::to_value(new_value).ok();
+    audit(state, entry);
 }
 
 pub async fn write_link_tag(
     State(state): State<GlobalState>,
-    Json(data): Json<TagWriteData>,
-) -> Result<StatusCode, StatusCode> {
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(data): ApiJson<TagWriteData>,
+) -> Result<StatusCode, ApiError> {
+    user.require(Role::Operator)?;
+    let mut locked_state = state.state_db.lock().await;
+    let link_id = data.tag_info.link_id as usize;
+    let tag_id = data.tag_info.tag_id as usize;
+    let index = TagIndex::build(&locked_state);
+    let Some(tag_ref) = index.by_id(link_id, tag_id) else {
+        info!("Could not find tag to write.");
+        return Err(ApiError::tag_not_found(&data.tag_info));
+    };
+    if let Some(tag) = tag_ref.get(&locked_state) {
+        user.require_tag(Role::Operator, link_id, tag.tk())?;
+    }
+    locked_state[tag_ref.link_index]
+        .check_write(tag_ref.tag_index, &data.tag_value)
+        .map_err(|reason| ApiError::new(ErrorCode::ValidationFailed, reason).field("tag_value"))?;
+
+    match state.write_tag(&mut locked_state, link_id, tag_id, data.tag_value.clone()) {
+        Some(old_value) => {
+            audit_write(
+                &state,
+                &requester,
+                "/api/write_tag",
+                (link_id, tag_id),
+                &old_value,
+                &data.tag_value,
+            );
+            Ok(StatusCode::OK)
+        }
+        None => {
+            info!("Could not find tag to write.");
+            Err(ApiError::tag_not_found(&data.tag_info))
+        }
+    }
+}
+
+/*
+ * Write many tags in one request. Device writes are queued for the next
+ * poll of their link, which writes neighbouring holding registers together.
+ * With atomic set nothing is written unless every item passes the checks.
+ */
+pub async fn write_tags(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(batch): ApiJson<TagBatchWrite>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Operator)?;
+    let mut locked_state = state.state_db.lock().await;
+    let index = TagIndex::build(&locked_state);
+
+    let mut results = Vec::with_capacity(batch.writes.len());
+    let mut accepted = Vec::new();
+    for item in batch.writes {
+        let checked = index
+            .by_key(&item.key)
+            .ok_or_else(|| "Unknown tag.".to_owned())
+            .and_then(|tag_ref| {
+                let link = &locked_state[tag_ref.link_index];
+                link.check_write(tag_ref.tag_index, &item.value)?;
+                let tag = tag_ref.get(&locked_state).ok_or("Unknown tag.")?;
+                let (link_id, tag_id) = tag_ref.ids(&locked_state).ok_or("Unknown tag.")?;
+                user.require_tag(Role::Operator, link_id, tag.tk())
+                    .map_err(|_| "Not permitted.".to_owned())?;
+                Ok((link_id, tag_id, matches!(link, Link::Device(_))))
+            });
+        let (ids, status) = match checked {
+            Ok((link_id, tag_id, queued)) => {
+                accepted.push((results.len(), link_id, tag_id, queued, item.value));
+                (Some((link_id, tag_id)), TagWriteStatus::Skipped)
+            }
+            Err(reason) => (None, TagWriteStatus::Rejected(reason)),
+        };
+        results.push(TagWriteResult {
+            key: item.key,
+            link_id: ids.map(|ids| ids.0),
+            tag_id: ids.map(|ids| ids.1),
+            status,
+        });
+    }
+
+    let rejected = accepted.len() < results.len();
+    if batch.atomic && rejected {
+        info!("Batch write rejected, nothing written.");
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            "Some writes were rejected, nothing was written.",
+        )
+        .field("writes")
+        .details(results));
+    }
+    for (result, link_id, tag_id, queued, value) in accepted {
+        let Some(old_value) = state.write_tag(&mut locked_state, link_id, tag_id, value.clone())
+        else {
+            results[result].status = TagWriteStatus::Rejected("Unknown tag.".to_owned());
+            continue;
+        };
+        audit_write(
+            &state,
+            &requester,
+            "/api/write_tags",
+            (link_id, tag_id),
+            &old_value,
+            &value,
+        );
+        results[result].status = if queued {
+            TagWriteStatus::Queued
+        } else {
+            TagWriteStatus::Written
+        };
+    }
+    Ok(Json(results))
+}
+
+/*
+ * Reconfigure a logger link, or add a new one when the id is the next free
+ * link index. New loggers get their own logging task.
+ */
+pub async fn reconfig_logger(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(mut config): ApiJson<LoggerLink>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require_link(Role::Engineer, config.id)?;
     let mut locked_state = state.state_db.lock().await;
+    config.status = crate::LinkStatus::PendingTagReconfig;
+    let mut entry = AuditEntry::new(&requester, "/api/reconfigure_logger");
+    entry.link_id = Some(config.id);
 
     for link in locked_state.iter_mut() {
-        match link {
-            Link::Device(link) => {
-                if link.id as u32 == data.tag_info.link_id {
-                    for (i, tag) in &mut link.tags.iter().enumerate() {
-                        if tag.id as u32 == data.tag_info.tag_id {
-                            //tag.pending_write = Some(data.tag_value);
-                            info!("Found tag to write. {:?}", tag.pending_write.clone());
-                            link.tags[i].pending_write = Some(data.tag_value);
-                            link.status = crate::LinkStatus::PendingTagReconfig;
-
-                            return Ok(StatusCode::OK);
-                        }
-                    }
+        if let Link::Logger(link) = link
+            && link.id == config.id
+        {
+            info!("Reconfigured logger: {}.", link.id);
+            entry.changes = config_diff(&*link, &config);
+            audit(&state, entry);
+            *link = config;
+            return Ok(StatusCode::OK);
+        }
+    }
+
+    if config.id == locked_state.len() {
+        let id = config.id;
+        entry.new_value = serde_json::to_value(&config).ok();
+        let link = Link::Logger(config);
+        check_link_counts(locked_state.iter().chain([&link]))?;
+        info!("Added logger: {id}.");
+        audit(&state, entry);
+        locked_state.push(link);
+        if let Err(e) = crate::task::spawn(Task::new(TaskType::Logging, state.clone(), id)) {
+            return Err(ApiError::internal(format!(
+                "Could not start the logger: {e}"
+            )));
+        }
+        return Ok(StatusCode::OK);
+    }
+    info!("Could not find logger to reconfigure.");
+    Err(ApiError::new(
+        ErrorCode::LinkNotFound,
+        format!(
+            "No logger with id {}, new loggers take id {}.",
+            config.id,
+            locked_state.len()
+        ),
+    )
+    .field("id"))
+}
+
+/*
+ * Read logged values back from the database of the logger link that records
+ * each requested tag, optionally reduced to aggregated buckets.
+ */
+pub async fn get_history(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    ApiJson(query): ApiJson<HistoryQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
+    if query
+        .bucket_count()
+        .is_none_or(|count| count > MAX_HISTORY_BUCKETS)
+    {
+        info!("Invalid history range or bucket size.");
+        return Err(ApiError::new(
+            ErrorCode::InvalidValue,
+            format!(
+                "The end must be after the start and the bucket positive, with at most {MAX_HISTORY_BUCKETS} buckets."
+            ),
+        ));
+    }
+
+    // Group the requested tags per logger so each database is queried once.
+    let mut requests: Vec<(usize, DataBase, Vec<String>)> = Vec::new();
+    {
+        let locked_state = state.state_db.lock().await;
+        for tk in query.tks.iter() {
+            let Some((link_id, tag_id)) =
+                locate_tk(&locked_state, tk).filter(|(link_id, _)| user.allows_tag(*link_id, tk))
+            else {
+                return Err(ApiError::tag_not_found(tk).field("tks"));
+            };
+            let logger = locked_state.iter().find_map(|link| match link {
+                Link::Logger(logger)
+                    if logger.logs_tag(link_id, tag_id)
+                        && query.logger_id.is_none_or(|id| id == logger.id) =>
+                {
+                    Some(logger)
                 }
+                _ => None,
+            });
+            let Some(logger) = logger else {
+                return Err(
+                    ApiError::new(ErrorCode::NotFound, format!("Tag {tk} is not logged."))
+                        .field("tks"),
+                );
+            };
+            match requests.iter_mut().find(|(id, _, _)| *id == logger.id) {
+                Some((_, _, tks)) => tks.push(tk.clone()),
+                None => requests.push((logger.id, logger.database.clone(), vec![tk.clone()])),
             }
-            Link::Inputs(link) => {
-                if link.id as u32 == data.tag_info.link_id {
-                    for tag in link.tags.iter_mut() {
-                        if tag.id as u32 == data.tag_info.tag_id {
-                            info!("Found tag to write. Value: {:?}", &data.tag_value);
-                            tag.value = data.tag_value;
-                            return Ok(StatusCode::OK);
-                        }
-                    }
+        }
+    }
+
+    let mut result = Vec::new();
+    for (logger_id, database, tks) in requests {
+        let session = state.logger_sessions.get(logger_id);
+        let mut session = session.lock().await;
+        match database
+            .query_history(&mut session, &tks, query.start, query.end)
+            .await
+        {
+            Ok(series) => {
+                for mut series in series {
+                    series.points = aggregate(&series.points, &query);
+                    result.push(series);
                 }
             }
-            _ => {
-                // TODO check for other types of tags.
-                continue;
+            Err(e) => {
+                info!("History query failed: {e}");
+                return Err(ApiError::new(
+                    ErrorCode::Upstream,
+                    format!("History query failed: {e}"),
+                ));
             }
         }
     }
-    info!("Could not find tag to write.");
-    Err(StatusCode::NOT_FOUND)
+    result.sort_by_key(|series| query.tks.iter().position(|tk| *tk == series.tk));
+    Ok(Json(result))
+}
+
+/*
+ * Prometheus scrape endpoint. Scrapers rarely log in, so it takes the bearer
+ * token of the metrics config instead of an access token.
+ */
+pub async fn get_metrics(
+    State(state): State<GlobalState>,
+    headers: axum::http::HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let authorization = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if !state.metrics.config().allows(authorization) {
+        return Err(ApiError::new(
+            ErrorCode::Unauthorized,
+            "Missing or invalid metrics token.",
+        ));
+    }
+    let body = {
+        let locked_state = state.state_db.lock().await;
+        state.metrics.render(&locked_state)
+    };
+    Ok((
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        body,
+    ))
+}
+
+// Select which tags are exported on /metrics.
+pub async fn reconfig_metrics(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(config): ApiJson<MetricsConfig>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require_unscoped(Role::Engineer)?;
+    let mut entry = AuditEntry::new(&requester, "/api/reconfigure_metrics");
+    entry.changes = config_diff(&state.metrics.config().redacted(), &config.redacted());
+    audit(&state, entry);
+
+    let file = File::create(METRICS_CONFIG_PATH);
+    if let Ok(file) = file {
+        let mut writer = BufWriter::new(file);
+        if serde_json::to_writer_pretty(&mut writer, &config).is_err() {
+            info!("Could not save the metrics config.");
+        }
+    } else {
+        info!("Could not create file");
+    }
+    state.metrics.set_config(config);
+    Ok(StatusCode::OK)
+}
+
+// Query the audit journal, newest entries first.
+pub async fn get_audit(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    ApiJson(filter): ApiJson<AuditFilter>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Engineer)?;
+    let journal = state.audit.clone();
+    // Reading the journal files is blocking IO.
+    let entries = tokio::task::spawn_blocking(move || journal.read_all::<AuditEntry>())
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    match entries {
+        Ok(entries) => {
+            let entries: Vec<AuditEntry> = entries
+                .into_iter()
+                .rev()
+                .filter(|entry| filter.matches(entry))
+                .take(filter.limit.unwrap_or(1000))
+                .collect();
+            Ok(Json(entries))
+        }
+        Err(e) => Err(ApiError::internal(format!(
+            "Could not read the audit journal: {e}"
+        ))),
+    }
+}
+
+// Dependency graph and evaluation order of all eval tags.
+pub async fn get_eval_graph(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
+    let locked_state = state.state_db.lock().await;
+    let mut graph = EvalGraph::build(&locked_state);
+    graph.retain(|node| {
+        locked_state
+            .iter()
+            .filter(|link| link.id() == Some(node.link_id))
+            .find_map(|link| link.tag(node.tag_id))
+            .is_some_and(|tag| user.allows_tag(node.link_id, tag.tk()))
+    });
+    Ok(Json(graph))
+}
+
+// Compile a formula and report errors and the variables it uses.
+pub async fn validate_eval(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    ApiJson(request): ApiJson<FormulaRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Engineer)?;
+    let mut runtime = EvalRuntime::new();
+    runtime.set_modules(state.script_modules.clone());
+    let mut report = runtime.validate(&request);
+    report.check_tags(&state.state_db.lock().await);
+    Ok(Json(report))
+}
+
+/*
+ * Run a formula once. Variables and tags take the supplied values, anything
+ * not supplied reads the current value. write_tag() calls fail, so a test
+ * never changes the plant.
+ */
+pub async fn test_eval(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    ApiJson(mut request): ApiJson<FormulaRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Engineer)?;
+    request.resolve_current(&state.state_db.lock().await);
+    let modules = state.script_modules.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let mut runtime = EvalRuntime::new();
+        runtime.set_modules(modules);
+        runtime.test(&request)
+    })
+    .await
+    .map_err(|e| ApiError::internal(e.to_string()))?;
+    Ok(Json(result))
+}
+
+pub async fn get_script_modules(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
+    Ok(Json(state.script_modules.list()))
+}
+
+fn save_script_modules(state: &GlobalState) {
+    let file = File::create(SCRIPT_MODULES_PATH);
+    if let Ok(file) = file {
+        let mut writer = BufWriter::new(file);
+        if serde_json::to_writer_pretty(&mut writer, &state.script_modules.list()).is_err() {
+            info!("Could not save the script modules.");
+        }
+    } else {
+        info!("Could not create file");
+    }
+}
+
+// Add or replace a script module. It is rejected if it doesn't compile.
+pub async fn reconfig_script_module(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(module): ApiJson<ScriptModule>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require_unscoped(Role::Engineer)?;
+    let old = state.script_modules.get(&module.name);
+    let mut entry = AuditEntry::new(&requester, "/api/reconfigure_script_module");
+    entry.changes = config_diff(&old, &Some(module.clone()));
+
+    if let Err(e) = state.script_modules.set(module) {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            "The script module does not compile.",
+        )
+        .field("source")
+        .details(e));
+    }
+    audit(&state, entry);
+    save_script_modules(&state);
+    Ok(StatusCode::OK)
+}
+
+pub async fn delete_script_module(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(query): ApiJson<ScriptModuleName>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require_unscoped(Role::Engineer)?;
+    let Some(old) = state.script_modules.remove(&query.name) else {
+        return Err(ApiError::new(
+            ErrorCode::NotFound,
+            format!("No script module named {}.", query.name),
+        )
+        .field("name"));
+    };
+    let mut entry = AuditEntry::new(&requester, "/api/delete_script_module");
+    entry.old_value = serde_json::to_value(&old).ok();
+    audit(&state, entry);
+    save_script_modules(&state);
+    Ok(StatusCode::OK)
+}
+
+// Active and unacknowledged alarms, most urgent first.
+pub async fn get_alarms(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
+    let mut alarms = state.alarms.lock().unwrap().alarms();
+    alarms.retain(|alarm| user.allows_tag(alarm.link_id, &alarm.tk));
+    Ok(Json(alarms))
+}
+
+pub async fn get_shelved_alarms(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
+    let mut alarms = state.alarms.lock().unwrap().shelved();
+    alarms.retain(|alarm| user.allows_tag(alarm.link_id, &alarm.tk));
+    Ok(Json(alarms))
+}
+
+// Journal and audit the events of an operator action. Returns the alarm ids.
+fn record_alarm_action(
+    state: &GlobalState,
+    requester: &Requester,
+    endpoint: &str,
+    events: Vec<AlarmEvent>,
+) -> Vec<String> {
+    let ids: Vec<String> = events.iter().map(|event| event.alarm.id.clone()).collect();
+    for event in events {
+        state.record_alarm_event(event);
+    }
+    if !ids.is_empty() {
+        let mut entry = AuditEntry::new(requester, endpoint);
+        entry.new_value = serde_json::to_value(&ids).ok();
+        audit(state, entry);
+    }
+    ids
+}
+
+// The ids of alarms on links or tags in the user's scope. Others are left out.
+fn permitted_alarm_ids(state: &GlobalState, user: &AuthUser, ids: &[String]) -> Vec<String> {
+    let alarms = state.alarms.lock().unwrap();
+    ids.iter()
+        .filter(|id| {
+            alarms
+                .get(id)
+                .is_some_and(|alarm| user.allows_tag(alarm.link_id, &alarm.tk))
+        })
+        .cloned()
+        .collect()
+}
+
+// Acknowledge alarms by id. Returns the ids that got acknowledged.
+pub async fn ack_alarms(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(ack): ApiJson<AlarmIds>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Operator)?;
+    let ids = permitted_alarm_ids(&state, &user, &ack.ids);
+    let events = state
+        .alarms
+        .lock()
+        .unwrap()
+        .acknowledge(&ids, requester.user.clone());
+    Ok(Json(record_alarm_action(
+        &state,
+        &requester,
+        "/api/alarms/ack",
+        events,
+    )))
+}
+
+// Shelve alarms for up to ALARM_MAX_SHELVE_MINUTES. Returns the shelved ids.
+pub async fn shelve_alarms(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(shelve): ApiJson<AlarmShelve>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Operator)?;
+    if !(shelve.minutes > 0.0 && shelve.minutes <= ALARM_MAX_SHELVE_MINUTES) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidValue,
+            format!("Shelve for more than 0 and at most {ALARM_MAX_SHELVE_MINUTES} minutes."),
+        )
+        .field("minutes"));
+    }
+    let ids = permitted_alarm_ids(&state, &user, &shelve.ids);
+    let events = state
+        .alarms
+        .lock()
+        .unwrap()
+        .shelve(&ids, shelve.minutes, requester.user.clone());
+    Ok(Json(record_alarm_action(
+        &state,
+        &requester,
+        "/api/alarms/shelve",
+        events,
+    )))
+}
+
+pub async fn unshelve_alarms(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(unshelve): ApiJson<AlarmIds>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Operator)?;
+    let ids = permitted_alarm_ids(&state, &user, &unshelve.ids);
+    let events = state
+        .alarms
+        .lock()
+        .unwrap()
+        .unshelve(&ids, requester.user.clone());
+    Ok(Json(record_alarm_action(
+        &state,
+        &requester,
+        "/api/alarms/unshelve",
+        events,
+    )))
+}
+
+// Query the alarm journal, newest events first.
+pub async fn get_alarm_history(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    ApiJson(filter): ApiJson<AlarmHistoryFilter>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
+    let journal = state.alarm_journal.clone();
+    // Reading the journal files is blocking IO.
+    let events = tokio::task::spawn_blocking(move || journal.read_all::<AlarmEvent>())
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    match events {
+        Ok(events) => {
+            let events: Vec<AlarmEvent> = events
+                .into_iter()
+                .rev()
+                .filter(|event| {
+                    filter.matches(event) && user.allows_tag(event.alarm.link_id, &event.alarm.tk)
+                })
+                .take(filter.limit.unwrap_or(1000))
+                .collect();
+            Ok(Json(events))
+        }
+        Err(e) => Err(ApiError::internal(format!(
+            "Could not read the alarm journal: {e}"
+        ))),
+    }
+}
+
+pub async fn get_alarms_config(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
+    let mut config = state.alarms.lock().unwrap().config();
+    let locked_state = state.state_db.lock().await;
+    config
+        .alarms
+        .retain(|definition| allows_tk(&user, &locked_state, &definition.tk));
+    Ok(Json(config))
+}
+
+// Replace the alarm definitions. Alarms of unchanged limits keep their state.
+pub async fn reconfig_alarms(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(config): ApiJson<AlarmConfig>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require_unscoped(Role::Engineer)?;
+    if config
+        .alarms
+        .iter()
+        .any(|definition| definition.tk.is_empty())
+    {
+        return Err(ApiError::new(ErrorCode::InvalidValue, "Every alarm needs a tag.").field("tk"));
+    }
+    let old = state.alarms.lock().unwrap().config();
+    let mut entry = AuditEntry::new(&requester, "/api/reconfigure_alarms");
+    entry.changes = config_diff(&old, &config);
+    audit(&state, entry);
+
+    save_alarms_config(&config);
+    state.alarms.lock().unwrap().set_config(config);
+    Ok(StatusCode::OK)
+}
+
+fn save_alarms_config(config: &AlarmConfig) {
+    let file = File::create(ALARMS_CONFIG_PATH);
+    if let Ok(file) = file {
+        let mut writer = BufWriter::new(file);
+        if serde_json::to_writer_pretty(&mut writer, config).is_err() {
+            info!("Could not save the alarms config.");
+        }
+    } else {
+        info!("Could not create file");
+    }
+}
+
+// Put a link under maintenance, or take it out. Its tags don't raise alarms meanwhile.
+pub async fn set_link_maintenance(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(maintenance): ApiJson<LinkMaintenance>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require_link(Role::Engineer, maintenance.link_id)?;
+    let old = state.alarms.lock().unwrap().config();
+    let mut config = old.clone();
+    config
+        .maintenance_links
+        .retain(|id| *id != maintenance.link_id);
+    if maintenance.maintenance {
+        config.maintenance_links.push(maintenance.link_id);
+    }
+    let mut entry = AuditEntry::new(&requester, "/api/alarms/maintenance");
+    entry.link_id = Some(maintenance.link_id);
+    entry.changes = config_diff(&old, &config);
+    audit(&state, entry);
+
+    save_alarms_config(&config);
+    state.alarms.lock().unwrap().set_config(config);
+    Ok(StatusCode::OK)
+}
+
+pub async fn get_notifications_config(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require_unscoped(Role::Admin)?;
+    Ok(Json(state.notifications.lock().unwrap().redacted()))
+}
+
+/*
+ * Replace the notification channels and routes. Routes must name existing channels.
+ * Admins only, since command channels run programs on the server.
+ */
+pub async fn reconfig_notifications(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(mut config): ApiJson<NotificationConfig>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require_unscoped(Role::Admin)?;
+    let unknown = config.unknown_channels();
+    if !unknown.is_empty() {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!("Routes name unknown channels: {unknown:?}"),
+        )
+        .field("routes")
+        .details(unknown));
+    }
+    let old = state.notifications.lock().unwrap().clone();
+    config.restore_secrets(&old);
+    let mut entry = AuditEntry::new(&requester, "/api/reconfigure_notifications");
+    entry.changes = config_diff(&old.redacted(), &config.redacted());
+    audit(&state, entry);
+
+    let file = File::create(NOTIFICATIONS_CONFIG_PATH);
+    if let Ok(file) = file {
+        let mut writer = BufWriter::new(file);
+        if serde_json::to_writer_pretty(&mut writer, &config).is_err() {
+            info!("Could not save the notifications config.");
+        }
+    } else {
+        info!("Could not create file");
+    }
+    *state.notifications.lock().unwrap() = config;
+    Ok(StatusCode::OK)
+}
+
+pub async fn login(
+    State(state): State<GlobalState>,
+    requester: Requester,
+    ApiJson(login): ApiJson<LoginData>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = state
+        .auth
+        .lock()
+        .unwrap()
+        .user(&login.username)
+        .filter(|user| user.enabled)
+        .cloned();
+    let known = user.is_some();
+    let password_hash = user
+        .as_ref()
+        .map_or(DUMMY_PASSWORD_HASH.to_owned(), |user| {
+            user.password_hash.clone()
+        });
+    // Hashing is slow on purpose, keep it off the runtime threads.
+    let valid = tokio::task::spawn_blocking(move || {
+        verify_password(&login.password, &password_hash) && known
+    })
+    .await
+    .map_err(|e| ApiError::internal(e.to_string()))?;
+
+    let requester = Requester {
+        user: Some(login.username),
+        ..requester
+    };
+    let mut entry = AuditEntry::new(&requester, "/api/login");
+    entry.new_value = Some(serde_json::Value::Bool(valid));
+    audit(&state, entry);
+
+    match user {
+        Some(user) if valid => {
+            let tokens = state.auth.lock().unwrap().issue_tokens(&user);
+            tokens
+                .map(Json)
+                .map_err(|_| ApiError::internal("Could not sign the tokens."))
+        }
+        _ => Err(ApiError::new(
+            ErrorCode::Unauthorized,
+            "Wrong username or password.",
+        )),
+    }
+}
+
+pub async fn refresh_token(
+    State(state): State<GlobalState>,
+    ApiJson(refresh): ApiJson<RefreshData>,
+) -> Result<impl IntoResponse, ApiError> {
+    let auth = state.auth.lock().unwrap();
+    let user = auth
+        .verify(&refresh.refresh_token, TokenKind::Refresh)
+        .ok_or_else(|| {
+            ApiError::new(ErrorCode::Unauthorized, "Invalid or expired refresh token.")
+        })?;
+    auth.issue_tokens(user)
+        .map(Json)
+        .map_err(|_| ApiError::internal("Could not sign the tokens."))
+}
+
+fn unknown_user(username: &str) -> ApiError {
+    ApiError::new(ErrorCode::NotFound, format!("No user named {username}.")).field("username")
+}
+
+fn user_info(user: &User) -> UserInfo {
+    UserInfo {
+        username: user.username.clone(),
+        enabled: user.enabled,
+        role: user.role,
+        scope: user.scope.clone(),
+    }
+}
+
+pub async fn get_users(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Admin)?;
+    let users: Vec<UserInfo> = state
+        .auth
+        .lock()
+        .unwrap()
+        .users
+        .iter()
+        .map(user_info)
+        .collect();
+    Ok(Json(users))
+}
+
+// Save a changed user store. An enabled admin has to remain, or nobody can manage users.
+fn save_users(
+    state: &GlobalState,
+    requester: &Requester,
+    endpoint: &str,
+    config: AuthConfig,
+    old: Option<UserInfo>,
+    new: Option<UserInfo>,
+    password_changed: bool,
+) -> Result<(), ApiError> {
+    if !has_enabled_admin(&config) {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            "At least one enabled admin has to remain.",
+        ));
+    }
+    // Password hashes stay out of the audit journal.
+    let mut entry = AuditEntry::new(requester, endpoint);
+    entry.old_value = old.and_then(|user| serde_json::to_value(user).ok());
+    entry.new_value = new.and_then(|user| serde_json::to_value(user).ok());
+    if password_changed {
+        entry.changes = vec![ConfigChange {
+            path: "password".to_owned(),
+            old: serde_json::Value::Null,
+            new: serde_json::Value::String("changed".to_owned()),
+        }];
+    }
+    audit(state, entry);
+
+    save_auth_config(&config);
+    *state.auth.lock().unwrap() = config;
+    Ok(())
+}
+
+pub async fn set_user(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(data): ApiJson<UserData>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Admin)?;
+    if data.username.is_empty() {
+        return Err(
+            ApiError::new(ErrorCode::InvalidValue, "The username is empty.").field("username"),
+        );
+    }
+    if data.password.as_ref().is_some_and(|p| p.is_empty()) {
+        return Err(
+            ApiError::new(ErrorCode::InvalidValue, "The password is empty.").field("password"),
+        );
+    }
+    let password_hash = match data.password {
+        Some(password) => Some(
+            tokio::task::spawn_blocking(move || crate::hash_password(&password))
+                .await
+                .map_err(|e| ApiError::internal(e.to_string()))?
+                .map_err(|_| ApiError::internal("Could not hash the password."))?,
+        ),
+        None => None,
+    };
+
+    let mut config = state.auth.lock().unwrap().clone();
+    let old = config.user(&data.username).map(user_info);
+    match config
+        .users
+        .iter_mut()
+        .find(|user| user.username == data.username)
+    {
+        Some(user) => {
+            if let Some(password_hash) = password_hash.clone() {
+                user.password_hash = password_hash;
+                user.token_version += 1;
+            }
+            if let Some(enabled) = data.enabled
+                && enabled != user.enabled
+            {
+                user.enabled = enabled;
+                user.token_version += 1;
+            }
+        }
+        None => {
+            let Some(password_hash) = password_hash.clone() else {
+                return Err(
+                    ApiError::new(ErrorCode::InvalidValue, "New users need a password.")
+                        .field("password"),
+                );
+            };
+            config.users.push(User {
+                username: data.username.clone(),
+                password_hash,
+                enabled: data.enabled.unwrap_or(true),
+                role: data.role.unwrap_or_default(),
+                scope: None,
+                token_version: 0,
+            });
+        }
+    }
+    let new = config.user(&data.username).map(user_info);
+    let password_changed = password_hash.is_some();
+    save_users(
+        &state,
+        &requester,
+        "/api/users/set",
+        config,
+        old,
+        new,
+        password_changed,
+    )?;
+    Ok(StatusCode::OK)
+}
+
+// Set the role of a user and the links and tags it is limited to.
+pub async fn set_user_permissions(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(permissions): ApiJson<UserPermissions>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Admin)?;
+    let mut config = state.auth.lock().unwrap().clone();
+    let Some(target) = config
+        .users
+        .iter_mut()
+        .find(|user| user.username == permissions.username)
+    else {
+        return Err(unknown_user(&permissions.username));
+    };
+    let old = Some(user_info(target));
+    target.role = permissions.role;
+    target.scope = permissions.scope;
+    let new = Some(user_info(target));
+    save_users(
+        &state,
+        &requester,
+        "/api/users/permissions",
+        config,
+        old,
+        new,
+        false,
+    )?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn delete_user(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(data): ApiJson<UserName>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Admin)?;
+    let mut config = state.auth.lock().unwrap().clone();
+    let old = config.user(&data.username).map(user_info);
+    if old.is_none() {
+        return Err(unknown_user(&data.username));
+    }
+    config.users.retain(|user| user.username != data.username);
+    save_users(
+        &state,
+        &requester,
+        "/api/users/delete",
+        config,
+        old,
+        None,
+        false,
+    )?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn get_auth_settings(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Admin)?;
+    let config = state.auth.lock().unwrap();
+    Ok(Json(AuthSettings {
+        access_token_minutes: config.access_token_minutes,
+        refresh_token_minutes: config.refresh_token_minutes,
+        rotate_signing_key: false,
+    }))
+}
+
+pub async fn reconfig_auth(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    ApiJson(settings): ApiJson<AuthSettings>,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Admin)?;
+    if settings.access_token_minutes <= 0
+        || settings.refresh_token_minutes < settings.access_token_minutes
+    {
+        return Err(ApiError::new(
+            ErrorCode::InvalidValue,
+            "Access tokens need a positive lifetime, no longer than refresh tokens.",
+        )
+        .field("access_token_minutes"));
+    }
+    let mut config = state.auth.lock().unwrap().clone();
+    let old = AuthSettings {
+        access_token_minutes: config.access_token_minutes,
+        refresh_token_minutes: config.refresh_token_minutes,
+        rotate_signing_key: false,
+    };
+    config.access_token_minutes = settings.access_token_minutes;
+    config.refresh_token_minutes = settings.refresh_token_minutes;
+    if settings.rotate_signing_key {
+        config.signing_key = generate_secret()
+            .map_err(|_| ApiError::internal("Could not generate a signing key."))?;
+    }
+    let mut entry = AuditEntry::new(&requester, "/api/reconfigure_auth");
+    entry.changes = config_diff(&old, &settings);
+    audit(&state, entry);
+
+    save_auth_config(&config);
+    *state.auth.lock().unwrap() = config;
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AccessScope;
+    use crate::test_support::{test_links, test_state};
+    use axum::response::Response;
+    use serde_json::{Value, json};
+
+    fn user(role: Role, scope: Option<AccessScope>) -> AuthUser {
+        AuthUser {
+            username: "test".to_owned(),
+            role,
+            scope,
+        }
+    }
+
+    fn requester() -> Requester {
+        Requester {
+            user: Some("test".to_owned()),
+            ip: None,
+        }
+    }
+
+    async fn body_json(response: Response) -> Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn batch(atomic: bool) -> TagBatchWrite {
+        serde_json::from_value(json!({
+            "atomic": atomic,
+            "writes": [
+                {"key": "LK0:000", "value": {"Real": 1.5}},
+                {"key": {"link_id": 1, "tag_id": 0}, "value": {"Real": 2.0}},
+                {"key": "LK0:002", "value": {"Bit": true}},
+                {"key": "LK9:000", "value": {"Real": 1.0}},
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn statuses(results: &Value) -> Vec<Value> {
+        results
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result["status"].clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn batch_writes_apply_the_valid_items() {
+        let state = test_state("batch-write");
+        let response = write_tags(
+            State(state.clone()),
+            user(Role::Operator, None),
+            requester(),
+            ApiJson(batch(false)),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let results = body_json(response).await;
+        assert_eq!(
+            statuses(&results),
+            vec![
+                json!("Queued"),
+                json!("Written"),
+                json!({"Rejected": "Bit tags can't be written yet."}),
+                json!({"Rejected": "Unknown tag."}),
+            ]
+        );
+
+        let links = state.state_db.lock().await;
+        let (Link::Device(device), Link::Inputs(inputs)) = (&links[0], &links[1]) else {
+            unreachable!()
+        };
+        assert_eq!(device.tags[0].pending_write, Some(TagValue::Real(1.5)));
+        assert_eq!(inputs.tags[0].value, TagValue::Real(2.0));
+    }
+
+    #[tokio::test]
+    async fn atomic_batch_writes_nothing_when_an_item_fails() {
+        let state = test_state("batch-atomic");
+        let error = write_tags(
+            State(state.clone()),
+            user(Role::Operator, None),
+            requester(),
+            ApiJson(batch(true)),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(error.code, ErrorCode::ValidationFailed);
+
+        let links = state.state_db.lock().await;
+        let (Link::Device(device), Link::Inputs(inputs)) = (&links[0], &links[1]) else {
+            unreachable!()
+        };
+        assert_eq!(device.tags[0].pending_write, None);
+        assert_eq!(inputs.tags[0].value, TagValue::Real(0.0));
+    }
+
+    #[tokio::test]
+    async fn batch_writes_outside_the_scope_are_rejected() {
+        let state = test_state("batch-scope");
+        let operator = user(
+            Role::Operator,
+            Some(AccessScope {
+                links: vec![1],
+                tks: Vec::new(),
+            }),
+        );
+        let response = write_tags(State(state), operator, requester(), ApiJson(batch(false)))
+            .await
+            .unwrap()
+            .into_response();
+        let results = body_json(response).await;
+        assert_eq!(statuses(&results)[0], json!({"Rejected": "Not permitted."}));
+        assert_eq!(statuses(&results)[1], json!("Written"));
+    }
+
+    fn scoped(role: Role, links: &[usize], tks: &[&str]) -> AuthUser {
+        user(
+            role,
+            Some(AccessScope {
+                links: links.to_vec(),
+                tks: tks.iter().map(|tk| tk.to_string()).collect(),
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn evals_can_only_write_tags_in_the_engineers_scope() {
+        let state = test_state("eval-scope");
+        let mut eval = Eval::new(0, "EVAL2:000".to_owned(), "EVAL0".to_owned());
+        eval.writable_tags = vec!["LK0:000".to_owned()];
+        let config = || EvalReconfigData {
+            tag_info: TagIdQuery {
+                link_id: 2,
+                tag_id: 0,
+            },
+            tag_data: eval.clone(),
+        };
+
+        let engineer = scoped(Role::Engineer, &[2], &[]);
+        let error = reconfig_eval(
+            State(state.clone()),
+            engineer,
+            requester(),
+            Ok(ApiJson(config())),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+
+        let engineer = scoped(Role::Engineer, &[0, 2], &[]);
+        assert!(
+            reconfig_eval(State(state), engineer, requester(), Ok(ApiJson(config())))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn global_config_needs_an_unscoped_user() {
+        let state = test_state("global-config");
+        let engineer = scoped(Role::Engineer, &[0], &[]);
+        let error = reconfig_metrics(
+            State(state.clone()),
+            engineer.clone(),
+            requester(),
+            ApiJson(state.metrics.config()),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+
+        // Notifications can run programs, so even unscoped engineers can't change them.
+        for user in [engineer, user(Role::Engineer, None)] {
+            let error = reconfig_notifications(
+                State(state.clone()),
+                user.clone(),
+                requester(),
+                ApiJson(NotificationConfig::default()),
+            )
+            .await
+            .err()
+            .unwrap();
+            assert_eq!(error.code, ErrorCode::Forbidden);
+            assert!(
+                get_notifications_config(State(state.clone()), user)
+                    .await
+                    .is_err()
+            );
+        }
+    }
+
+    // Raise a high alarm on each input tag.
+    async fn raise_alarms(state: &GlobalState) {
+        let mut links = state.state_db.lock().await;
+        for tag in ["IN:0", "IN:1"] {
+            let (link_id, tag_id) = locate_tk(&links, tag).unwrap();
+            crate::write_tag(&mut links, link_id, tag_id, TagValue::Real(20.0));
+        }
+        let config: AlarmConfig = serde_json::from_value(json!({
+            "alarms": [{"tk": "IN:0", "h": 10.0}, {"tk": "IN:1", "h": 10.0}],
+        }))
+        .unwrap();
+        let mut alarms = state.alarms.lock().unwrap();
+        alarms.set_config(config);
+        alarms.evaluate(&mut links, std::time::Instant::now());
+    }
+
+    #[tokio::test]
+    async fn operators_only_acknowledge_alarms_in_their_scope() {
+        let state = test_state("alarm-scope");
+        raise_alarms(&state).await;
+        let ids: Vec<String> = state
+            .alarms
+            .lock()
+            .unwrap()
+            .alarms()
+            .into_iter()
+            .map(|alarm| alarm.id)
+            .collect();
+        assert_eq!(ids.len(), 2);
+
+        let operator = scoped(Role::Operator, &[], &["IN:0"]);
+        let response = ack_alarms(
+            State(state.clone()),
+            operator,
+            requester(),
+            ApiJson(AlarmIds { ids }),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(body_json(response).await, json!(["IN:0:H"]));
+        let alarms = state.alarms.lock().unwrap();
+        assert!(alarms.get("IN:0:H").unwrap().acked);
+        assert!(!alarms.get("IN:1:H").unwrap().acked);
+    }
+
+    #[tokio::test]
+    async fn scoped_reads_only_return_links_and_tags_in_the_scope() {
+        let state = test_state("read-scope");
+        raise_alarms(&state).await;
+        let viewer = || scoped(Role::Viewer, &[0], &["IN:1"]);
+
+        let response = get_links_config(State(state.clone()), viewer())
+            .await
+            .unwrap()
+            .into_response();
+        let links: Vec<Link> = serde_json::from_value(body_json(response).await).unwrap();
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].tag_count(), 4);
+        assert_eq!(links[1].id(), Some(1));
+        assert_eq!(links[1].tag_at(0).unwrap().tk(), "IN:1");
+        assert_eq!(links[1].tag_count(), 1);
+
+        let tag = |link_id, tag_id| ApiJson(TagIdQuery { link_id, tag_id });
+        assert!(
+            get_tag_config(State(state.clone()), viewer(), tag(0, 1))
+                .await
+                .is_ok()
+        );
+        assert!(
+            get_tag_config(State(state.clone()), viewer(), tag(1, 1))
+                .await
+                .is_ok()
+        );
+        let error = get_tag_config(State(state.clone()), viewer(), tag(1, 0))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.code, ErrorCode::TagNotFound);
+        let error = get_device_link_config(
+            State(state.clone()),
+            scoped(Role::Viewer, &[1], &[]),
+            ApiJson(LinkIdQuery { link_id: 0 }),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(error.code, ErrorCode::LinkNotFound);
+
+        let key = |tk: &str| ApiJson(TagKey::Tk(tk.to_owned()));
+        assert!(
+            read_tag_value(State(state.clone()), viewer(), key("IN:1"))
+                .await
+                .is_ok()
+        );
+        let error = read_tag_value(State(state.clone()), viewer(), key("IN:0"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.code, ErrorCode::TagNotFound);
+        let request = TagBatchRead {
+            keys: vec![TagKey::Tk("IN:0".to_owned()), TagKey::Tk("IN:1".to_owned())],
+        };
+        let response = read_tag_values(State(state.clone()), viewer(), ApiJson(request))
+            .await
+            .unwrap()
+            .into_response();
+        let results = &body_json(response).await["results"];
+        assert!(results[0]["reading"].is_null());
+        assert_eq!(results[1]["reading"]["tk"], "IN:1");
+
+        let query: HistoryQuery = serde_json::from_value(json!({
+            "tks": ["IN:0"],
+            "start": "2026-01-01T00:00:00Z",
+            "end": "2026-01-02T00:00:00Z",
+        }))
+        .unwrap();
+        let error = get_history(State(state.clone()), viewer(), ApiJson(query))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.code, ErrorCode::TagNotFound);
+
+        let response = get_alarms(State(state.clone()), viewer())
+            .await
+            .unwrap()
+            .into_response();
+        let alarms = body_json(response).await;
+        assert_eq!(alarms.as_array().unwrap().len(), 1);
+        assert_eq!(alarms[0]["id"], "IN:1:H");
+        let response = get_alarms_config(State(state.clone()), viewer())
+            .await
+            .unwrap()
+            .into_response();
+        let config = body_json(response).await;
+        assert_eq!(config["alarms"].as_array().unwrap().len(), 1);
+        assert_eq!(config["alarms"][0]["tk"], "IN:1");
+
+        let response = get_eval_graph(State(state.clone()), viewer())
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(body_json(response).await["nodes"], json!([]));
+        let response = get_eval_graph(State(state), scoped(Role::Viewer, &[2], &[]))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(
+            body_json(response).await["nodes"].as_array().unwrap().len(),
+            1
+        );
+    }
+
+    fn logger(id: usize) -> LoggerLink {
+        serde_json::from_value(json!({
+            "name": format!("Log {id}"),
+            "id": id,
+            "database": {"PrometheusRemoteWrite": {"url": "http://127.0.0.1:9/api/v1/write"}},
+            "status": "Normal",
+            "tags": [],
+            "log_delay_millis": 1000,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn logger_links_have_their_own_limit() {
+        let state = test_state("logger-limit");
+        let engineer = user(Role::Engineer, None);
+
+        let mut links = test_links();
+        links.extend((3..=MAX_NUM_LOGGER_LINKS + 3).map(|id| Link::Logger(logger(id))));
+        let error = reconfig_links(
+            State(state.clone()),
+            engineer.clone(),
+            requester(),
+            ApiJson(links),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(error.code, ErrorCode::ValidationFailed);
+        assert_eq!(error.details.unwrap()["count"], MAX_NUM_LOGGER_LINKS + 1);
+
+        // Adding one more logger to a full set is rejected before it starts.
+        state
+            .state_db
+            .lock()
+            .await
+            .extend((3..MAX_NUM_LOGGER_LINKS + 3).map(|id| Link::Logger(logger(id))));
+        let id = MAX_NUM_LOGGER_LINKS + 3;
+        let error = reconfig_logger(
+            State(state.clone()),
+            engineer,
+            requester(),
+            ApiJson(logger(id)),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(error.code, ErrorCode::ValidationFailed);
+        assert_eq!(state.state_db.lock().await.len(), id);
+    }
+
+    #[tokio::test]
+    async fn metrics_need_the_configured_token() {
+        let state = test_state("metrics-token");
+        let mut headers = axum::http::HeaderMap::new();
+        assert!(
+            get_metrics(State(state.clone()), headers.clone())
+                .await
+                .is_ok()
+        );
+
+        state.metrics.set_config(MetricsConfig {
+            tags: vec!["IN:0".to_owned()],
+            token: "secret".to_owned(),
+        });
+        let error = get_metrics(State(state.clone()), headers.clone())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.code, ErrorCode::Unauthorized);
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            "Bearer secret".parse().unwrap(),
+        );
+        let response = get_metrics(State(state), headers)
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn formula(formula: &str, values: Value) -> FormulaRequest {
+        serde_json::from_value(json!({
+            "formula": formula,
+            "vars": [{"name": "x", "link_id": 1, "tag_id": 0, "value": {"Real": 0.0}}],
+            "value": {"Real": 0.0},
+            "values": values,
+        }))
+        .unwrap()
+    }
+
+    async fn validate(state: &GlobalState, formula_text: &str) -> Value {
+        let response = validate_eval(
+            State(state.clone()),
+            user(Role::Engineer, None),
+            ApiJson(formula(formula_text, json!({}))),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        body_json(response).await
+    }
+
+    async fn run(state: &GlobalState, formula_text: &str, values: Value) -> Value {
+        let response = test_eval(
+            State(state.clone()),
+            user(Role::Engineer, None),
+            ApiJson(formula(formula_text, values)),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        body_json(response).await
+    }
+
+    #[tokio::test]
+    async fn validate_eval_reports_errors_with_positions() {
+        let state = test_state("validate-eval");
+
+        let report = validate(&state, "x * 2.0 + tag(\"IN:1\")").await;
+        assert_eq!(report["valid"], true);
+        assert_eq!(report["variables"], json!(["x"]));
+        assert_eq!(report["tags"], json!(["IN:1"]));
+
+        for (text, line, column, message) in [
+            ("let a = 1.0;\na + y", 2, 5, "Undefined variable: y"),
+            ("let a = 1.0;\nlet b = a +;", 2, 12, "Unexpected ';'"),
+            (
+                "let k = \"IN:\" + 1;\ntag(k)",
+                2,
+                1,
+                "tag() takes the tag key in quotes, e.g. tag(\"LK0:000\")",
+            ),
+        ] {
+            let report = validate(&state, text).await;
+            assert_eq!(report["valid"], false, "{text}");
+            assert_eq!(
+                report["errors"],
+                json!([{"line": line, "column": column, "message": message}]),
+                "{text}"
+            );
+        }
+
+        // Unknown tags have no position, they are looked up after compiling.
+        let report = validate(&state, "tag(\"LK9:000\") + tag(\"IN:1\")").await;
+        assert_eq!(report["valid"], false);
+        assert_eq!(
+            report["errors"],
+            json!([{"line": null, "column": null, "message": "Unknown tag: LK9:000"}])
+        );
+
+        let error = validate_eval(
+            State(state),
+            user(Role::Operator, None),
+            ApiJson(formula("1.0", json!({}))),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+    }
+
+    #[tokio::test]
+    async fn test_eval_runs_once_without_writing() {
+        let state = test_state("test-eval");
+        crate::write_tag(&mut state.state_db.lock().await, 1, 1, TagValue::Real(4.0));
+
+        // Values that aren't supplied are read from the links.
+        let result = run(&state, "x + tag(\"IN:1\")", json!({"x": {"Real": 1.5}})).await;
+        assert_eq!(result["result"], 5.5);
+        assert_eq!(result["value"], json!({"Real": 5.5}));
+        let result = run(&state, "x + tag(\"IN:1\")", json!({"IN:1": {"Real": 2.0}})).await;
+        assert_eq!(result["result"], 2.0);
+
+        let result = run(&state, "loop {}", json!({})).await;
+        assert_eq!(result["valid"], true);
+        assert_eq!(result["result"], Value::Null);
+        assert_eq!(
+            result["error"],
+            json!({"line": 1, "column": 6, "message": "Too many operations (line 1, position 6)"})
+        );
+
+        let result = run(&state, "write_tag(\"LK0:000\", 5.0); 1.0", json!({})).await;
+        assert_eq!(result["result"], Value::Null);
+        assert!(
+            result["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("Tag LK0:000 is not writable from this eval")
+        );
+        let links = state.state_db.lock().await;
+        let Link::Device(device) = &links[0] else {
+            unreachable!()
+        };
+        assert!(device.tags.iter().all(|tag| tag.pending_write.is_none()));
+    }
+
+    async fn read(state: &GlobalState, key: Value) -> Result<Value, ApiError> {
+        let key: TagKey = serde_json::from_value(key).unwrap();
+        let response = read_tag_value(State(state.clone()), user(Role::Viewer, None), ApiJson(key))
+            .await?
+            .into_response();
+        Ok(body_json(response).await)
+    }
+
+    #[tokio::test]
+    async fn tags_are_read_by_key_or_ids() {
+        let state = test_state("read-tag");
+        crate::write_tag(&mut state.state_db.lock().await, 1, 1, TagValue::Real(2.5));
+
+        let reading = read(&state, json!("IN:1")).await.unwrap();
+        assert_eq!(reading["link_id"], 1);
+        assert_eq!(reading["tag_id"], 1);
+        assert_eq!(reading["value"], json!({"Real": 2.5}));
+        let reading = read(&state, json!({"link_id": 0, "tag_id": 1}))
+            .await
+            .unwrap();
+        assert_eq!(reading["tk"], "LK0:001");
+        assert_eq!(reading["value"], json!({"Int": 0}));
+        // Disabled tags can still be read.
+        let reading = read(&state, json!("LK0:003")).await.unwrap();
+        assert_eq!(reading["enabled"], false);
+
+        for key in [
+            json!("LK9:000"),
+            json!("IN"),
+            json!({"link_id": 1, "tag_id": 9}),
+            json!({"link_id": 9, "tag_id": 0}),
+        ] {
+            let error = read(&state, key.clone()).await.err().unwrap();
+            assert_eq!(error.code, ErrorCode::TagNotFound, "{key}");
+            assert_eq!(error.status, StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_reads_keep_the_key_order() {
+        let state = test_state("read-tags");
+        let request: TagBatchRead = serde_json::from_value(json!({
+            "keys": [
+                "IN:1",
+                "LK9:000",
+                {"link_id": 0, "tag_id": 2},
+                "IN:1",
+                {"link_id": 1, "tag_id": 7},
+                "EVAL2:000",
+            ],
+        }))
+        .unwrap();
+        let response = read_tag_values(
+            State(state.clone()),
+            user(Role::Viewer, None),
+            ApiJson(request),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        let body = body_json(response).await;
+        assert!(body["timestamp"].is_string());
+
+        let results = body["results"].as_array().unwrap();
+        let keys: Vec<&Value> = results.iter().map(|result| &result["key"]).collect();
+        assert_eq!(
+            keys,
+            [
+                &json!("IN:1"),
+                &json!("LK9:000"),
+                &json!({"link_id": 0, "tag_id": 2}),
+                &json!("IN:1"),
+                &json!({"link_id": 1, "tag_id": 7}),
+                &json!("EVAL2:000"),
+            ]
+        );
+        let tks: Vec<&Value> = results
+            .iter()
+            .map(|result| &result["reading"]["tk"])
+            .collect();
+        assert_eq!(
+            tks,
+            [
+                &json!("IN:1"),
+                &Value::Null,
+                &json!("LK0:002"),
+                &json!("IN:1"),
+                &Value::Null,
+                &json!("EVAL2:000"),
+            ]
+        );
+
+        let request = TagBatchRead { keys: Vec::new() };
+        let response = read_tag_values(State(state), user(Role::Viewer, None), ApiJson(request))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(body_json(response).await["results"], json!([]));
+    }
 }
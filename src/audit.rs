@@ -0,0 +1,188 @@
+use crate::AuthUser;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+pub const AUDIT_DIR: &str = "./Audit";
+pub const AUDIT_MAX_FILE_BYTES: u64 = 10 * 1024 * 1024;
+pub const AUDIT_MAX_FILES: usize = 10;
+
+// Fields that change on every poll or evaluation and are left out of config diffs.
+const RUNTIME_FIELDS: [&str; 6] = [
+    "value",
+    "status",
+    "changed_at",
+    "last_poll_time",
+    "scan_time",
+    "scan_time_us",
+];
+
+// Who sent a request. Extracted in every handler that changes the plant.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Requester {
+    pub user: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Requester {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|info| info.0.ip().to_string());
+        let user = parts
+            .extensions
+            .get::<AuthUser>()
+            .map(|user| user.username.clone());
+        Ok(Self { user, ip })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConfigChange {
+    pub path: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub requester: Requester,
+    pub endpoint: String,
+    pub link_id: Option<usize>,
+    pub tag_id: Option<usize>,
+    pub old_value: Option<Value>,
+    pub new_value: Option<Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<ConfigChange>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub user: Option<String>,
+    pub endpoint: Option<String>,
+    pub link_id: Option<usize>,
+    pub tag_id: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+impl AuditEntry {
+    pub fn new(requester: &Requester, endpoint: &str) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            requester: requester.clone(),
+            endpoint: endpoint.to_owned(),
+            link_id: None,
+            tag_id: None,
+            old_value: None,
+            new_value: None,
+            changes: Vec::new(),
+        }
+    }
+}
+
+impl AuditFilter {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp < to)
+            && self
+                .user
+                .as_ref()
+                .is_none_or(|user| entry.requester.user.as_ref() == Some(user))
+            && self
+                .endpoint
+                .as_ref()
+                .is_none_or(|endpoint| entry.endpoint == *endpoint)
+            && self.link_id.is_none_or(|id| entry.link_id == Some(id))
+            && self.tag_id.is_none_or(|id| entry.tag_id == Some(id))
+    }
+}
+
+// List the configuration fields that differ between two versions.
+pub fn config_diff<T: Serialize>(old: &T, new: &T) -> Vec<ConfigChange> {
+    let mut changes = Vec::new();
+    if let (Ok(old), Ok(new)) = (serde_json::to_value(old), serde_json::to_value(new)) {
+        diff_values(String::new(), &old, &new, &mut changes);
+    }
+    changes
+}
+
+fn diff_values(path: String, old: &Value, new: &Value, changes: &mut Vec<ConfigChange>) {
+    match (old, new) {
+        (Value::Object(old_map), Value::Object(new_map)) => {
+            let keys = old_map
+                .keys()
+                .chain(new_map.keys().filter(|key| !old_map.contains_key(*key)));
+            for key in keys {
+                if RUNTIME_FIELDS.contains(&key.as_str()) {
+                    continue;
+                }
+                diff_values(
+                    format!("{path}/{key}"),
+                    old_map.get(key).unwrap_or(&Value::Null),
+                    new_map.get(key).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        (Value::Array(old_list), Value::Array(new_list)) => {
+            for i in 0..old_list.len().max(new_list.len()) {
+                diff_values(
+                    format!("{path}/{i}"),
+                    old_list.get(i).unwrap_or(&Value::Null),
+                    new_list.get(i).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        _ => {
+            if old != new {
+                changes.push(ConfigChange {
+                    path,
+                    old: old.clone(),
+                    new: new.clone(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_links;
+    use crate::{Link, TagValue};
+
+    #[test]
+    fn runtime_fields_are_not_config_changes() {
+        let old = test_links();
+        let mut new = old.clone();
+        let Link::Eval(link) = &mut new[2] else {
+            unreachable!()
+        };
+        link.scan_time_us = 1234;
+        link.tags[0].value = TagValue::Real(2.0);
+        let Link::Device(device) = &mut new[0] else {
+            unreachable!()
+        };
+        device.scan_time = 12;
+        assert_eq!(config_diff(&old, &new), Vec::new());
+
+        let Link::Eval(link) = &mut new[2] else {
+            unreachable!()
+        };
+        link.tags[0].formula = "2.0".to_owned();
+        let changes = config_diff(&old, &new);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, "/2/Eval/tags/0/formula");
+    }
+}
@@ -0,0 +1,524 @@
+use crate::{ApiError, ErrorCode, GlobalState};
+use anyhow::{Result, anyhow};
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::header;
+use axum::http::request::Parts;
+use axum::middleware::Next;
+use axum::response::Response;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use log::info;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufWriter;
+use std::num::NonZeroU32;
+
+pub const AUTH_CONFIG_PATH: &str = "./CurrentConfig/auth_config.json";
+
+const PASSWORD_ALGORITHM: &str = "pbkdf2-sha256";
+const PASSWORD_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+// Length of password hashes, generated signing keys and initial passwords.
+const SECRET_LEN: usize = 32;
+// Checked for unknown users, so a login takes as long whether the user exists or not.
+pub const DUMMY_PASSWORD_HASH: &str =
+    "pbkdf2-sha256$100000$Y0ea1poJCyWCd+yPum+ZQQ$Hc5k8HXG0ycI0YJ6j4scHpZNbA5H0BsXmGIKERibYdI";
+// Routes that accept the access token as a query parameter.
+const QUERY_TOKEN_PREFIX: &str = "/api/stream/";
+
+// Each role can do everything the ones before it can.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Role {
+    // Reads values, alarms and history.
+    #[default]
+    Viewer,
+    // Writes tags and handles alarms.
+    Operator,
+    // Reconfigures links, evals, loggers and alarms.
+    Engineer,
+    // Manages users, token settings and notifications.
+    Admin,
+}
+
+// Links and tags a user may write or reconfigure, and see in tag streams.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccessScope {
+    #[serde(default)]
+    pub links: Vec<usize>,
+    #[serde(default)]
+    pub tks: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    pub password_hash: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub role: Role,
+    // None means the user is not limited to some links or tags.
+    #[serde(default)]
+    pub scope: Option<AccessScope>,
+    // Bumped on password changes, so tokens issued before stop working.
+    #[serde(default)]
+    pub token_version: u32,
+}
+
+// Users and token settings. Holds secrets, so never sent back by the API as is.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub signing_key: String,
+    #[serde(default = "default_access_minutes")]
+    pub access_token_minutes: i64,
+    #[serde(default = "default_refresh_minutes")]
+    pub refresh_token_minutes: i64,
+    #[serde(default)]
+    pub users: Vec<User>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenKind {
+    Access,
+    Refresh,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub kind: TokenKind,
+    pub ver: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    // Lifetime of the access token in seconds.
+    pub expires_in: i64,
+}
+
+// The user a request was authenticated as. Put in the request extensions by require_auth.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthUser {
+    pub username: String,
+    pub role: Role,
+    pub scope: Option<AccessScope>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_access_minutes() -> i64 {
+    15
+}
+
+fn default_refresh_minutes() -> i64 {
+    12 * 60
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            signing_key: String::new(),
+            access_token_minutes: default_access_minutes(),
+            refresh_token_minutes: default_refresh_minutes(),
+            users: Vec::new(),
+        }
+    }
+}
+
+impl User {
+    pub fn new(username: String, password: &str, role: Role) -> Result<Self> {
+        Ok(Self {
+            username,
+            password_hash: hash_password(password)?,
+            enabled: true,
+            role,
+            scope: None,
+            token_version: 0,
+        })
+    }
+}
+
+impl AccessScope {
+    pub fn allows_link(&self, link_id: usize) -> bool {
+        self.links.contains(&link_id)
+    }
+
+    pub fn allows_tag(&self, link_id: usize, tk: &str) -> bool {
+        self.allows_link(link_id) || self.tks.iter().any(|scope_tk| scope_tk == tk)
+    }
+}
+
+/*
+ * Permission checks for the handlers. A missing role or a link or tag
+ * outside the user's scope is a forbidden error. Admins have no scope.
+ */
+impl AuthUser {
+    pub fn require(&self, role: Role) -> Result<(), ApiError> {
+        if self.role >= role {
+            Ok(())
+        } else {
+            info!("{} needs the {role:?} role.", self.username);
+            Err(ApiError::new(
+                ErrorCode::Forbidden,
+                format!("Needs the {role:?} role."),
+            ))
+        }
+    }
+
+    fn in_scope(&self, allowed: impl FnOnce(&AccessScope) -> bool) -> bool {
+        match &self.scope {
+            Some(scope) => self.role == Role::Admin || allowed(scope),
+            None => true,
+        }
+    }
+
+    fn require_scope(&self, allowed: impl FnOnce(&AccessScope) -> bool) -> Result<(), ApiError> {
+        if self.in_scope(allowed) {
+            return Ok(());
+        }
+        info!("{} is not permitted here.", self.username);
+        Err(ApiError::new(
+            ErrorCode::Forbidden,
+            "Not permitted for this link or tag.",
+        ))
+    }
+
+    // For filtering lists. True when the user has no scope or it covers the link.
+    // Whether the user sees every link and tag.
+    pub fn unscoped(&self) -> bool {
+        self.in_scope(|_| false)
+    }
+
+    pub fn allows_link(&self, link_id: usize) -> bool {
+        self.in_scope(|scope| scope.allows_link(link_id))
+    }
+
+    pub fn allows_tag(&self, link_id: usize, tk: &str) -> bool {
+        self.in_scope(|scope| scope.allows_tag(link_id, tk))
+    }
+
+    pub fn require_link(&self, role: Role, link_id: usize) -> Result<(), ApiError> {
+        self.require(role)?;
+        self.require_scope(|scope| scope.allows_link(link_id))
+    }
+
+    pub fn require_tag(&self, role: Role, link_id: usize, tk: &str) -> Result<(), ApiError> {
+        self.require(role)?;
+        self.require_scope(|scope| scope.allows_tag(link_id, tk))
+    }
+
+    // For changes that touch every link.
+    pub fn require_unscoped(&self, role: Role) -> Result<(), ApiError> {
+        self.require(role)?;
+        self.require_scope(|_| false)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<AuthUser>()
+            .cloned()
+            .ok_or_else(unauthorized)
+    }
+}
+
+impl AuthConfig {
+    pub fn user(&self, username: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.username == username)
+    }
+
+    pub fn issue_tokens(&self, user: &User) -> Result<TokenPair> {
+        Ok(TokenPair {
+            access_token: self.token(user, TokenKind::Access, self.access_token_minutes)?,
+            refresh_token: self.token(user, TokenKind::Refresh, self.refresh_token_minutes)?,
+            token_type: "Bearer".to_owned(),
+            expires_in: self.access_token_minutes * 60,
+        })
+    }
+
+    fn token(&self, user: &User, kind: TokenKind, minutes: i64) -> Result<String> {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            sub: user.username.clone(),
+            iat: now,
+            exp: now + minutes * 60,
+            kind,
+            ver: user.token_version,
+        };
+        let key = EncodingKey::from_secret(self.signing_key.as_bytes());
+        Ok(jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &key,
+        )?)
+    }
+
+    /*
+     * Check the signature and expiry of a token. The user must still exist,
+     * be enabled and not have changed their password since it was issued.
+     */
+    pub fn verify(&self, token: &str, kind: TokenKind) -> Option<&User> {
+        let key = DecodingKey::from_secret(self.signing_key.as_bytes());
+        let validation = Validation::new(Algorithm::HS256);
+        let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .ok()?
+            .claims;
+        let user = self.user(&claims.sub)?;
+        (claims.kind == kind && user.enabled && user.token_version == claims.ver).then_some(user)
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| anyhow!("No random source for the salt"))?;
+    let mut hash = [0u8; SECRET_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PASSWORD_ITERATIONS).unwrap(),
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    Ok(format!(
+        "{PASSWORD_ALGORITHM}${PASSWORD_ITERATIONS}${}${}",
+        STANDARD_NO_PAD.encode(salt),
+        STANDARD_NO_PAD.encode(hash)
+    ))
+}
+
+// Stored as pbkdf2-sha256$iterations$salt$hash, salt and hash in base64.
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    let mut parts = password_hash.split('$');
+    let (Some(PASSWORD_ALGORITHM), Some(iterations), Some(salt), Some(hash), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return false;
+    };
+    let (Some(iterations), Ok(salt), Ok(hash)) = (
+        iterations.parse().ok().and_then(NonZeroU32::new),
+        STANDARD_NO_PAD.decode(salt),
+        STANDARD_NO_PAD.decode(hash),
+    ) else {
+        return false;
+    };
+    pbkdf2::verify(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &hash,
+    )
+    .is_ok()
+}
+
+pub fn generate_secret() -> Result<String> {
+    let mut secret = [0u8; SECRET_LEN];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| anyhow!("No random source for the secret"))?;
+    Ok(URL_SAFE_NO_PAD.encode(secret))
+}
+
+pub fn save_auth_config(config: &AuthConfig) {
+    let file = File::create(AUTH_CONFIG_PATH);
+    if let Ok(file) = file {
+        let mut writer = BufWriter::new(file);
+        if serde_json::to_writer_pretty(&mut writer, config).is_err() {
+            info!("Could not save the auth config.");
+        }
+    } else {
+        info!("Could not create file");
+    }
+}
+
+/*
+ * Make sure there is a signing key and someone who can log in. On first
+ * start an admin user is created and its password printed once to stderr.
+ * Never through the logger, the logs are collected and kept.
+ */
+pub fn bootstrap_auth_config(config: &mut AuthConfig) -> Result<()> {
+    let mut changed = false;
+    if config.signing_key.is_empty() {
+        config.signing_key = generate_secret()?;
+        changed = true;
+    }
+    if config.users.is_empty() {
+        let password = generate_secret()?;
+        config
+            .users
+            .push(User::new("admin".to_owned(), &password, Role::Admin)?);
+        info!("Created user admin, its password is printed to stderr.");
+        eprintln!("Created user admin with password {password}. Change it after logging in.");
+        changed = true;
+    }
+    // Users from before roles default to Viewer, someone has to manage them.
+    if !has_enabled_admin(config)
+        && let Some(user) = config.users.iter_mut().find(|user| user.enabled)
+    {
+        info!("No admin user, {} is made admin.", user.username);
+        user.role = Role::Admin;
+        changed = true;
+    }
+    if changed {
+        save_auth_config(config);
+    }
+    Ok(())
+}
+
+pub fn has_enabled_admin(config: &AuthConfig) -> bool {
+    config
+        .users
+        .iter()
+        .any(|user| user.enabled && user.role == Role::Admin)
+}
+
+/*
+ * Bearer token from the Authorization header. Browsers can't set headers on
+ * WebSocket and EventSource requests, so the stream routes take an
+ * access_token query parameter too. Nowhere else, URLs end up in logs.
+ */
+fn request_token(request: &Request) -> Option<&str> {
+    if let Some(value) = request.headers().get(header::AUTHORIZATION) {
+        return value.to_str().ok()?.strip_prefix("Bearer ");
+    }
+    if !request.uri().path().starts_with(QUERY_TOKEN_PREFIX) {
+        return None;
+    }
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("access_token="))
+}
+
+fn unauthorized() -> ApiError {
+    ApiError::new(ErrorCode::Unauthorized, "Missing or invalid access token.")
+}
+
+// Middleware rejecting requests without a valid access token.
+pub async fn require_auth(
+    State(state): State<GlobalState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let token = request_token(&request).ok_or_else(unauthorized)?;
+    let user = state
+        .auth
+        .lock()
+        .unwrap()
+        .verify(token, TokenKind::Access)
+        .map(|user| AuthUser {
+            username: user.username.clone(),
+            role: user.role,
+            scope: user.scope.clone(),
+        })
+        .ok_or_else(unauthorized)?;
+    request.extensions_mut().insert(user);
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    fn request(uri: &str, authorization: Option<&str>) -> Request {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(value) = authorization {
+            builder = builder.header(header::AUTHORIZATION, value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn passwords_verify_against_their_hash() {
+        let hash = hash_password("s3cret").unwrap();
+        assert!(verify_password("s3cret", &hash));
+        assert!(!verify_password("secret", &hash));
+        assert!(!verify_password("s3cret", "plain"));
+    }
+
+    #[test]
+    fn dummy_hash_costs_as_much_as_a_real_one() {
+        let parts: Vec<&str> = DUMMY_PASSWORD_HASH.split('$').collect();
+        assert_eq!(parts[0], PASSWORD_ALGORITHM);
+        assert_eq!(parts[1], PASSWORD_ITERATIONS.to_string());
+        assert_eq!(STANDARD_NO_PAD.decode(parts[2]).unwrap().len(), SALT_LEN);
+        assert_eq!(STANDARD_NO_PAD.decode(parts[3]).unwrap().len(), SECRET_LEN);
+        assert!(!verify_password("admin", DUMMY_PASSWORD_HASH));
+    }
+
+    #[test]
+    fn query_tokens_only_work_on_stream_routes() {
+        let header = request("/api/alarms", Some("Bearer abc"));
+        assert_eq!(request_token(&header), Some("abc"));
+        let stream = request("/api/stream/ws?tks=LK0:000&access_token=abc", None);
+        assert_eq!(request_token(&stream), Some("abc"));
+        let other = request("/api/alarms?access_token=abc", None);
+        assert_eq!(request_token(&other), None);
+    }
+
+    fn user(role: Role, scope: Option<AccessScope>) -> AuthUser {
+        AuthUser {
+            username: "test".to_owned(),
+            role,
+            scope,
+        }
+    }
+
+    #[test]
+    fn roles_and_scopes_limit_what_a_user_may_do() {
+        let scope = AccessScope {
+            links: vec![1],
+            tks: vec!["LK2:005".to_owned()],
+        };
+        let operator = user(Role::Operator, Some(scope.clone()));
+        assert!(operator.require(Role::Viewer).is_ok());
+        assert!(operator.require(Role::Engineer).is_err());
+        assert!(operator.require_link(Role::Operator, 1).is_ok());
+        assert!(operator.require_link(Role::Operator, 2).is_err());
+        assert!(operator.require_tag(Role::Operator, 2, "LK2:005").is_ok());
+        assert!(operator.require_tag(Role::Operator, 2, "LK2:006").is_err());
+
+        assert!(operator.allows_link(1) && !operator.allows_link(2));
+        assert!(operator.allows_tag(2, "LK2:005") && !operator.allows_tag(2, "LK2:006"));
+        assert!(user(Role::Viewer, None).allows_tag(2, "LK2:006"));
+
+        let engineer = user(Role::Engineer, Some(scope.clone()));
+        assert!(engineer.require_unscoped(Role::Engineer).is_err());
+        assert!(
+            user(Role::Engineer, None)
+                .require_unscoped(Role::Engineer)
+                .is_ok()
+        );
+        // Admins are never limited by a scope.
+        let admin = user(Role::Admin, Some(scope));
+        assert!(admin.allows_tag(3, "LK3:000"));
+        assert!(admin.require_link(Role::Admin, 2).is_ok());
+        assert!(admin.require_unscoped(Role::Admin).is_ok());
+    }
+}
@@ -1,6 +1,6 @@
 use crate::LinkStatus;
 use anyhow::Result;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::{net::SocketAddr, time::Duration};
 use tokio::time::Instant;
@@ -115,6 +115,51 @@ impl Default for TagValue {
         Self::Real(0.0)
     }
 }
+
+impl TagValue {
+    // Numeric view of the value, used for logging and exporting.
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            TagValue::Int(v) => *v as f64,
+            TagValue::Dint(v) => *v as f64,
+            TagValue::Real(v) => *v as f64,
+            TagValue::Bit(v) => {
+                if *v {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    // A value of the same type as self, converted from a number.
+    pub fn with_f64(&self, value: f64) -> TagValue {
+        match self {
+            TagValue::Int(_) => TagValue::Int(value as u16),
+            TagValue::Dint(_) => TagValue::Dint(value as u32),
+            TagValue::Real(_) => TagValue::Real(value as f32),
+            TagValue::Bit(_) => TagValue::Bit(value != 0.0),
+        }
+    }
+
+    pub fn same_type(&self, other: &TagValue) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    // Holding register words, high word first like Tag::write. Bits don't go in registers.
+    pub fn to_registers(&self) -> Option<Vec<u16>> {
+        match self {
+            TagValue::Int(v) => Some(vec![*v]),
+            TagValue::Dint(v) => Some(vec![(*v >> 16) as u16, *v as u16]),
+            TagValue::Real(v) => {
+                let bits = v.to_bits();
+                Some(vec![(bits >> 16) as u16, bits as u16])
+            }
+            TagValue::Bit(_) => None,
+        }
+    }
+}
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum TagStatus {
     Normal,
@@ -143,6 +188,9 @@ pub struct Tag {
     pub pending_write: Option<TagValue>,
     #[serde(skip_deserializing)]
     pub status: TagStatus,
+    // When the value last changed.
+    #[serde(default)]
+    pub changed_at: Option<DateTime<Utc>>,
 }
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct DeviceLink {
@@ -186,6 +234,7 @@ impl Tag {
             value: TagValue::Real(0.0),
             pending_write: None,
             status: TagStatus::Error(String::from("Initiated.")),
+            changed_at: None,
         }
     }
     pub async fn read(&mut self, ctx: &mut DeviceLinkContext) -> Result<()> {
@@ -455,8 +504,70 @@ impl DeviceLink {
         }
     }
 
+    /*
+     * Pending writes of holding registers that follow each other, as
+     * (first register, tag indexes, words). Runs of one tag are left out,
+     * Tag::write handles those and the other registers.
+     */
+    fn contiguous_writes(&self) -> Vec<(u16, Vec<usize>, Vec<u16>)> {
+        let mut pending: Vec<(u16, usize, Vec<u16>)> = Vec::new();
+        for (index, tag) in self.tags.iter().enumerate() {
+            if let (true, Some(value), TagAddress::ModbusAddr(ModbusRegister::Holding(reg))) =
+                (tag.enabled, &tag.pending_write, &tag.address)
+                && value.same_type(&tag.value)
+                && let Some(words) = value.to_registers()
+            {
+                pending.push((*reg, index, words));
+            }
+        }
+        pending.sort_by_key(|(reg, _, _)| *reg);
+
+        let mut runs: Vec<(u16, Vec<usize>, Vec<u16>)> = Vec::new();
+        for (reg, index, words) in pending {
+            match runs.last_mut() {
+                Some((start, indexes, data)) if *start as usize + data.len() == reg as usize => {
+                    indexes.push(index);
+                    data.extend(words);
+                }
+                _ => runs.push((reg, vec![index], words)),
+            }
+        }
+        runs.retain(|(_, indexes, _)| indexes.len() > 1);
+        runs
+    }
+
+    // Write each run of contiguous holding registers with one write_multiple_registers request.
+    async fn write_contiguous(&mut self, ctx: &mut DeviceLinkContext) {
+        let DeviceLinkContext::ModbusContext(modbus) = ctx else {
+            return;
+        };
+        for (start, indexes, data) in self.contiguous_writes() {
+            let result = match modbus.write_multiple_registers(start, &data).await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            for index in indexes {
+                let tag = &mut self.tags[index];
+                tag.pending_write = None;
+                if let Err(e) = &result {
+                    info!("Could not write tag. {e}");
+                    tag.status = TagStatus::Error(format!("Error writing tag: {}", e));
+                    self.status = LinkStatus::Error(format!(
+                        "Writing failed at Tag: {}. Error: {}",
+                        tag.id, e
+                    ));
+                }
+            }
+            if result.is_ok() {
+                info!("Wrote {} registers from {start}.", data.len());
+            }
+        }
+    }
+
     pub async fn poll(&mut self, ctx: &mut DeviceLinkContext) {
         let now = Instant::now();
+        self.write_contiguous(ctx).await;
         for tag in self.tags.iter_mut() {
             if tag.enabled {
                 if let Some(write_value) = tag.pending_write.clone() {
@@ -500,3 +611,79 @@ impl DeviceLink {
         *self = link_update;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_split_into_registers_high_word_first() {
+        assert_eq!(TagValue::Int(7).to_registers(), Some(vec![7]));
+        assert_eq!(TagValue::Dint(0x0001_0002).to_registers(), Some(vec![1, 2]));
+        assert_eq!(
+            TagValue::Real(1.0).to_registers(),
+            Some(vec![0x3f80, 0x0000])
+        );
+        assert_eq!(TagValue::Bit(true).to_registers(), None);
+    }
+
+    fn link_with(registers: &[(u16, TagValue, bool, Option<TagValue>)]) -> DeviceLink {
+        let protocol = Protocol::ModbusTcp(ModbusTcpConfig::new(String::from("127.0.0.1"), 502));
+        let mut link = DeviceLink::new(
+            String::from("Device"),
+            String::from("LK"),
+            0,
+            protocol,
+            registers.len(),
+            100,
+        );
+        for (tag, (register, value, enabled, pending)) in link.tags.iter_mut().zip(registers) {
+            tag.address = TagAddress::ModbusAddr(ModbusRegister::Holding(*register));
+            tag.value = value.clone();
+            tag.enabled = *enabled;
+            tag.pending_write = pending.clone();
+        }
+        link
+    }
+
+    #[test]
+    fn neighbouring_holding_registers_are_written_together() {
+        let real = |v| (TagValue::Real(0.0), Some(TagValue::Real(v)));
+        let int = |v| (TagValue::Int(0), Some(TagValue::Int(v)));
+        let tags = [
+            // Out of order: 12 is written before 10 but follows it.
+            (12, int(3)),
+            (10, real(1.0)),
+            (13, int(4)),
+            // Gap at 14.
+            (15, int(5)),
+            (20, real(2.0)),
+            (22, real(3.0)),
+            // Nothing pending.
+            (24, (TagValue::Int(0), None)),
+        ];
+        let registers: Vec<_> = tags
+            .into_iter()
+            .map(|(register, (value, pending))| (register, value, true, pending))
+            .collect();
+        let link = link_with(&registers);
+        assert_eq!(
+            link.contiguous_writes(),
+            vec![
+                (10, vec![1, 0, 2], vec![0x3f80, 0, 3, 4]),
+                (20, vec![4, 5], vec![0x4000, 0, 0x4040, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_and_mismatched_writes_are_left_to_the_tags() {
+        let link = link_with(&[
+            (0, TagValue::Int(0), true, Some(TagValue::Int(1))),
+            (1, TagValue::Int(0), false, Some(TagValue::Int(2))),
+            (2, TagValue::Int(0), true, Some(TagValue::Real(3.0))),
+            (3, TagValue::Int(0), true, Some(TagValue::Int(4))),
+        ]);
+        assert!(link.contiguous_writes().is_empty());
+    }
+}
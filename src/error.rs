@@ -0,0 +1,236 @@
+use axum::Json;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{FromRequest, Request};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ops::Deref;
+
+// What went wrong, stable for clients to match on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // The body is not JSON, or not the expected shape.
+    InvalidJson,
+    // A field has a value that can't be used, e.g. an unparsable protocol string.
+    InvalidValue,
+    // The request is well formed but breaks a rule, e.g. too many links.
+    ValidationFailed,
+    Unauthorized,
+    Forbidden,
+    LinkNotFound,
+    TagNotFound,
+    NotFound,
+    // The change would leave things in an unusable state.
+    Conflict,
+    // A database or other service behind the API failed.
+    Upstream,
+    Internal,
+}
+
+/*
+ * Error body returned by every API handler:
+ * {"code": "tag_not_found", "message": "...", "field": "tk", "details": null}
+ */
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: StatusCode,
+    pub code: ErrorCode,
+    pub message: String,
+    // The request field the error is about, if any.
+    pub field: Option<String>,
+    pub details: Option<Value>,
+}
+
+// Json extractor whose rejection is an ApiError instead of plain text.
+pub struct ApiJson<T>(pub T);
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidJson | ErrorCode::InvalidValue => StatusCode::BAD_REQUEST,
+            ErrorCode::ValidationFailed => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::LinkNotFound | ErrorCode::TagNotFound | ErrorCode::NotFound => {
+                StatusCode::NOT_FOUND
+            }
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::Upstream => StatusCode::BAD_GATEWAY,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            status: code.status(),
+            code,
+            message: message.into(),
+            field: None,
+            details: None,
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    pub fn link_not_found(link_id: impl std::fmt::Display) -> Self {
+        Self::new(
+            ErrorCode::LinkNotFound,
+            format!("No link with id {link_id}."),
+        )
+        .field("link_id")
+    }
+
+    pub fn tag_not_found(tag: impl std::fmt::Debug) -> Self {
+        Self::new(ErrorCode::TagNotFound, format!("Unknown tag: {tag:?}."))
+    }
+
+    pub fn field(mut self, field: &str) -> Self {
+        self.field = Some(field.to_owned());
+        self
+    }
+
+    pub fn details(mut self, details: impl Serialize) -> Self {
+        self.details = serde_json::to_value(details).ok();
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if self.status.is_server_error() {
+            info!("{:?}: {}", self.code, self.message);
+        }
+        (self.status, Json(self)).into_response()
+    }
+}
+
+// Keeps the status axum picked: 400 for bad syntax, 415 without a JSON content type, 422 for the wrong shape.
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self {
+            status: rejection.status(),
+            ..Self::new(ErrorCode::InvalidJson, rejection.body_text())
+        }
+    }
+}
+
+impl<T> Deref for ApiJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for ApiJson<T> {
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(request, state).await?;
+        Ok(Self(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize)]
+    struct Query {
+        link_id: u32,
+    }
+
+    async fn body_json(response: Response) -> Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    async fn extract(content_type: Option<&str>, body: &str) -> Result<ApiJson<Query>, ApiError> {
+        let mut request = Request::builder().method("POST").uri("/");
+        if let Some(content_type) = content_type {
+            request = request.header("content-type", content_type);
+        }
+        let request = request.body(Body::from(body.to_owned())).unwrap();
+        ApiJson::<Query>::from_request(request, &()).await
+    }
+
+    #[test]
+    fn codes_map_to_statuses() {
+        let statuses = [
+            (ErrorCode::InvalidJson, 400),
+            (ErrorCode::InvalidValue, 400),
+            (ErrorCode::ValidationFailed, 422),
+            (ErrorCode::Unauthorized, 401),
+            (ErrorCode::Forbidden, 403),
+            (ErrorCode::LinkNotFound, 404),
+            (ErrorCode::TagNotFound, 404),
+            (ErrorCode::NotFound, 404),
+            (ErrorCode::Conflict, 409),
+            (ErrorCode::Upstream, 502),
+            (ErrorCode::Internal, 500),
+        ];
+        for (code, status) in statuses {
+            assert_eq!(code.status().as_u16(), status, "{code:?}");
+            assert_eq!(ApiError::new(code, "").status.as_u16(), status, "{code:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn errors_render_as_json_bodies() {
+        let response = ApiError::link_not_found(7)
+            .details(json!({"known": [0, 1]}))
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            body_json(response).await,
+            json!({
+                "code": "link_not_found",
+                "message": "No link with id 7.",
+                "field": "link_id",
+                "details": {"known": [0, 1]},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn json_rejections_keep_the_axum_status() {
+        let json = Some("application/json");
+        assert_eq!(extract(json, r#"{"link_id": 3}"#).await.unwrap().link_id, 3);
+
+        let cases = [
+            (json, "{", StatusCode::BAD_REQUEST),
+            (
+                None,
+                r#"{"link_id": 3}"#,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            (
+                json,
+                r#"{"link_id": "three"}"#,
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+        ];
+        for (content_type, body, status) in cases {
+            let error = extract(content_type, body).await.err().unwrap();
+            assert_eq!(error.status, status, "{body}");
+            let response = error.into_response();
+            assert_eq!(response.status(), status);
+            let body = body_json(response).await;
+            assert_eq!(body["code"], "invalid_json");
+            assert!(!body["message"].as_str().unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,636 @@
+use rhai::{Dynamic, Engine, EvalAltResult, FLOAT};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Functions that keep state between evaluation cycles.
+pub const STATEFUL_FUNCTIONS: [&str; 12] = [
+    "prev",
+    "delta",
+    "rate",
+    "avg_n",
+    "ema",
+    "integrate",
+    "min_over",
+    "max_over",
+    "ton",
+    "tof",
+    "rising",
+    "falling",
+];
+
+#[derive(Clone, Debug)]
+enum Slot {
+    Prev(FLOAT),
+    Rate {
+        value: FLOAT,
+        time: Instant,
+    },
+    Samples(VecDeque<FLOAT>),
+    Ema(FLOAT),
+    Integral {
+        sum: FLOAT,
+        value: FLOAT,
+        time: Instant,
+    },
+    Window(VecDeque<(Instant, FLOAT)>),
+    Timer {
+        since: Option<Instant>,
+        output: bool,
+    },
+    Edge(bool),
+}
+
+/*
+ * State of the stateful function calls of one eval. Calls are identified by
+ * the order they run in, so every call in a formula gets its own slot.
+ * If a branch changes which functions run, the mismatching slots restart.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct EvalFnState {
+    slots: Vec<Slot>,
+}
+
+#[derive(Debug)]
+struct CallContext {
+    state: EvalFnState,
+    next_slot: usize,
+    now: Instant,
+}
+
+impl CallContext {
+    fn slot(&mut self, matches: fn(&Slot) -> bool, init: impl FnOnce() -> Slot) -> &mut Slot {
+        let i = self.next_slot;
+        self.next_slot += 1;
+        if i >= self.state.slots.len() {
+            self.state.slots.push(init());
+        } else if !matches(&self.state.slots[i]) {
+            self.state.slots[i] = init();
+        }
+        &mut self.state.slots[i]
+    }
+}
+
+// Registers the stateful functions and swaps the per-eval state in and out.
+#[derive(Clone, Debug)]
+pub struct StatefulFunctions {
+    context: Arc<Mutex<CallContext>>,
+}
+
+impl Default for StatefulFunctions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatefulFunctions {
+    pub fn new() -> Self {
+        Self {
+            context: Arc::new(Mutex::new(CallContext {
+                state: EvalFnState::default(),
+                next_slot: 0,
+                now: Instant::now(),
+            })),
+        }
+    }
+
+    // Install the state of the eval about to run.
+    pub fn begin(&self, state: EvalFnState, now: Instant) {
+        let mut context = self.context.lock().unwrap();
+        context.state = state;
+        context.next_slot = 0;
+        context.now = now;
+    }
+
+    // Take the state back once the eval ran.
+    pub fn end(&self) -> EvalFnState {
+        std::mem::take(&mut self.context.lock().unwrap().state)
+    }
+
+    /*
+     * Numeric arguments take ints as well as floats, Int and Dint tags are
+     * pushed to the scope as ints and Rhai doesn't convert them by itself.
+     */
+    pub fn register(&self, engine: &mut Engine) {
+        // Value of `x` in the previous cycle.
+        let context = self.context.clone();
+        engine.register_fn(
+            "prev",
+            move |x: Dynamic| -> Result<FLOAT, Box<EvalAltResult>> {
+                let x = number(&x, "x")?;
+                let mut context = context.lock().unwrap();
+                Ok(
+                    match context.slot(|s| matches!(s, Slot::Prev(_)), || Slot::Prev(x)) {
+                        Slot::Prev(last) => std::mem::replace(last, x),
+                        _ => x,
+                    },
+                )
+            },
+        );
+
+        // Change of `x` since the previous cycle.
+        let context = self.context.clone();
+        engine.register_fn(
+            "delta",
+            move |x: Dynamic| -> Result<FLOAT, Box<EvalAltResult>> {
+                let x = number(&x, "x")?;
+                let mut context = context.lock().unwrap();
+                Ok(
+                    match context.slot(|s| matches!(s, Slot::Prev(_)), || Slot::Prev(x)) {
+                        Slot::Prev(last) => x - std::mem::replace(last, x),
+                        _ => 0.0,
+                    },
+                )
+            },
+        );
+
+        // Change of `x` per second.
+        let context = self.context.clone();
+        engine.register_fn(
+            "rate",
+            move |x: Dynamic| -> Result<FLOAT, Box<EvalAltResult>> {
+                let x = number(&x, "x")?;
+                let mut context = context.lock().unwrap();
+                let now = context.now;
+                Ok(
+                    match context.slot(
+                        |s| matches!(s, Slot::Rate { .. }),
+                        || Slot::Rate {
+                            value: x,
+                            time: now,
+                        },
+                    ) {
+                        Slot::Rate { value, time } => {
+                            let dt = now.duration_since(*time).as_secs_f32();
+                            let rate = if dt > 0.0 { (x - *value) / dt } else { 0.0 };
+                            *value = x;
+                            *time = now;
+                            rate
+                        }
+                        _ => 0.0,
+                    },
+                )
+            },
+        );
+
+        // Average of the last `n` values.
+        let context = self.context.clone();
+        engine.register_fn(
+            "avg_n",
+            move |x: Dynamic, n: Dynamic| -> Result<FLOAT, Box<EvalAltResult>> {
+                let x = number(&x, "x")?;
+                let n = number(&n, "n")?.max(1.0) as usize;
+                let mut context = context.lock().unwrap();
+                Ok(
+                    match context.slot(
+                        |s| matches!(s, Slot::Samples(_)),
+                        || Slot::Samples(VecDeque::new()),
+                    ) {
+                        Slot::Samples(samples) => {
+                            samples.push_back(x);
+                            while samples.len() > n {
+                                samples.pop_front();
+                            }
+                            samples.iter().sum::<FLOAT>() / samples.len() as FLOAT
+                        }
+                        _ => x,
+                    },
+                )
+            },
+        );
+
+        // Exponential moving average with smoothing factor `alpha` (0..1).
+        let context = self.context.clone();
+        engine.register_fn(
+            "ema",
+            move |x: Dynamic, alpha: Dynamic| -> Result<FLOAT, Box<EvalAltResult>> {
+                let x = number(&x, "x")?;
+                let alpha = number(&alpha, "alpha")?.clamp(0.0, 1.0);
+                let mut context = context.lock().unwrap();
+                Ok(
+                    match context.slot(|s| matches!(s, Slot::Ema(_)), || Slot::Ema(x)) {
+                        Slot::Ema(average) => {
+                            *average = alpha * x + (1.0 - alpha) * *average;
+                            *average
+                        }
+                        _ => x,
+                    },
+                )
+            },
+        );
+
+        // Running integral of `x` over time in seconds (trapezoidal).
+        let context = self.context.clone();
+        engine.register_fn(
+            "integrate",
+            move |x: Dynamic| -> Result<FLOAT, Box<EvalAltResult>> {
+                let x = number(&x, "x")?;
+                let mut context = context.lock().unwrap();
+                let now = context.now;
+                Ok(
+                    match context.slot(
+                        |s| matches!(s, Slot::Integral { .. }),
+                        || Slot::Integral {
+                            sum: 0.0,
+                            value: x,
+                            time: now,
+                        },
+                    ) {
+                        Slot::Integral { sum, value, time } => {
+                            let dt = now.duration_since(*time).as_secs_f32();
+                            *sum += (x + *value) / 2.0 * dt;
+                            *value = x;
+                            *time = now;
+                            *sum
+                        }
+                        _ => 0.0,
+                    },
+                )
+            },
+        );
+
+        // Smallest and largest value of `x` over the last `secs` seconds.
+        let context = self.context.clone();
+        engine.register_fn(
+            "min_over",
+            move |x: Dynamic, secs: Dynamic| -> Result<FLOAT, Box<EvalAltResult>> {
+                let x = number(&x, "x")?;
+                let secs = number(&secs, "secs")?;
+                let mut context = context.lock().unwrap();
+                Ok(window(&mut context, x, secs)?.fold(x, FLOAT::min))
+            },
+        );
+        let context = self.context.clone();
+        engine.register_fn(
+            "max_over",
+            move |x: Dynamic, secs: Dynamic| -> Result<FLOAT, Box<EvalAltResult>> {
+                let x = number(&x, "x")?;
+                let secs = number(&secs, "secs")?;
+                let mut context = context.lock().unwrap();
+                Ok(window(&mut context, x, secs)?.fold(x, FLOAT::max))
+            },
+        );
+
+        // On-delay timer: true once `input` has been true for `ms` milliseconds.
+        let context = self.context.clone();
+        engine.register_fn(
+            "ton",
+            move |input: bool, ms: Dynamic| -> Result<bool, Box<EvalAltResult>> {
+                let delay = millis(&ms)?;
+                let mut context = context.lock().unwrap();
+                let now = context.now;
+                Ok(
+                    match context.slot(
+                        |s| matches!(s, Slot::Timer { .. }),
+                        || Slot::Timer {
+                            since: None,
+                            output: false,
+                        },
+                    ) {
+                        Slot::Timer { since, output } => {
+                            if input {
+                                let start = *since.get_or_insert(now);
+                                *output = now.duration_since(start) >= delay;
+                            } else {
+                                *since = None;
+                                *output = false;
+                            }
+                            *output
+                        }
+                        _ => false,
+                    },
+                )
+            },
+        );
+
+        // Off-delay timer: stays true for `ms` milliseconds after `input` drops.
+        let context = self.context.clone();
+        engine.register_fn(
+            "tof",
+            move |input: bool, ms: Dynamic| -> Result<bool, Box<EvalAltResult>> {
+                let delay = millis(&ms)?;
+                let mut context = context.lock().unwrap();
+                let now = context.now;
+                Ok(
+                    match context.slot(
+                        |s| matches!(s, Slot::Timer { .. }),
+                        || Slot::Timer {
+                            since: None,
+                            output: false,
+                        },
+                    ) {
+                        Slot::Timer { since, output } => {
+                            if input {
+                                *since = None;
+                                *output = true;
+                            } else if *output {
+                                let start = *since.get_or_insert(now);
+                                if now.duration_since(start) >= delay {
+                                    *since = None;
+                                    *output = false;
+                                }
+                            }
+                            *output
+                        }
+                        _ => false,
+                    },
+                )
+            },
+        );
+
+        // Edge detection. The first call never reports an edge.
+        let context = self.context.clone();
+        engine.register_fn("rising", move |input: bool| -> bool {
+            let mut context = context.lock().unwrap();
+            match context.slot(|s| matches!(s, Slot::Edge(_)), || Slot::Edge(input)) {
+                Slot::Edge(last) => {
+                    let previous = std::mem::replace(last, input);
+                    input && !previous
+                }
+                _ => false,
+            }
+        });
+        let context = self.context.clone();
+        engine.register_fn("falling", move |input: bool| -> bool {
+            let mut context = context.lock().unwrap();
+            match context.slot(|s| matches!(s, Slot::Edge(_)), || Slot::Edge(input)) {
+                Slot::Edge(last) => {
+                    let previous = std::mem::replace(last, input);
+                    !input && previous
+                }
+                _ => false,
+            }
+        });
+    }
+}
+
+// A float or int argument as a float.
+fn number(value: &Dynamic, name: &str) -> Result<FLOAT, Box<EvalAltResult>> {
+    value
+        .as_float()
+        .or_else(|_| value.as_int().map(|value| value as FLOAT))
+        .map_err(|type_name| format!("{name} must be a number, got {type_name}").into())
+}
+
+// A delay in milliseconds, negative ones count as 0.
+fn millis(ms: &Dynamic) -> Result<Duration, Box<EvalAltResult>> {
+    if let Ok(ms) = ms.as_int() {
+        return Ok(Duration::from_millis(ms.max(0) as u64));
+    }
+    let ms = number(ms, "ms")?;
+    Duration::try_from_secs_f32(ms.max(0.0) / 1000.0)
+        .map_err(|_| format!("Invalid delay: {ms} ms").into())
+}
+
+// Push `x` into the call's time window and return the values still inside it.
+fn window(
+    context: &mut CallContext,
+    x: FLOAT,
+    secs: FLOAT,
+) -> Result<impl Iterator<Item = FLOAT> + '_, Box<EvalAltResult>> {
+    // Infinite or oversized lengths don't fit a Duration.
+    let span = Duration::try_from_secs_f32(secs.max(0.0))
+        .map_err(|_| format!("Invalid window length: {secs} seconds"))?;
+    let now = context.now;
+    let slot = context.slot(
+        |s| matches!(s, Slot::Window(_)),
+        || Slot::Window(VecDeque::new()),
+    );
+    let samples = match slot {
+        Slot::Window(samples) => samples,
+        _ => unreachable!(),
+    };
+    samples.push_back((now, x));
+    while samples
+        .front()
+        .is_some_and(|(time, _)| now.duration_since(*time) > span)
+    {
+        samples.pop_front();
+    }
+    Ok(samples.iter().map(|(_, value)| *value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rhai::{INT, Scope};
+
+    // Runs one formula cycle after cycle, the way the eval task does.
+    struct Cycles {
+        engine: Engine,
+        functions: StatefulFunctions,
+        state: EvalFnState,
+        start: Instant,
+    }
+
+    impl Cycles {
+        fn new() -> Self {
+            let mut engine = Engine::new();
+            let functions = StatefulFunctions::new();
+            functions.register(&mut engine);
+            Self {
+                engine,
+                functions,
+                state: EvalFnState::default(),
+                start: Instant::now(),
+            }
+        }
+
+        fn run(
+            &mut self,
+            formula: &str,
+            x: Dynamic,
+            at_ms: u64,
+        ) -> Result<Dynamic, Box<EvalAltResult>> {
+            let now = self.start + Duration::from_millis(at_ms);
+            self.functions.begin(std::mem::take(&mut self.state), now);
+            let mut scope = Scope::new();
+            scope.push_dynamic("x", x);
+            let result = self.engine.eval_with_scope::<Dynamic>(&mut scope, formula);
+            self.state = self.functions.end();
+            result
+        }
+
+        fn float(&mut self, formula: &str, x: FLOAT, at_ms: u64) -> FLOAT {
+            self.run(formula, Dynamic::from_float(x), at_ms)
+                .unwrap()
+                .as_float()
+                .unwrap()
+        }
+
+        fn bool(&mut self, formula: &str, x: bool, at_ms: u64) -> bool {
+            self.run(formula, Dynamic::from_bool(x), at_ms)
+                .unwrap()
+                .as_bool()
+                .unwrap()
+        }
+    }
+
+    fn assert_close(actual: FLOAT, expected: FLOAT) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn prev_and_delta_start_from_the_first_value() {
+        let mut cycles = Cycles::new();
+        assert_eq!(cycles.float("prev(x)", 1.0, 0), 1.0);
+        assert_eq!(cycles.float("prev(x)", 4.0, 100), 1.0);
+        assert_eq!(cycles.float("prev(x)", 6.0, 200), 4.0);
+
+        let mut cycles = Cycles::new();
+        assert_eq!(cycles.float("delta(x)", 1.0, 0), 0.0);
+        assert_eq!(cycles.float("delta(x)", 4.0, 100), 3.0);
+        assert_eq!(cycles.float("delta(x)", 2.5, 200), -1.5);
+    }
+
+    #[test]
+    fn rate_is_per_second() {
+        let mut cycles = Cycles::new();
+        assert_eq!(cycles.float("rate(x)", 10.0, 0), 0.0);
+        assert_close(cycles.float("rate(x)", 12.0, 500), 4.0);
+        assert_close(cycles.float("rate(x)", 11.0, 1500), -1.0);
+    }
+
+    #[test]
+    fn averages() {
+        let mut cycles = Cycles::new();
+        assert_eq!(cycles.float("avg_n(x, 3)", 3.0, 0), 3.0);
+        assert_eq!(cycles.float("avg_n(x, 3)", 6.0, 100), 4.5);
+        assert_eq!(cycles.float("avg_n(x, 3)", 9.0, 200), 6.0);
+        assert_eq!(cycles.float("avg_n(x, 3)", 12.0, 300), 9.0);
+
+        let mut cycles = Cycles::new();
+        assert_eq!(cycles.float("ema(x, 0.5)", 10.0, 0), 10.0);
+        assert_eq!(cycles.float("ema(x, 0.5)", 20.0, 100), 15.0);
+        // Alpha is clamped to 0..1.
+        assert_eq!(cycles.float("ema(x, 2.0)", 30.0, 200), 30.0);
+    }
+
+    #[test]
+    fn integrate_uses_the_trapezoidal_rule() {
+        let mut cycles = Cycles::new();
+        assert_eq!(cycles.float("integrate(x)", 0.0, 0), 0.0);
+        assert_close(cycles.float("integrate(x)", 2.0, 1000), 1.0);
+        assert_close(cycles.float("integrate(x)", 2.0, 3000), 5.0);
+    }
+
+    #[test]
+    fn windows_drop_old_samples() {
+        let mut cycles = Cycles::new();
+        assert_eq!(cycles.float("max_over(x, 1.0)", 5.0, 0), 5.0);
+        assert_eq!(cycles.float("max_over(x, 1.0)", 2.0, 500), 5.0);
+        assert_eq!(cycles.float("max_over(x, 1.0)", 3.0, 1200), 3.0);
+
+        let mut cycles = Cycles::new();
+        assert_eq!(cycles.float("min_over(x, 1.0)", 5.0, 0), 5.0);
+        assert_eq!(cycles.float("min_over(x, 1.0)", 7.0, 500), 5.0);
+        assert_eq!(cycles.float("min_over(x, 1.0)", 6.0, 1200), 6.0);
+    }
+
+    #[test]
+    fn windows_reject_lengths_that_do_not_fit_a_duration() {
+        let mut cycles = Cycles::new();
+        for formula in ["max_over(x, 1.0 / 0.0)", "min_over(x, 1e30)"] {
+            let result = cycles.run(formula, Dynamic::from_float(1.0), 0);
+            assert!(result.is_err(), "{formula}");
+        }
+        // A NaN length is no window at all.
+        assert_eq!(cycles.float("max_over(x, 0.0 / 0.0)", 1.0, 0), 1.0);
+    }
+
+    #[test]
+    fn timers() {
+        let mut cycles = Cycles::new();
+        assert!(!cycles.bool("ton(x, 1000)", true, 0));
+        assert!(!cycles.bool("ton(x, 1000)", true, 900));
+        assert!(cycles.bool("ton(x, 1000)", true, 1000));
+        assert!(!cycles.bool("ton(x, 1000)", false, 1100));
+        assert!(!cycles.bool("ton(x, 1000)", true, 1200));
+
+        let mut cycles = Cycles::new();
+        assert!(!cycles.bool("tof(x, 1000)", false, 0));
+        assert!(cycles.bool("tof(x, 1000)", true, 100));
+        assert!(cycles.bool("tof(x, 1000)", false, 200));
+        assert!(cycles.bool("tof(x, 1000)", false, 1100));
+        assert!(!cycles.bool("tof(x, 1000)", false, 1200));
+    }
+
+    #[test]
+    fn int_arguments_count_as_floats() {
+        let int = |x: INT| Dynamic::from_int(x);
+        let float = |result: Result<Dynamic, _>| result.unwrap().as_float().unwrap();
+
+        let mut cycles = Cycles::new();
+        assert_eq!(float(cycles.run("prev(x)", int(3), 0)), 3.0);
+        assert_eq!(float(cycles.run("prev(x)", int(5), 100)), 3.0);
+        let mut cycles = Cycles::new();
+        assert_eq!(float(cycles.run("delta(x)", int(3), 0)), 0.0);
+        assert_eq!(float(cycles.run("delta(x)", int(5), 100)), 2.0);
+        let mut cycles = Cycles::new();
+        assert_eq!(float(cycles.run("rate(x)", int(10), 0)), 0.0);
+        assert_close(float(cycles.run("rate(x)", int(12), 500)), 4.0);
+        let mut cycles = Cycles::new();
+        assert_eq!(float(cycles.run("avg_n(x, 2)", int(2), 0)), 2.0);
+        assert_eq!(float(cycles.run("avg_n(x, 2)", int(5), 100)), 3.5);
+        let mut cycles = Cycles::new();
+        assert_eq!(float(cycles.run("ema(x, 1)", int(4), 0)), 4.0);
+        assert_eq!(float(cycles.run("ema(x, 1)", int(8), 100)), 8.0);
+        let mut cycles = Cycles::new();
+        assert_eq!(float(cycles.run("integrate(x)", int(2), 0)), 0.0);
+        assert_close(float(cycles.run("integrate(x)", int(2), 1000)), 2.0);
+        let mut cycles = Cycles::new();
+        assert_eq!(float(cycles.run("min_over(x, 1)", int(4), 0)), 4.0);
+        assert_eq!(float(cycles.run("min_over(x, 1)", int(6), 500)), 4.0);
+        let mut cycles = Cycles::new();
+        assert_eq!(float(cycles.run("max_over(x, 1)", int(4), 0)), 4.0);
+        assert_eq!(float(cycles.run("max_over(x, 1)", int(6), 500)), 6.0);
+
+        let mut cycles = Cycles::new();
+        assert!(!cycles.bool("ton(x, 1000.0)", true, 0));
+        assert!(cycles.bool("ton(x, 1000.0)", true, 1000));
+        let mut cycles = Cycles::new();
+        assert!(cycles.bool("tof(x, 500.0)", true, 0));
+        assert!(cycles.bool("tof(x, 500.0)", false, 600));
+        assert!(!cycles.bool("tof(x, 500.0)", false, 1200));
+
+        let error = Cycles::new().run(r#"prev("text")"#, int(0), 0).unwrap_err();
+        assert!(error.to_string().contains("must be a number"), "{error}");
+        assert!(
+            Cycles::new()
+                .run("ton(true, 1.0 / 0.0)", int(0), 0)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn edges() {
+        let mut cycles = Cycles::new();
+        let inputs = [true, true, false, true, false];
+        let rising: Vec<bool> = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, x)| cycles.bool("rising(x)", *x, i as u64 * 100))
+            .collect();
+        assert_eq!(rising, vec![false, false, false, true, false]);
+
+        let mut cycles = Cycles::new();
+        let falling: Vec<bool> = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, x)| cycles.bool("falling(x)", *x, i as u64 * 100))
+            .collect();
+        assert_eq!(falling, vec![false, false, true, false, true]);
+    }
+
+    #[test]
+    fn every_call_gets_its_own_slot() {
+        let mut cycles = Cycles::new();
+        let formula = "prev(x) + prev(x * 10.0)";
+        assert_eq!(cycles.float(formula, 1.0, 0), 11.0);
+        assert_eq!(cycles.float(formula, 2.0, 100), 11.0);
+        assert_eq!(cycles.float(formula, 3.0, 200), 22.0);
+    }
+}
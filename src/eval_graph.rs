@@ -0,0 +1,311 @@
+use crate::{Link, TagIndex};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EvalNode {
+    pub link_id: usize,
+    pub tag_id: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EvalEdge {
+    // `to` reads the value of `from`.
+    pub from: EvalNode,
+    pub to: EvalNode,
+}
+
+/*
+ * Dependencies between eval tags across all eval links.
+ * `order` lists every eval so that dependencies come first. Evals that are
+ * part of a circular reference (or depend on one) come last.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EvalGraph {
+    pub nodes: Vec<EvalNode>,
+    pub edges: Vec<EvalEdge>,
+    pub order: Vec<EvalNode>,
+    pub cycles: Vec<Vec<EvalNode>>,
+}
+
+impl EvalGraph {
+    pub fn build(links: &[Link]) -> Self {
+        let mut graph = Self::default();
+        for link in links {
+            if let Link::Eval(link) = link {
+                for eval in &link.tags {
+                    graph.nodes.push(EvalNode {
+                        link_id: link.id,
+                        tag_id: eval.id,
+                    });
+                }
+            }
+        }
+        let positions: HashMap<EvalNode, usize> = graph
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (*node, i))
+            .collect();
+
+        let index = TagIndex::build(links);
+        // dependents[i] lists the nodes that read node i.
+        let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); graph.nodes.len()];
+        for link in links {
+            let Link::Eval(link) = link else {
+                continue;
+            };
+            for eval in link.tags.iter().filter(|eval| eval.enabled) {
+                let to = EvalNode {
+                    link_id: link.id,
+                    tag_id: eval.id,
+                };
+                for (link_id, tag_id) in eval.dependencies(links, &index) {
+                    let from = EvalNode { link_id, tag_id };
+                    let Some(&from_index) = positions.get(&from) else {
+                        continue;
+                    };
+                    let to_index = positions[&to];
+                    if !dependents[from_index].contains(&to_index) {
+                        dependents[from_index].push(to_index);
+                        graph.edges.push(EvalEdge { from, to });
+                    }
+                }
+            }
+        }
+
+        graph.order = topological_order(&dependents)
+            .into_iter()
+            .map(|i| graph.nodes[i])
+            .collect();
+        graph.cycles = strongly_connected(&dependents)
+            .into_iter()
+            .filter(|component| {
+                component.len() > 1 || dependents[component[0]].contains(&component[0])
+            })
+            .map(|component| component.into_iter().map(|i| graph.nodes[i]).collect())
+            .collect();
+        graph
+    }
+
+    pub fn cycle_of(&self, node: EvalNode) -> Option<&Vec<EvalNode>> {
+        self.cycles.iter().find(|cycle| cycle.contains(&node))
+    }
+
+    // Leave out the nodes that fail `keep`, and every edge and cycle touching them.
+    pub fn retain(&mut self, mut keep: impl FnMut(&EvalNode) -> bool) {
+        self.nodes.retain(&mut keep);
+        self.order.retain(&mut keep);
+        self.edges.retain(|edge| keep(&edge.from) && keep(&edge.to));
+        self.cycles.retain(|cycle| cycle.iter().all(&mut keep));
+    }
+}
+
+// Kahn's algorithm. Nodes left over because of cycles are appended in their original order.
+fn topological_order(dependents: &[Vec<usize>]) -> Vec<usize> {
+    let mut in_degree = vec![0; dependents.len()];
+    for targets in dependents {
+        for &target in targets {
+            in_degree[target] += 1;
+        }
+    }
+    let mut queue: VecDeque<usize> = (0..dependents.len())
+        .filter(|&i| in_degree[i] == 0)
+        .collect();
+    let mut order = Vec::with_capacity(dependents.len());
+    let mut placed = vec![false; dependents.len()];
+
+    while let Some(node) = queue.pop_front() {
+        order.push(node);
+        placed[node] = true;
+        for &target in &dependents[node] {
+            in_degree[target] -= 1;
+            if in_degree[target] == 0 {
+                queue.push_back(target);
+            }
+        }
+    }
+    order.extend((0..dependents.len()).filter(|&i| !placed[i]));
+    order
+}
+
+// Tarjan's strongly connected components.
+fn strongly_connected(dependents: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    struct Tarjan<'a> {
+        dependents: &'a [Vec<usize>],
+        next_index: usize,
+        index: Vec<Option<usize>>,
+        low_link: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        components: Vec<Vec<usize>>,
+    }
+
+    impl Tarjan<'_> {
+        fn visit(&mut self, node: usize) {
+            self.index[node] = Some(self.next_index);
+            self.low_link[node] = self.next_index;
+            self.next_index += 1;
+            self.stack.push(node);
+            self.on_stack[node] = true;
+
+            let dependents = self.dependents;
+            for &target in &dependents[node] {
+                match self.index[target] {
+                    None => {
+                        self.visit(target);
+                        self.low_link[node] = self.low_link[node].min(self.low_link[target]);
+                    }
+                    Some(target_index) if self.on_stack[target] => {
+                        self.low_link[node] = self.low_link[node].min(target_index);
+                    }
+                    _ => {}
+                }
+            }
+
+            if Some(self.low_link[node]) == self.index[node] {
+                let mut component = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                component.reverse();
+                self.components.push(component);
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        dependents,
+        next_index: 0,
+        index: vec![None; dependents.len()],
+        low_link: vec![0; dependents.len()],
+        on_stack: vec![false; dependents.len()],
+        stack: Vec::new(),
+        components: Vec::new(),
+    };
+    for node in 0..dependents.len() {
+        if tarjan.index[node].is_none() {
+            tarjan.visit(node);
+        }
+    }
+    tarjan.components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EvalLink, EvalRuntime, TagValue};
+
+    fn node(tag_id: usize) -> EvalNode {
+        EvalNode { link_id: 5, tag_id }
+    }
+
+    fn links(formulas: &[&str]) -> Vec<Link> {
+        let mut link = EvalLink::new(5, "Evals".to_owned(), formulas.len());
+        for (eval, formula) in link.tags.iter_mut().zip(formulas) {
+            eval.formula = formula.to_string();
+        }
+        vec![Link::Eval(link)]
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let graph = EvalGraph::build(&links(&[
+            r#"tag("EVAL5:001") + 1.0"#,
+            r#"tag("EVAL5:002") * 2.0"#,
+            "1.0",
+        ]));
+        assert_eq!(graph.order, vec![node(2), node(1), node(0)]);
+        assert_eq!(
+            graph.edges,
+            vec![
+                EvalEdge {
+                    from: node(1),
+                    to: node(0)
+                },
+                EvalEdge {
+                    from: node(2),
+                    to: node(1)
+                },
+            ]
+        );
+        assert!(graph.cycles.is_empty());
+    }
+
+    #[test]
+    fn cycles_are_reported_and_ordered_last() {
+        let graph = EvalGraph::build(&links(&[
+            r#"tag("EVAL5:001")"#,
+            r#"tag("EVAL5:000")"#,
+            r#"tag("EVAL5:002") + 1.0"#,
+            r#"tag("EVAL5:001") + 2.0"#,
+            "3.0",
+        ]));
+        assert_eq!(graph.cycles.len(), 2);
+        assert_eq!(graph.cycle_of(node(0)), Some(&vec![node(0), node(1)]));
+        assert_eq!(graph.cycle_of(node(2)), Some(&vec![node(2)]));
+        assert_eq!(graph.cycle_of(node(3)), None);
+        // Evals in or behind a cycle can't be ordered and keep their position.
+        assert_eq!(
+            graph.order,
+            vec![node(4), node(0), node(1), node(2), node(3)]
+        );
+    }
+
+    #[test]
+    fn disabled_evals_have_no_dependencies() {
+        let mut links = links(&[r#"tag("EVAL5:001")"#, r#"tag("EVAL5:000")"#]);
+        let Link::Eval(link) = &mut links[0] else {
+            unreachable!()
+        };
+        link.tags[1].enabled = false;
+        let graph = EvalGraph::build(&links);
+        assert!(graph.cycles.is_empty());
+        assert_eq!(graph.order, vec![node(1), node(0)]);
+    }
+
+    #[test]
+    fn one_cycle_evaluates_a_whole_chain() {
+        let links = links(&[
+            r#"tag("EVAL5:001") + 1.0"#,
+            r#"tag("EVAL5:002") * 2.0"#,
+            "3.0",
+        ]);
+        let Link::Eval(mut link) = links[0].clone() else {
+            unreachable!()
+        };
+        let mut runtime = EvalRuntime::new();
+        runtime.compile(&link, &links);
+        runtime.bind(&mut link, &links);
+        runtime.evaluate(&mut link, None);
+        let values: Vec<_> = link.tags.iter().map(|eval| eval.value.clone()).collect();
+        assert_eq!(
+            values,
+            vec![
+                TagValue::Real(7.0),
+                TagValue::Real(6.0),
+                TagValue::Real(3.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn commented_out_references_make_no_edges() {
+        let graph = EvalGraph::build(&links(&[
+            "// was tag(\"EVAL5:001\")\n1.0",
+            r#"let note = "see tag(\"EVAL5:001\")"; tag("EVAL5:000")"#,
+        ]));
+        assert!(graph.cycles.is_empty());
+        assert_eq!(
+            graph.edges,
+            vec![EvalEdge {
+                from: node(0),
+                to: node(1)
+            }]
+        );
+    }
+}
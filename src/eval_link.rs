@@ -1,6 +1,15 @@
-use crate::{Input, Link, LinkStatus, Tag, TagStatus, TagValue};
-use rhai::{Engine, Scope};
+use crate::{
+    EvalFnState, EvalGraph, EvalNode, Input, Link, LinkStatus, STATEFUL_FUNCTIONS,
+    ScriptModuleResolver, ScriptModules, StatefulFunctions, Tag, TagIndex, TagRef, TagStatus,
+    TagValue, find_tag, locate_tk, register_flow_functions,
+};
+use chrono::{DateTime, Utc};
+use rhai::{AST, ASTNode, Dynamic, Engine, EvalAltResult, Expr, Position, Scope, Stmt};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum EvalInputVarType {
@@ -28,12 +37,26 @@ pub struct Eval {
     // List of variables included in the formula.
     // The eval function will use this list to expand
     // the formula before evaluating.
+    // Formulas can also read tags directly by key with tag("LK0:003").
+    #[serde(default)]
     pub vars: Vec<EvalInputVar>,
     // Formula that might include variables.
     pub formula: String,
     pub value: TagValue,
     #[serde(skip_deserializing)]
     pub status: TagStatus,
+    // When the value last changed.
+    #[serde(default)]
+    pub changed_at: Option<DateTime<Utc>>,
+    // Device or input tags (by tk) this formula may write with write_tag().
+    // Empty means the eval can't write anything.
+    #[serde(default)]
+    pub writable_tags: Vec<String>,
+    // Minimum time between two writes to the same tag.
+    #[serde(default = "default_write_interval_ms")]
+    pub write_interval_ms: u64,
+    #[serde(default)]
+    pub limits: EvalLimits,
 }
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct EvalLink {
@@ -45,6 +68,135 @@ pub struct EvalLink {
     pub tag_count: usize,
     #[serde(skip)]
     pub status: LinkStatus,
+    // Duration of the last evaluation cycle in microseconds.
+    #[serde(default)]
+    pub scan_time_us: u128,
+    #[serde(default = "default_cycle_time_ms")]
+    pub cycle_time_ms: u64,
+    #[serde(default)]
+    pub trigger: EvalTrigger,
+}
+
+/*
+ * When an eval link runs. OnChange links only run the evals whose inputs
+ * changed, and otherwise just check for reconfigs every cycle time.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum EvalTrigger {
+    #[default]
+    Cyclic,
+    OnChange,
+}
+
+fn default_cycle_time_ms() -> u64 {
+    500
+}
+
+fn default_write_interval_ms() -> u64 {
+    1000
+}
+
+// Cycles an eval is skipped after exceeding its limits, doubled on every repeat.
+pub const EVAL_BACKOFF_MAX_CYCLES: u32 = 64;
+
+/*
+ * Resource limits of one eval. 0 disables a limit, except max_call_depth:
+ * without a call limit deep recursion overflows the task's stack, so 0 falls
+ * back to the default depth.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EvalLimits {
+    pub max_operations: u64,
+    pub max_call_depth: usize,
+    pub max_string_size: usize,
+    pub max_array_size: usize,
+    pub max_time_ms: u64,
+}
+
+impl Default for EvalLimits {
+    fn default() -> Self {
+        Self {
+            max_operations: 100_000,
+            max_call_depth: 32,
+            max_string_size: 4096,
+            max_array_size: 1024,
+            max_time_ms: 50,
+        }
+    }
+}
+
+// True if the error comes from hitting one of the limits.
+fn exceeded_limits(e: &EvalAltResult) -> bool {
+    match e {
+        EvalAltResult::ErrorTooManyOperations(..)
+        | EvalAltResult::ErrorStackOverflow(..)
+        | EvalAltResult::ErrorDataTooLarge(..)
+        | EvalAltResult::ErrorTerminated(..) => true,
+        EvalAltResult::ErrorInFunctionCall(_, _, inner, _)
+        | EvalAltResult::ErrorInModule(_, inner, _) => exceeded_limits(inner),
+        _ => false,
+    }
+}
+
+// Set the engine limits and the wall-clock deadline for the next evaluation.
+pub fn apply_limits(
+    engine: &mut Engine,
+    deadline_us: &AtomicU64,
+    started: Instant,
+    limits: &EvalLimits,
+) {
+    let max_call_depth = match limits.max_call_depth {
+        0 => EvalLimits::default().max_call_depth,
+        depth => depth,
+    };
+    engine
+        .set_max_operations(limits.max_operations)
+        .set_max_call_levels(max_call_depth)
+        .set_max_string_size(limits.max_string_size)
+        .set_max_array_size(limits.max_array_size);
+    let deadline = match limits.max_time_ms {
+        0 => 0,
+        ms => started.elapsed().as_micros() as u64 + ms * 1000,
+    };
+    deadline_us.store(deadline, Ordering::Relaxed);
+}
+
+// Terminate a run once it passes the deadline set by apply_limits().
+pub fn stop_at_deadline(engine: &mut Engine, started: Instant, deadline_us: Arc<AtomicU64>) {
+    engine.on_progress(move |operations| {
+        // Reading the clock on every operation is too slow.
+        if operations % 256 != 0 {
+            return None;
+        }
+        let deadline = deadline_us.load(Ordering::Relaxed);
+        if deadline != 0 && started.elapsed().as_micros() as u64 > deadline {
+            Some(Dynamic::from("time limit exceeded".to_string()))
+        } else {
+            None
+        }
+    });
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Backoff {
+    cycles: u32,
+    remaining: u32,
+}
+
+// A write requested by an eval with write_tag(), applied by the eval task.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalWrite {
+    pub eval_tk: String,
+    pub tk: String,
+    pub value: f64,
+}
+
+// Collects the write_tag() calls of the eval currently running.
+#[derive(Debug, Default)]
+struct WriteRequests {
+    allowed: Vec<String>,
+    requested: Vec<(String, f64)>,
 }
 
 impl Eval {
@@ -71,137 +223,808 @@ impl Eval {
             formula: String::from("5.0 + 5.0"),
             value: TagValue::Real(0.0),
             status: TagStatus::Normal,
+            changed_at: None,
+            writable_tags: Vec::new(),
+            write_interval_ms: default_write_interval_ms(),
+            limits: EvalLimits::default(),
         }
     }
-    pub fn evaluate(&mut self, links: &Vec<Link>) {
-        if self.enabled {
-            // Expand the variables using the links list.
-            for var in self.vars.iter_mut() {
-                for link in links.iter() {
-                    match link {
-                        Link::Device(link) => {
-                            if link.id == var.link_id {
-                                for tag in &link.tags {
-                                    if tag.id == var.tag_id {
-                                        if !tag.enabled {
-                                            self.status = TagStatus::Error(
-                                                "Variable in the formula is not enabled."
-                                                    .to_string(),
-                                            );
-                                            return;
-                                        }
-                                        var.value = tag.value.clone();
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                        Link::Inputs(link) => {
-                            if link.id == var.link_id {
-                                for tag in &link.tags {
-                                    if tag.id == var.tag_id {
-                                        if !tag.enabled {
-                                            self.status = TagStatus::Error(
-                                                "Variable in the formula is not enabled."
-                                                    .to_string(),
-                                            );
-                                            return;
-                                        }
-                                        var.value = tag.value.clone();
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                        Link::Eval(link) => {
-                            if link.id == var.link_id {
-                                for tag in &link.tags {
-                                    if tag.id == var.tag_id {
-                                        if !tag.enabled {
-                                            self.status = TagStatus::Error(
-                                                "Variable in the formula is not enabled."
-                                                    .to_string(),
-                                            );
-                                            return;
-                                        }
-                                        var.value = tag.value.clone();
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
+    // (link_id, tag_id) of the variables and tag keys the formula actually uses.
+    pub fn dependencies(&self, links: &[Link], index: &TagIndex) -> Vec<(usize, usize)> {
+        let refs = parse_formula_refs(&self.formula);
+        let mut dependencies: Vec<(usize, usize)> = self
+            .vars
+            .iter()
+            .filter(|var| refs.identifiers.contains(&var.name))
+            .map(|var| (var.link_id, var.tag_id))
+            .collect();
+        for tk in refs.tags {
+            if let Some(ids) = index.by_tk(&tk).and_then(|tag_ref| tag_ref.ids(links)) {
+                dependencies.push(ids);
+            }
+        }
+        dependencies
+    }
+
+    // Every writable tag has to be a device or input tag that write_tag() can write.
+    pub fn check_writable_tags(&self, links: &[Link]) -> Result<(), String> {
+        let index = TagIndex::build(links);
+        for tk in self.writable_tags.iter() {
+            let found = index
+                .by_tk(tk)
+                .and_then(|tag_ref| Some((tag_ref, tag_ref.get(links)?)));
+            let Some((tag_ref, tag)) = found else {
+                return Err(format!("Unknown tag: {tk}"));
+            };
+            links[tag_ref.link_index]
+                .check_write(tag_ref.tag_index, tag.value())
+                .map_err(|e| format!("{tk}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    // Push the variables to a new scope.
+    fn scope(&self) -> Scope<'static> {
+        let mut scope = Scope::new();
+        for var in self.vars.iter() {
+            match var.value {
+                TagValue::Real(v) => {
+                    scope.push(var.name.clone(), v as f32);
+                }
+                TagValue::Int(v) => {
+                    scope.push(var.name.clone(), v as i64);
+                }
+                TagValue::Bit(v) => {
+                    scope.push(var.name.clone(), v);
+                }
+                TagValue::Dint(v) => {
+                    scope.push(var.name.clone(), v as i64);
                 }
             }
-            // Create the eval engine and push variables to its scope.
-            let engine = Engine::new();
-            let mut scope = Scope::new();
+        }
+        scope
+    }
 
-            for var in self.vars.iter_mut() {
-                match var.value {
-                    TagValue::Real(v) => {
-                        scope.push(&var.name, v as f32);
-                    }
-                    TagValue::Int(v) => {
-                        scope.push(&var.name, v as i64);
-                    }
-                    TagValue::Bit(v) => {
-                        scope.push(&var.name, v);
-                    }
-                    TagValue::Dint(v) => {
-                        scope.push(&var.name, v as i64);
+    // Run the compiled formula and store the result with the tag's value type.
+    // On failure the status is set and the error returned.
+    pub fn evaluate(&mut self, engine: &Engine, ast: &AST) -> Result<(), Box<EvalAltResult>> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let mut scope = self.scope();
+        let res = match self.value {
+            TagValue::Real(_) => engine
+                .eval_ast_with_scope::<f32>(&mut scope, ast)
+                .map(TagValue::Real),
+            TagValue::Int(_) => engine
+                .eval_ast_with_scope::<i64>(&mut scope, ast)
+                .map(|res| TagValue::Int(res as u16)),
+            TagValue::Dint(_) => engine
+                .eval_ast_with_scope::<i64>(&mut scope, ast)
+                .map(|res| TagValue::Dint(res as u32)),
+            TagValue::Bit(_) => engine
+                .eval_ast_with_scope::<bool>(&mut scope, ast)
+                .map(TagValue::Bit),
+        };
+        match res {
+            Ok(value) => {
+                self.value = value;
+                self.status = TagStatus::Normal;
+                Ok(())
+            }
+            Err(e) => {
+                self.status = TagStatus::Error(e.to_string());
+                Err(e)
+            }
+        }
+    }
+}
+
+// Names a formula uses, read from its compiled AST. Comments and strings don't count.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FormulaRefs {
+    // Variables read.
+    pub identifiers: HashSet<String>,
+    // Functions called without a module prefix.
+    pub functions: HashSet<String>,
+    // Tag keys read through tag("..."), in order of first appearance.
+    pub tags: Vec<String>,
+}
+
+const TAG_KEY_LITERAL: &str = "tag() takes the tag key in quotes, e.g. tag(\"LK0:000\")";
+
+/*
+ * Walk the AST, function bodies included. The tag key of tag() has to be a
+ * string literal (or a constant), otherwise the dependency can't be known
+ * before the formula runs.
+ */
+pub fn formula_refs(ast: &AST) -> Result<FormulaRefs, FormulaError> {
+    let mut refs = FormulaRefs::default();
+    let mut error = None;
+    ast.walk(&mut |path: &[ASTNode]| {
+        let (call, position) = match path.last() {
+            Some(ASTNode::Expr(Expr::Variable(variable, ..))) => {
+                let (_, name, namespace, _) = variable.as_ref();
+                if namespace.is_empty() {
+                    refs.identifiers.insert(name.to_string());
+                }
+                return true;
+            }
+            Some(ASTNode::Expr(Expr::FnCall(call, position)))
+            | Some(ASTNode::Stmt(Stmt::FnCall(call, position))) => (call, *position),
+            Some(ASTNode::Expr(Expr::MethodCall(call, position))) if call.name == "tag" => {
+                error = Some(FormulaError::new(TAG_KEY_LITERAL.to_owned(), *position));
+                return false;
+            }
+            _ => return true,
+        };
+        if !call.namespace.is_empty() {
+            return true;
+        }
+        refs.functions.insert(call.name.to_string());
+        if call.name == "tag" {
+            match call.args.first() {
+                Some(Expr::StringConstant(tk, _)) if call.args.len() == 1 => {
+                    if !refs.tags.iter().any(|r| r == tk.as_str()) {
+                        refs.tags.push(tk.to_string());
                     }
                 }
+                _ => {
+                    error = Some(FormulaError::new(TAG_KEY_LITERAL.to_owned(), position));
+                    return false;
+                }
             }
+        }
+        true
+    });
+    match error {
+        Some(error) => Err(error),
+        None => Ok(refs),
+    }
+}
 
-            // Evaluate the formula.
-            match self.value {
-                TagValue::Real(_) => {
-                    let res = engine.eval_with_scope::<f32>(&mut scope, &self.formula);
-                    match res {
-                        Ok(res) => {
-                            self.value = TagValue::Real(res as f32);
-                            self.status = TagStatus::Normal;
-                        }
-                        Err(e) => self.status = TagStatus::Error(e.to_string()),
-                    }
+// The references of a formula that isn't compiled yet. Empty if it doesn't compile.
+pub fn parse_formula_refs(formula: &str) -> FormulaRefs {
+    Engine::new_raw()
+        .compile(formula)
+        .ok()
+        .and_then(|ast| formula_refs(&ast).ok())
+        .unwrap_or_default()
+}
+
+pub fn to_dynamic(value: &TagValue) -> Dynamic {
+    match value {
+        TagValue::Real(v) => Dynamic::from_float(*v),
+        TagValue::Int(v) => Dynamic::from_int(*v as i64),
+        TagValue::Dint(v) => Dynamic::from_int(*v as i64),
+        TagValue::Bit(v) => Dynamic::from_bool(*v),
+    }
+}
+
+// Compiled formula of an eval and the resolved positions of its variables.
+struct CompiledEval {
+    ast: Result<AST, String>,
+    bindings: Vec<Option<TagRef>>,
+    // Tags read with tag("..."), resolved at compile time.
+    tag_refs: Vec<(String, Option<TagRef>)>,
+    // Set when a variable is disabled, the eval is skipped for the cycle.
+    disabled_var: bool,
+    // Variables reading evals of this same link: (var index, tag index).
+    // They are refreshed right before evaluating so they see this cycle's value.
+    local_vars: Vec<(usize, usize)>,
+    // Set when the eval is part of a circular reference.
+    cycle: Option<String>,
+    // Set when the formula calls prev(), ton() and the like.
+    stateful: bool,
+    // (link_id, tag_id) of the tags the formula reads.
+    inputs: Vec<(usize, usize)>,
+}
+
+/*
+ * Evaluation state owned by an eval link task: one engine for the whole link
+ * and a compiled AST per eval. Formulas are only recompiled and variables
+ * only re-resolved after a reconfig (or when a cached position went stale).
+ */
+pub struct EvalRuntime {
+    engine: Engine,
+    compiled: Vec<CompiledEval>,
+    // Tag indices in dependency order.
+    order: Vec<usize>,
+    // Values served by the tag() function, filled in by bind().
+    tag_values: Arc<RwLock<HashMap<String, Dynamic>>>,
+    functions: StatefulFunctions,
+    // State of the stateful functions by eval id, with the formula it belongs to.
+    // Kept across recompiles as long as the formula is unchanged.
+    fn_states: HashMap<usize, (String, EvalFnState)>,
+    write_requests: Arc<Mutex<WriteRequests>>,
+    // Last value written and when, by (eval id, target tk).
+    last_writes: HashMap<(usize, String), (f64, Instant)>,
+    // Writes waiting for the task to apply them.
+    pending_writes: Vec<EvalWrite>,
+    // Wall-clock deadline of the running eval, in microseconds since `started`.
+    started: Instant,
+    deadline_us: Arc<AtomicU64>,
+    // Evals that exceeded their limits, by eval id.
+    backoff: HashMap<usize, Backoff>,
+}
+
+impl Default for EvalRuntime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EvalRuntime {
+    pub fn new() -> Self {
+        let tag_values: Arc<RwLock<HashMap<String, Dynamic>>> = Arc::default();
+        let mut engine = Engine::new();
+        let values = tag_values.clone();
+        engine.register_fn(
+            "tag",
+            move |tk: &str| -> Result<Dynamic, Box<EvalAltResult>> {
+                match values.read().unwrap().get(tk) {
+                    Some(value) => Ok(value.clone()),
+                    None => Err(format!("Unknown tag: {tk}").into()),
                 }
-                TagValue::Int(_) => {
-                    let res = engine.eval_with_scope::<i64>(&mut scope, &self.formula);
-                    match res {
-                        Ok(res) => {
-                            self.status = TagStatus::Normal;
-                            self.value = TagValue::Int(res as u16);
-                        }
-                        Err(e) => self.status = TagStatus::Error(e.to_string()),
-                    }
+            },
+        );
+        let functions = StatefulFunctions::new();
+        functions.register(&mut engine);
+        register_flow_functions(&mut engine);
+        let write_requests: Arc<Mutex<WriteRequests>> = Arc::default();
+        let requests = write_requests.clone();
+        engine.register_fn(
+            "write_tag",
+            move |tk: &str, value: Dynamic| -> Result<(), Box<EvalAltResult>> {
+                let mut requests = requests.lock().unwrap();
+                if !requests.allowed.iter().any(|allowed| allowed == tk) {
+                    return Err(format!("Tag {tk} is not writable from this eval").into());
                 }
-                TagValue::Dint(_) => {
-                    let res = engine.eval_with_scope::<i64>(&mut scope, &self.formula);
-                    match res {
-                        Ok(res) => {
-                            self.value = TagValue::Dint(res as u32);
-                            self.status = TagStatus::Normal;
-                        }
-                        Err(e) => self.status = TagStatus::Error(e.to_string()),
-                    }
+                let value = if let Ok(v) = value.as_float() {
+                    v as f64
+                } else if let Ok(v) = value.as_int() {
+                    v as f64
+                } else if let Ok(v) = value.as_bool() {
+                    v as u8 as f64
+                } else {
+                    return Err(format!("Can't write a {} to a tag", value.type_name()).into());
+                };
+                requests.requested.push((tk.to_owned(), value));
+                Ok(())
+            },
+        );
+
+        let started = Instant::now();
+        let deadline_us: Arc<AtomicU64> = Arc::default();
+        stop_at_deadline(&mut engine, started, deadline_us.clone());
+
+        Self {
+            engine,
+            compiled: Vec::new(),
+            order: Vec::new(),
+            tag_values,
+            functions,
+            fn_states: HashMap::new(),
+            write_requests,
+            last_writes: HashMap::new(),
+            pending_writes: Vec::new(),
+            started,
+            deadline_us,
+            backoff: HashMap::new(),
+        }
+    }
+
+    pub fn compile(&mut self, link: &EvalLink, links: &[Link]) {
+        let index = TagIndex::build(links);
+        let graph = EvalGraph::build(links);
+        let tag_positions: HashMap<usize, usize> = link
+            .tags
+            .iter()
+            .enumerate()
+            .map(|(i, eval)| (eval.id, i))
+            .collect();
+
+        self.compiled = link
+            .tags
+            .iter()
+            .map(|eval| {
+                let node = EvalNode {
+                    link_id: link.id,
+                    tag_id: eval.id,
+                };
+                let cycle = graph.cycle_of(node).map(|cycle| {
+                    let path = cycle
+                        .iter()
+                        .filter_map(|node| crate::find_tag(links, node.link_id, node.tag_id))
+                        .map(|tag| tag.tk().to_owned())
+                        .collect::<Vec<String>>()
+                        .join(" -> ");
+                    format!("Circular reference: {path}")
+                });
+                let compiled = self
+                    .engine
+                    .compile(&eval.formula)
+                    .map_err(|e| e.to_string())
+                    .and_then(|ast| {
+                        let refs = formula_refs(&ast).map_err(|e| e.message)?;
+                        Ok((ast, refs))
+                    });
+                let (ast, refs) = match compiled {
+                    Ok((ast, refs)) => (Ok(ast), refs),
+                    Err(e) => (Err(e), FormulaRefs::default()),
+                };
+                CompiledEval {
+                    ast,
+                    bindings: eval
+                        .vars
+                        .iter()
+                        .map(|var| index.by_id(var.link_id, var.tag_id))
+                        .collect(),
+                    tag_refs: refs
+                        .tags
+                        .into_iter()
+                        .map(|tk| {
+                            let tag_ref = index.by_tk(&tk);
+                            (tk, tag_ref)
+                        })
+                        .collect(),
+                    disabled_var: false,
+                    local_vars: eval
+                        .vars
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, var)| var.link_id == link.id)
+                        .filter_map(|(i, var)| Some((i, *tag_positions.get(&var.tag_id)?)))
+                        .collect(),
+                    cycle,
+                    stateful: refs
+                        .functions
+                        .iter()
+                        .any(|name| STATEFUL_FUNCTIONS.contains(&name.as_str())),
+                    inputs: eval.dependencies(links, &index),
+                }
+            })
+            .collect();
+
+        // Drop the function state of evals that were removed or got a new formula.
+        self.fn_states.retain(|id, (formula, _)| {
+            link.tags
+                .iter()
+                .any(|eval| eval.id == *id && eval.formula == *formula)
+        });
+        self.backoff.clear();
+        self.last_writes.retain(|(id, tk), _| {
+            link.tags
+                .iter()
+                .any(|eval| eval.id == *id && eval.writable_tags.contains(tk))
+        });
+
+        self.order = graph
+            .order
+            .iter()
+            .filter(|node| node.link_id == link.id)
+            .filter_map(|node| tag_positions.get(&node.tag_id).copied())
+            .collect();
+        if self.order.len() != self.compiled.len() {
+            // Duplicate tag ids, fall back to the list order.
+            self.order = (0..self.compiled.len()).collect();
+        }
+    }
+
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    // Resolve `import` statements against the shared script modules.
+    pub fn set_modules(&mut self, modules: Arc<ScriptModules>) {
+        self.engine
+            .set_module_resolver(ScriptModuleResolver::new(modules));
+    }
+
+    // Every tag read by an eval of the link.
+    pub fn inputs(&self) -> HashSet<(usize, usize)> {
+        self.compiled
+            .iter()
+            .flat_map(|compiled| compiled.inputs.iter().copied())
+            .collect()
+    }
+
+    /*
+     * Copy the current values of the variables into the evals.
+     * Must be called with the state locked. Returns false if a cached
+     * position no longer matches, in which case the caller recompiles.
+     */
+    pub fn bind(&mut self, link: &mut EvalLink, links: &[Link]) -> bool {
+        if self.compiled.len() != link.tags.len() {
+            return false;
+        }
+        let mut tag_values = self.tag_values.write().unwrap();
+        tag_values.clear();
+        for (eval, compiled) in link.tags.iter_mut().zip(self.compiled.iter_mut()) {
+            compiled.disabled_var = false;
+            if !eval.enabled {
+                continue;
+            }
+            for (var, binding) in eval.vars.iter_mut().zip(compiled.bindings.iter()) {
+                // Unknown variables keep their last value, as before.
+                let Some(tag_ref) = binding else {
+                    continue;
+                };
+                let Some(tag) = tag_ref.get(links) else {
+                    return false;
+                };
+                if links[tag_ref.link_index].id() != Some(var.link_id) || tag.id() != var.tag_id {
+                    return false;
+                }
+                if !tag.enabled() {
+                    eval.status =
+                        TagStatus::Error("Variable in the formula is not enabled.".to_string());
+                    compiled.disabled_var = true;
+                    break;
+                }
+                var.value = tag.value().clone();
+            }
+            for (tk, binding) in compiled.tag_refs.iter() {
+                // Unknown keys are reported by tag() when the formula runs.
+                let Some(tag_ref) = binding else {
+                    continue;
+                };
+                let Some(tag) = tag_ref.get(links) else {
+                    return false;
+                };
+                if tag.tk() != tk {
+                    return false;
+                }
+                if !tag.enabled() {
+                    eval.status =
+                        TagStatus::Error(format!("Tag {tk} in the formula is not enabled."));
+                    compiled.disabled_var = true;
+                    break;
                 }
-                TagValue::Bit(_) => {
-                    let res = engine.eval_with_scope::<bool>(&mut scope, &self.formula);
-                    match res {
-                        Ok(res) => {
-                            self.value = TagValue::Bit(res);
-                            self.status = TagStatus::Normal;
+                tag_values.insert(tk.clone(), to_dynamic(tag.value()));
+            }
+        }
+        true
+    }
+
+    /*
+     * Evaluate the evals of the link in dependency order. Doesn't need the
+     * state lock. Evals reading another eval link see that link's last value.
+     * With `changed` only the evals reading one of those tags run, plus the
+     * evals of this link that read a value that changed on the way.
+     */
+    pub fn evaluate(&mut self, link: &mut EvalLink, mut changed: Option<HashSet<(usize, usize)>>) {
+        self.pending_writes.clear();
+        let now = Instant::now();
+        for &i in self.order.iter() {
+            let compiled = &self.compiled[i];
+            if compiled.disabled_var || !link.tags[i].enabled {
+                continue;
+            }
+            if changed
+                .as_ref()
+                .is_some_and(|changed| !compiled.inputs.iter().any(|tag| changed.contains(tag)))
+            {
+                continue;
+            }
+            if let Some(cycle) = &compiled.cycle {
+                link.tags[i].status = TagStatus::Error(cycle.clone());
+                continue;
+            }
+            for &(var_index, tag_index) in compiled.local_vars.iter() {
+                let value = link.tags[tag_index].value.clone();
+                link.tags[i].vars[var_index].value = value;
+            }
+            let eval = &mut link.tags[i];
+            match self.backoff.get_mut(&eval.id) {
+                Some(backoff) if backoff.remaining > 0 => {
+                    // Still backing off after exceeding the limits, keep the error status.
+                    backoff.remaining -= 1;
+                    continue;
+                }
+                _ => {}
+            }
+            let previous = eval.value.clone();
+            let can_write = !eval.writable_tags.is_empty();
+            if can_write {
+                self.write_requests.lock().unwrap().allowed = eval.writable_tags.clone();
+            }
+            apply_limits(
+                &mut self.engine,
+                &self.deadline_us,
+                self.started,
+                &eval.limits,
+            );
+            let result = match &compiled.ast {
+                Ok(ast) if compiled.stateful => {
+                    let (_, state) = self
+                        .fn_states
+                        .remove(&eval.id)
+                        .unwrap_or_else(|| (eval.formula.clone(), EvalFnState::default()));
+                    self.functions.begin(state, now);
+                    let result = eval.evaluate(&self.engine, ast);
+                    self.fn_states
+                        .insert(eval.id, (eval.formula.clone(), self.functions.end()));
+                    result
+                }
+                Ok(ast) => eval.evaluate(&self.engine, ast),
+                Err(e) => {
+                    eval.status = TagStatus::Error(e.clone());
+                    Ok(())
+                }
+            };
+            self.deadline_us.store(0, Ordering::Relaxed);
+            match result {
+                Ok(()) => {
+                    self.backoff.remove(&eval.id);
+                }
+                Err(e) if exceeded_limits(&e) => {
+                    let backoff = self.backoff.entry(eval.id).or_default();
+                    backoff.cycles = (backoff.cycles * 2).clamp(1, EVAL_BACKOFF_MAX_CYCLES);
+                    backoff.remaining = backoff.cycles;
+                    eval.status = TagStatus::Error(format!(
+                        "Exceeded limits: {e}. Skipping {} cycles.",
+                        backoff.cycles
+                    ));
+                }
+                Err(_) => {}
+            }
+            if can_write {
+                let requested = {
+                    let mut requests = self.write_requests.lock().unwrap();
+                    requests.allowed.clear();
+                    std::mem::take(&mut requests.requested)
+                };
+                // Writes of a failed evaluation are dropped.
+                if eval.status == TagStatus::Normal {
+                    for (tk, value) in requested {
+                        let key = (eval.id, tk);
+                        // Only write changed values, at most once per write interval.
+                        let interval = Duration::from_millis(eval.write_interval_ms);
+                        let skip = self.last_writes.get(&key).is_some_and(|(last, time)| {
+                            *last == value || now.duration_since(*time) < interval
+                        });
+                        if skip {
+                            continue;
                         }
-                        Err(e) => self.status = TagStatus::Error(e.to_string()),
+                        self.pending_writes.push(EvalWrite {
+                            eval_tk: eval.tk.clone(),
+                            tk: key.1.clone(),
+                            value,
+                        });
+                        self.last_writes.insert(key, (value, now));
                     }
                 }
             }
+            // Evals later in the order read this cycle's value.
+            let mut tag_values = self.tag_values.write().unwrap();
+            if let Some(value) = tag_values.get_mut(&eval.tk) {
+                *value = to_dynamic(&eval.value);
+            }
+            // Let evals later in the order react to this one.
+            if let Some(changed) = changed.as_mut().filter(|_| eval.value != previous) {
+                changed.insert((link.id, eval.id));
+            }
+        }
+    }
+
+    // Writes requested during the last evaluate() that passed the change and rate checks.
+    pub fn take_writes(&mut self) -> Vec<EvalWrite> {
+        std::mem::take(&mut self.pending_writes)
+    }
+}
+
+// Formula sent to /api/eval/validate and /api/eval/test.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FormulaRequest {
+    pub formula: String,
+    #[serde(default)]
+    pub vars: Vec<EvalInputVar>,
+    // Value type of the result, as in Eval::value.
+    #[serde(default)]
+    pub value: TagValue,
+    // Values by variable name or tag key.
+    #[serde(default)]
+    pub values: HashMap<String, TagValue>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FormulaError {
+    pub message: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FormulaReport {
+    pub valid: bool,
+    pub errors: Vec<FormulaError>,
+    // Declared variables the formula uses.
+    pub variables: Vec<String>,
+    // Tags read with tag("...").
+    pub tags: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FormulaTestResult {
+    #[serde(flatten)]
+    pub report: FormulaReport,
+    pub result: Option<serde_json::Value>,
+    pub result_type: Option<String>,
+    // The result as the eval would store it, if the type fits.
+    pub value: Option<TagValue>,
+    pub error: Option<FormulaError>,
+}
+
+impl FormulaError {
+    fn new(message: String, position: Position) -> Self {
+        Self {
+            message,
+            line: position.line(),
+            column: position.position(),
+        }
+    }
+}
+
+impl FormulaRequest {
+    // Fill in the current value of every variable and tag that wasn't supplied.
+    pub fn resolve_current(&mut self, links: &[Link]) {
+        for var in self.vars.iter() {
+            let value = find_tag(links, var.link_id, var.tag_id).map(|tag| tag.value().clone());
+            if let Some(value) = value {
+                self.values.entry(var.name.clone()).or_insert(value);
+            }
+        }
+        for tk in parse_formula_refs(&self.formula).tags {
+            let value = locate_tk(links, &tk)
+                .and_then(|(link_id, tag_id)| find_tag(links, link_id, tag_id))
+                .map(|tag| tag.value().clone());
+            if let Some(value) = value {
+                self.values.entry(tk).or_insert(value);
+            }
+        }
+    }
+}
+
+impl FormulaReport {
+    // Report tag keys that don't exist.
+    pub fn check_tags(&mut self, links: &[Link]) {
+        for tk in self.tags.iter() {
+            if locate_tk(links, tk).is_none() {
+                self.valid = false;
+                self.errors.push(FormulaError {
+                    message: format!("Unknown tag: {tk}"),
+                    line: None,
+                    column: None,
+                });
+            }
         }
     }
 }
 
+fn dynamic_to_json(value: &Dynamic) -> serde_json::Value {
+    if let Ok(v) = value.as_float() {
+        serde_json::json!(v)
+    } else if let Ok(v) = value.as_int() {
+        serde_json::json!(v)
+    } else if let Ok(v) = value.as_bool() {
+        serde_json::json!(v)
+    } else {
+        serde_json::Value::String(value.to_string())
+    }
+}
+
+// Convert a result the way Eval::evaluate would for a tag of this type.
+fn stored_value(kind: &TagValue, value: &Dynamic) -> Option<TagValue> {
+    match kind {
+        TagValue::Real(_) => value.as_float().ok().map(TagValue::Real),
+        TagValue::Int(_) => value.as_int().ok().map(|v| TagValue::Int(v as u16)),
+        TagValue::Dint(_) => value.as_int().ok().map(|v| TagValue::Dint(v as u32)),
+        TagValue::Bit(_) => value.as_bool().ok().map(TagValue::Bit),
+    }
+}
+
+impl EvalRuntime {
+    /*
+     * Compile a formula for diagnostics. Unlike the eval tasks this uses
+     * strict variables, so names that are neither declared variables nor
+     * defined in the formula itself are reported with their position.
+     */
+    fn check_formula(&mut self, request: &FormulaRequest) -> (FormulaReport, Option<AST>) {
+        let mut report = FormulaReport {
+            valid: true,
+            errors: Vec::new(),
+            variables: Vec::new(),
+            tags: Vec::new(),
+        };
+        let mut scope = Scope::new();
+        for var in request.vars.iter() {
+            scope.push(var.name.clone(), to_dynamic(&var.value));
+        }
+        self.engine.set_strict_variables(true);
+        let compiled = self.engine.compile_with_scope(&scope, &request.formula);
+        self.engine.set_strict_variables(false);
+        match compiled.map_err(|e| FormulaError::new(e.err_type().to_string(), e.position())) {
+            Ok(ast) => match formula_refs(&ast) {
+                Ok(refs) => {
+                    report.variables = request
+                        .vars
+                        .iter()
+                        .filter(|var| refs.identifiers.contains(&var.name))
+                        .map(|var| var.name.clone())
+                        .collect();
+                    report.tags = refs.tags;
+                    (report, Some(ast))
+                }
+                Err(e) => {
+                    report.valid = false;
+                    report.errors.push(e);
+                    (report, None)
+                }
+            },
+            Err(e) => {
+                report.valid = false;
+                report.errors.push(e);
+                (report, None)
+            }
+        }
+    }
+
+    pub fn validate(&mut self, request: &FormulaRequest) -> FormulaReport {
+        self.check_formula(request).0
+    }
+
+    // Compile and run a formula once with the request's values. Nothing is written.
+    pub fn test(&mut self, request: &FormulaRequest) -> FormulaTestResult {
+        let (report, ast) = self.check_formula(request);
+        let mut result = FormulaTestResult {
+            report,
+            result: None,
+            result_type: None,
+            value: None,
+            error: None,
+        };
+        let Some(ast) = ast else {
+            return result;
+        };
+        {
+            let mut tag_values = self.tag_values.write().unwrap();
+            tag_values.clear();
+            for tk in result.report.tags.iter() {
+                if let Some(value) = request.values.get(tk) {
+                    tag_values.insert(tk.clone(), to_dynamic(value));
+                }
+            }
+        }
+        let mut scope = Scope::new();
+        for var in request.vars.iter() {
+            let value = request.values.get(&var.name).unwrap_or(&var.value);
+            scope.push(var.name.clone(), to_dynamic(value));
+        }
+
+        apply_limits(
+            &mut self.engine,
+            &self.deadline_us,
+            self.started,
+            &EvalLimits::default(),
+        );
+        self.functions.begin(EvalFnState::default(), Instant::now());
+        let res = self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &ast);
+        self.functions.end();
+        self.deadline_us.store(0, Ordering::Relaxed);
+
+        match res {
+            Ok(value) => {
+                result.result = Some(dynamic_to_json(&value));
+                result.result_type = Some(value.type_name().to_owned());
+                result.value = stored_value(&request.value, &value);
+            }
+            Err(e) => result.error = Some(FormulaError::new(e.to_string(), e.position())),
+        }
+        result
+    }
+}
+
 impl EvalLink {
     pub fn new(id: usize, name: String, tag_count: usize) -> Self {
         let mut tags: Vec<Eval> = Vec::with_capacity(tag_count);
@@ -221,6 +1044,193 @@ impl EvalLink {
             tags,
             tag_count,
             status: LinkStatus::Normal,
+            scan_time_us: 0,
+            cycle_time_ms: default_cycle_time_ms(),
+            trigger: EvalTrigger::Cyclic,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_links;
+
+    fn eval_writing(tks: &[&str]) -> Eval {
+        let mut eval = Eval::new(0, String::from("EVAL9:000"), String::from("EVAL0"));
+        eval.writable_tags = tks.iter().map(|tk| tk.to_string()).collect();
+        eval
+    }
+
+    fn run_once(formula: &str, limits: EvalLimits) -> EvalLink {
+        let mut links = test_links();
+        let Link::Eval(mut link) = links.remove(2) else {
+            unreachable!()
+        };
+        link.tags[0].formula = formula.to_owned();
+        link.tags[0].limits = limits;
+        let mut runtime = EvalRuntime::new();
+        runtime.compile(&link, &links);
+        runtime.bind(&mut link, &links);
+        runtime.evaluate(&mut link, None);
+        link
+    }
+
+    const RECURSION: &str = "fn depth(n) { if n == 0 { 0.0 } else { depth(n - 1) + 1.0 } }";
+
+    #[test]
+    fn zero_call_depth_falls_back_to_the_default() {
+        let limits = EvalLimits {
+            max_call_depth: 0,
+            ..EvalLimits::default()
+        };
+        let link = run_once(&format!("{RECURSION} depth(5)"), limits.clone());
+        assert_eq!(link.tags[0].value, TagValue::Real(5.0));
+        assert_eq!(link.tags[0].status, TagStatus::Normal);
+
+        let link = run_once(&format!("{RECURSION} depth(100)"), limits);
+        assert!(matches!(link.tags[0].status, TagStatus::Error(_)));
+    }
+
+    #[test]
+    fn writable_tags_accept_registers_and_inputs() {
+        let links = test_links();
+        let eval = eval_writing(&["LK0:000", "LK0:001", "IN:1"]);
+        assert_eq!(eval.check_writable_tags(&links), Ok(()));
+    }
+
+    #[test]
+    fn writable_tags_reject_tags_that_cant_be_written() {
+        let links = test_links();
+        for tk in ["LK0:002", "LK0:003", "EVAL2:000", "LK9:000"] {
+            let result = eval_writing(&[tk]).check_writable_tags(&links);
+            assert!(result.is_err(), "{tk} should be rejected");
+        }
+    }
+
+    fn refs(formula: &str) -> Result<FormulaRefs, FormulaError> {
+        formula_refs(&Engine::new_raw().compile(formula).unwrap())
+    }
+
+    #[test]
+    fn tag_keys_in_comments_and_strings_are_not_references() {
+        let refs = refs(
+            r#"
+            // tag("LK0:001") used to be read here
+            /* tag("LK0:002") */
+            let note = "tag(\"LK0:003\")";
+            fn scaled() { tag("IN:1") * 2.0 }
+            tag("LK0:000") + scaled() + prev(x)
+            "#,
+        )
+        .unwrap();
+        assert_eq!(refs.tags, ["LK0:000", "IN:1"]);
+        assert!(refs.identifiers.contains("x"));
+        assert!(!refs.identifiers.contains("LK0"));
+        assert!(refs.functions.contains("prev"));
+        assert_eq!(refs.functions.get("tag").map(String::as_str), Some("tag"));
+    }
+
+    #[test]
+    fn tag_keys_must_be_literals() {
+        // Constants are folded into the call when compiling.
+        assert_eq!(
+            refs(r#"const KEY = "IN:0"; tag(KEY)"#).unwrap().tags,
+            ["IN:0"]
+        );
+        for formula in [
+            "tag(name)",
+            r#"let key = if x > 0.0 { "IN:0" } else { "IN:1" }; tag(key)"#,
+            "tag(`IN:${x}`)",
+            r#""IN:0".tag()"#,
+            "tag()",
+        ] {
+            let error = refs(formula).unwrap_err();
+            assert_eq!(error.message, TAG_KEY_LITERAL, "{formula}");
+            assert!(error.line.is_some(), "{formula}");
+        }
+
+        let link = run_once("tag(name)", EvalLimits::default());
+        assert_eq!(
+            link.tags[0].status,
+            TagStatus::Error(TAG_KEY_LITERAL.to_owned())
+        );
+    }
+
+    fn eval_link(links: &mut Vec<Link>, formula: &str) -> EvalLink {
+        let Link::Eval(mut link) = links.remove(2) else {
+            unreachable!()
+        };
+        link.tags[0].formula = formula.to_owned();
+        link
+    }
+
+    #[test]
+    fn bind_rereads_values_until_a_tag_moves() {
+        let mut links = test_links();
+        let mut link = eval_link(&mut links, r#"tag("IN:1") * 2.0"#);
+        let mut runtime = EvalRuntime::new();
+        runtime.compile(&link, &links);
+
+        crate::write_tag(&mut links, 1, 1, TagValue::Real(3.0));
+        assert!(runtime.bind(&mut link, &links));
+        runtime.evaluate(&mut link, None);
+        assert_eq!(link.tags[0].value, TagValue::Real(6.0));
+
+        // IN:1 takes the place of IN:0, the cached position is stale.
+        let Link::Inputs(inputs) = &mut links[1] else {
+            unreachable!()
+        };
+        inputs.tags.remove(0);
+        assert!(!runtime.bind(&mut link, &links));
+        runtime.compile(&link, &links);
+        assert!(runtime.bind(&mut link, &links));
+
+        // So is the compiled list once the link gets another eval.
+        link.tags
+            .push(Eval::new(1, "EVAL2:001".to_owned(), "EVAL1".to_owned()));
+        assert!(!runtime.bind(&mut link, &links));
+    }
+
+    // Set IN:0 and run one cycle. Returns the eval's value.
+    fn cycle(
+        runtime: &mut EvalRuntime,
+        link: &mut EvalLink,
+        links: &mut [Link],
+        x: f32,
+    ) -> TagValue {
+        crate::write_tag(links, 1, 0, TagValue::Real(x));
+        assert!(runtime.bind(link, links));
+        runtime.evaluate(link, None);
+        link.tags[0].value.clone()
+    }
+
+    #[test]
+    fn function_state_is_kept_while_the_formula_is_unchanged() {
+        let mut links = test_links();
+        let mut link = eval_link(&mut links, r#"prev(tag("IN:0"))"#);
+        let mut runtime = EvalRuntime::new();
+
+        runtime.compile(&link, &links);
+        assert_eq!(
+            cycle(&mut runtime, &mut link, &mut links, 1.0),
+            TagValue::Real(1.0)
+        );
+        assert_eq!(
+            cycle(&mut runtime, &mut link, &mut links, 5.0),
+            TagValue::Real(1.0)
+        );
+        runtime.compile(&link, &links);
+        assert_eq!(
+            cycle(&mut runtime, &mut link, &mut links, 7.0),
+            TagValue::Real(5.0)
+        );
+
+        link.tags[0].formula = r#"prev(tag("IN:0")) + 0.0"#.to_owned();
+        runtime.compile(&link, &links);
+        assert_eq!(
+            cycle(&mut runtime, &mut link, &mut links, 9.0),
+            TagValue::Real(9.0)
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use rhai::{Engine, EvalAltResult, FLOAT};
+use std::f64::consts::PI;
+
+/*
+ * Flow measurement calculations for eval formulas. Everything is in SI units:
+ * metres, pascals or kilopascals as noted, degrees Celsius and kg/m3.
+ * The math is done in f64 and only the Rhai wrappers use FLOAT.
+ */
+
+// Flange tap distance from the orifice plate, metres.
+const FLANGE_TAP_M: f64 = 0.0254;
+// Water density at 60 °F, used for API gravity and specific gravity.
+const WATER_DENSITY_60F: f64 = 999.016;
+
+/*
+ * Orifice discharge coefficient with flange taps, Reader-Harris/Gallagher
+ * equation as used by AGA-3 and ISO 5167-2.
+ */
+pub fn orifice_cd(beta: f64, reynolds: f64, pipe_d: f64) -> f64 {
+    let l1 = FLANGE_TAP_M / pipe_d;
+    let m2 = 2.0 * l1 / (1.0 - beta);
+    let a = (19000.0 * beta / reynolds).powf(0.8);
+    let beta4 = beta.powi(4);
+
+    let mut cd = 0.5961 + 0.0261 * beta.powi(2) - 0.216 * beta.powi(8)
+        + 0.000521 * (1e6 * beta / reynolds).powf(0.7)
+        + (0.0188 + 0.0063 * a) * beta.powf(3.5) * (1e6 / reynolds).powf(0.3)
+        + (0.043 + 0.080 * (-10.0 * l1).exp() - 0.123 * (-7.0 * l1).exp())
+            * (1.0 - 0.11 * a)
+            * beta4
+            / (1.0 - beta4)
+        - 0.031 * (m2 - 0.8 * m2.powf(1.1)) * beta.powf(1.3);
+    // Small pipes.
+    if pipe_d < 0.07112 {
+        cd += 0.011 * (0.75 - beta) * (2.8 - pipe_d / 0.0254);
+    }
+    cd
+}
+
+/*
+ * Mass flow through an orifice meter in kg/s (AGA-3).
+ * `bore` and `pipe_d` in metres at flowing temperature, `dp` and the upstream
+ * static pressure `p1` in Pa (absolute), `density` at flowing conditions,
+ * `viscosity` in Pa·s and `k` the isentropic exponent. A `k` of 0 or less
+ * means an incompressible fluid (no expansion correction).
+ * The discharge coefficient depends on the Reynolds number, so it is iterated.
+ */
+pub fn orifice_mass_flow(
+    bore: f64,
+    pipe_d: f64,
+    dp: f64,
+    p1: f64,
+    density: f64,
+    viscosity: f64,
+    k: f64,
+) -> f64 {
+    if bore <= 0.0 || pipe_d <= bore || dp <= 0.0 || density <= 0.0 || viscosity <= 0.0 {
+        return 0.0;
+    }
+    let beta = bore / pipe_d;
+    let velocity_of_approach = 1.0 / (1.0 - beta.powi(4)).sqrt();
+    let expansion = if k > 0.0 && p1 > 0.0 {
+        1.0 - (0.41 + 0.35 * beta.powi(4)) * (dp / p1) / k
+    } else {
+        1.0
+    };
+    let flow_without_cd =
+        velocity_of_approach * expansion * PI / 4.0 * bore.powi(2) * (2.0 * density * dp).sqrt();
+
+    let mut cd = 0.6;
+    let mut mass_flow = cd * flow_without_cd;
+    for _ in 0..20 {
+        let reynolds = 4.0 * mass_flow / (PI * pipe_d * viscosity);
+        let next_cd = orifice_cd(beta, reynolds, pipe_d);
+        mass_flow = next_cd * flow_without_cd;
+        if (next_cd - cd).abs() < 1e-9 {
+            break;
+        }
+        cd = next_cd;
+    }
+    mass_flow
+}
+
+/*
+ * Gas volume at base conditions from a volume at flowing conditions (AGA-7).
+ * Pressures absolute, in any one unit. Temperatures in °C.
+ */
+pub fn gas_base_volume(
+    volume: f64,
+    p_flow: f64,
+    t_flow: f64,
+    z_flow: f64,
+    p_base: f64,
+    t_base: f64,
+    z_base: f64,
+) -> f64 {
+    volume * (p_flow / p_base) * ((t_base + 273.15) / (t_flow + 273.15)) * (z_base / z_flow)
+}
+
+// Volume counted by a pulse meter with a K-factor in pulses per unit volume.
+pub fn pulses_to_volume(pulses: f64, k_factor: f64) -> f64 {
+    if k_factor <= 0.0 {
+        return 0.0;
+    }
+    pulses / k_factor
+}
+
+/*
+ * Thermal expansion coefficient at 15 °C for an API MPMS 11.1 commodity
+ * group. `density` is the base density at 15 °C in kg/m3.
+ */
+pub fn thermal_expansion(density: f64, product: &str) -> Option<f64> {
+    let (k0, k1, k2) = match product {
+        "crude" => (613.9723, 0.0, 0.0),
+        "gasoline" => (346.4228, 0.4388, 0.0),
+        "jet" => (594.5418, 0.0, 0.0),
+        "fuel_oil" => (186.9696, 0.4862, 0.0),
+        // Transition zone between gasoline and jet fuel.
+        "transition" => (2680.3206, 0.0, -0.00336312),
+        _ => return None,
+    };
+    Some(k0 / density.powi(2) + k1 / density + k2)
+}
+
+/*
+ * Correction for the effect of temperature on a liquid (CTL), API MPMS 11.1
+ * (Table 54 form): the ratio of the volume at 15 °C to the volume at `t` °C.
+ */
+pub fn ctl(t: f64, density: f64, product: &str) -> Option<f64> {
+    let alpha = thermal_expansion(density, product)?;
+    let dt = t - 15.0;
+    Some((-alpha * dt * (1.0 + 0.8 * alpha * dt)).exp())
+}
+
+/*
+ * Compressibility factor of a hydrocarbon liquid in 1/kPa, API MPMS 11.2.1M.
+ * `t` in °C, `density` at 15 °C in kg/m3.
+ */
+pub fn compressibility(t: f64, density: f64) -> f64 {
+    let d2 = density.powi(2);
+    (-1.6208 + 0.00021592 * t + 0.87096e6 / d2 + 4.2092e3 * t / d2).exp() * 1e-6
+}
+
+/*
+ * Correction for the effect of pressure on a liquid (CPL). `p` and the
+ * equilibrium vapour pressure `pe` in kPa gauge.
+ */
+pub fn cpl(t: f64, p: f64, pe: f64, density: f64) -> f64 {
+    1.0 / (1.0 - compressibility(t, density) * (p - pe.max(0.0)))
+}
+
+pub fn api_to_density(api: f64) -> f64 {
+    141.5 / (api + 131.5) * WATER_DENSITY_60F
+}
+
+pub fn density_to_api(density: f64) -> f64 {
+    141.5 * WATER_DENSITY_60F / density - 131.5
+}
+
+// Register the flow functions on an eval engine.
+pub fn register_flow_functions(engine: &mut Engine) {
+    engine.register_fn(
+        "orifice_cd",
+        |beta: FLOAT, reynolds: FLOAT, pipe_d: FLOAT| -> FLOAT {
+            orifice_cd(beta as f64, reynolds as f64, pipe_d as f64) as FLOAT
+        },
+    );
+    engine.register_fn(
+        "orifice_mass_flow",
+        |bore: FLOAT,
+         pipe_d: FLOAT,
+         dp: FLOAT,
+         p1: FLOAT,
+         density: FLOAT,
+         viscosity: FLOAT,
+         k: FLOAT|
+         -> FLOAT {
+            orifice_mass_flow(
+                bore as f64,
+                pipe_d as f64,
+                dp as f64,
+                p1 as f64,
+                density as f64,
+                viscosity as f64,
+                k as f64,
+            ) as FLOAT
+        },
+    );
+    engine.register_fn(
+        "gas_base_volume",
+        |volume: FLOAT,
+         p_flow: FLOAT,
+         t_flow: FLOAT,
+         z_flow: FLOAT,
+         p_base: FLOAT,
+         t_base: FLOAT,
+         z_base: FLOAT|
+         -> FLOAT {
+            gas_base_volume(
+                volume as f64,
+                p_flow as f64,
+                t_flow as f64,
+                z_flow as f64,
+                p_base as f64,
+                t_base as f64,
+                z_base as f64,
+            ) as FLOAT
+        },
+    );
+    engine.register_fn(
+        "pulses_to_volume",
+        |pulses: FLOAT, k_factor: FLOAT| -> FLOAT {
+            pulses_to_volume(pulses as f64, k_factor as f64) as FLOAT
+        },
+    );
+    engine.register_fn(
+        "ctl",
+        |t: FLOAT, density: FLOAT, product: &str| -> Result<FLOAT, Box<EvalAltResult>> {
+            match ctl(t as f64, density as f64, product) {
+                Some(ctl) => Ok(ctl as FLOAT),
+                None => Err(format!("Unknown product: {product}").into()),
+            }
+        },
+    );
+    engine.register_fn(
+        "cpl",
+        |t: FLOAT, p: FLOAT, pe: FLOAT, density: FLOAT| -> FLOAT {
+            cpl(t as f64, p as f64, pe as f64, density as f64) as FLOAT
+        },
+    );
+    engine.register_fn("api_to_density", |api: FLOAT| -> FLOAT {
+        api_to_density(api as f64) as FLOAT
+    });
+    engine.register_fn("density_to_api", |density: FLOAT| -> FLOAT {
+        density_to_api(density as f64) as FLOAT
+    });
+    engine.register_fn("c_to_f", |t: FLOAT| -> FLOAT { t * 1.8 + 32.0 });
+    engine.register_fn("f_to_c", |t: FLOAT| -> FLOAT { (t - 32.0) / 1.8 });
+    engine.register_fn("c_to_k", |t: FLOAT| -> FLOAT { t + 273.15 });
+    engine.register_fn("psi_to_kpa", |p: FLOAT| -> FLOAT { p * 6.894757 });
+    engine.register_fn("kpa_to_psi", |p: FLOAT| -> FLOAT { p / 6.894757 });
+    engine.register_fn("bar_to_kpa", |p: FLOAT| -> FLOAT { p * 100.0 });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} is not within {tolerance} of {expected}"
+        );
+    }
+
+    // ISO 5167-2 / AGA-3 Reader-Harris/Gallagher equation, flange taps, evaluated by hand.
+    #[test]
+    fn orifice_cd_matches_the_reader_harris_gallagher_equation() {
+        assert_close(orifice_cd(0.5, 1e6, 0.1), 0.603132, 1e-6);
+        assert_close(orifice_cd(0.6, 1e5, 0.2), 0.610130, 1e-6);
+        // Below 71.12 mm the small pipe term applies.
+        assert_close(orifice_cd(0.4, 1e5, 0.05), 0.604829, 1e-6);
+    }
+
+    #[test]
+    fn orifice_mass_flow_converges_on_the_reynolds_number() {
+        let (bore, pipe_d, dp, density, viscosity): (f64, f64, f64, f64, f64) =
+            (0.05, 0.1, 25_000.0, 999.0, 0.001);
+        // The flow has to agree with the Cd at its own Reynolds number.
+        let expected = |mass_flow: f64, expansion: f64| {
+            let reynolds = 4.0 * mass_flow / (PI * pipe_d * viscosity);
+            orifice_cd(0.5, reynolds, pipe_d) / (1.0 - 0.5f64.powi(4)).sqrt() * expansion * PI / 4.0
+                * bore.powi(2)
+                * (2.0 * density * dp).sqrt()
+        };
+        let liquid = orifice_mass_flow(bore, pipe_d, dp, 0.0, density, viscosity, 0.0);
+        assert_close(liquid, expected(liquid, 1.0), liquid * 1e-9);
+        assert_close(liquid, 8.685, 0.001);
+
+        // AGA-3 expansion factor Y = 1 - (0.41 + 0.35 β⁴) x / k, x = dp / p1.
+        let gas = orifice_mass_flow(bore, pipe_d, dp, 1e6, density, viscosity, 1.3);
+        let y = 1.0 - (0.41 + 0.35 * 0.0625) * 0.025 / 1.3;
+        assert_close(gas, expected(gas, y), gas * 1e-9);
+        assert!(gas < liquid);
+    }
+
+    #[test]
+    fn orifice_mass_flow_is_zero_for_invalid_input() {
+        assert_eq!(orifice_mass_flow(0.0, 0.1, 1e4, 0.0, 1.0, 1e-5, 0.0), 0.0);
+        assert_eq!(orifice_mass_flow(0.1, 0.1, 1e4, 0.0, 1.0, 1e-5, 0.0), 0.0);
+        assert_eq!(orifice_mass_flow(0.05, 0.1, -1.0, 0.0, 1.0, 1e-5, 0.0), 0.0);
+    }
+
+    // AGA-7: Vb = Vf (Pf / Pb) (Tb / Tf) (Zb / Zf).
+    #[test]
+    fn gas_base_volume_applies_the_aga7_ratios() {
+        let volume = gas_base_volume(1000.0, 4000.0, 15.0, 0.9, 101.325, 15.0, 0.998);
+        assert_close(volume, 43775.53, 0.01);
+        // A 10 °C warmer gas takes less volume at base conditions.
+        let warmer = gas_base_volume(1000.0, 4000.0, 25.0, 0.9, 101.325, 15.0, 0.998);
+        assert_close(warmer / volume, 288.15 / 298.15, 1e-12);
+        // At base conditions nothing changes.
+        assert_close(
+            gas_base_volume(1.0, 101.325, 15.0, 0.998, 101.325, 15.0, 0.998),
+            1.0,
+            1e-12,
+        );
+    }
+
+    #[test]
+    fn pulses_to_volume_uses_the_k_factor() {
+        assert_eq!(pulses_to_volume(5000.0, 250.0), 20.0);
+        assert_eq!(pulses_to_volume(5000.0, 0.0), 0.0);
+    }
+
+    // API MPMS 11.1 Tables 54A (crude) and 54B (products), four decimals.
+    #[test]
+    fn ctl_matches_the_volume_correction_tables() {
+        assert_close(ctl(30.0, 850.0, "crude").unwrap(), 0.9872, 1e-4);
+        assert_close(ctl(0.0, 850.0, "crude").unwrap(), 1.0127, 1e-4);
+        assert_close(ctl(25.0, 750.0, "gasoline").unwrap(), 0.9879, 1e-4);
+        assert_close(ctl(40.0, 820.0, "jet").unwrap(), 0.9778, 1e-4);
+        assert_eq!(ctl(15.0, 850.0, "crude"), Some(1.0));
+        assert_eq!(ctl(20.0, 850.0, "water"), None);
+    }
+
+    // API MPMS 11.2.1M compressibility factor and CPL.
+    #[test]
+    fn cpl_uses_the_compressibility_factor() {
+        assert_close(compressibility(15.0, 850.0), 0.72275e-6, 1e-10);
+        assert_close(cpl(15.0, 1000.0, 0.0, 850.0), 1.000723, 1e-6);
+        // The vapour pressure is subtracted, negative values count as 0.
+        assert_close(
+            cpl(15.0, 1100.0, 100.0, 850.0),
+            cpl(15.0, 1000.0, 0.0, 850.0),
+            1e-12,
+        );
+        assert_eq!(
+            cpl(15.0, 1000.0, -50.0, 850.0),
+            cpl(15.0, 1000.0, 0.0, 850.0)
+        );
+    }
+
+    #[test]
+    fn api_gravity_round_trips() {
+        // 10 °API is the density of water at 60 °F.
+        assert_close(api_to_density(10.0), WATER_DENSITY_60F, 1e-9);
+        assert_close(density_to_api(api_to_density(35.0)), 35.0, 1e-9);
+    }
+}
@@ -0,0 +1,261 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+// Upper bound on the number of buckets a single query may produce.
+pub const MAX_HISTORY_BUCKETS: i64 = 10_000;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum HistoryAggregation {
+    #[default]
+    Raw,
+    Avg,
+    Min,
+    Max,
+    First,
+    Last,
+    Count,
+    // Average where each sample is weighted by how long it was held.
+    TimeWeightedAvg,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct HistoryQuery {
+    pub tks: Vec<String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    #[serde(default)]
+    pub aggregation: HistoryAggregation,
+    // Bucket size for aggregated queries. Without it the whole
+    // time range is reduced to a single value.
+    pub bucket_secs: Option<u64>,
+    // Only answer from this logger link.
+    pub logger_id: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HistoryPoint {
+    pub time: DateTime<Utc>,
+    pub value: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HistorySeries {
+    pub tk: String,
+    pub points: Vec<HistoryPoint>,
+}
+
+impl HistoryQuery {
+    // None when `bucket_secs` is 0 or too large to be a time span.
+    pub fn bucket(&self) -> Option<Duration> {
+        match self.bucket_secs {
+            Some(secs) => i64::try_from(secs)
+                .ok()
+                .and_then(Duration::try_seconds)
+                .filter(|bucket| *bucket > Duration::zero()),
+            None => Some(self.end - self.start),
+        }
+    }
+
+    // None for an invalid bucket size or an empty time range.
+    pub fn bucket_count(&self) -> Option<i64> {
+        let bucket = self.bucket()?.num_milliseconds();
+        let range = (self.end - self.start).num_milliseconds();
+        if bucket <= 0 || range <= 0 {
+            return None;
+        }
+        Some((range + bucket - 1) / bucket)
+    }
+}
+
+/*
+ * Reduce raw points (sorted by time, inside [start, end)) to one point per bucket.
+ * Buckets without samples are left out, except for the time-weighted average
+ * which holds the last known value across empty buckets.
+ */
+pub fn aggregate(points: &[HistoryPoint], query: &HistoryQuery) -> Vec<HistoryPoint> {
+    if query.aggregation == HistoryAggregation::Raw {
+        return points.to_vec();
+    }
+
+    let Some(bucket) = query.bucket().filter(|bucket| *bucket > Duration::zero()) else {
+        return Vec::new();
+    };
+    let mut result = Vec::new();
+    let mut bucket_start = query.start;
+    let mut index = 0;
+    // Last value seen before the current bucket.
+    let mut held: Option<f64> = None;
+
+    while bucket_start < query.end {
+        let bucket_end = (bucket_start + bucket).min(query.end);
+        let first = index;
+        while index < points.len() && points[index].time < bucket_end {
+            index += 1;
+        }
+        let samples = &points[first..index];
+        let values = samples.iter().map(|p| p.value);
+
+        let value = match query.aggregation {
+            HistoryAggregation::Raw => None,
+            HistoryAggregation::Avg => {
+                if samples.is_empty() {
+                    None
+                } else {
+                    Some(values.sum::<f64>() / samples.len() as f64)
+                }
+            }
+            HistoryAggregation::Min => values.reduce(f64::min),
+            HistoryAggregation::Max => values.reduce(f64::max),
+            HistoryAggregation::First => samples.first().map(|p| p.value),
+            HistoryAggregation::Last => samples.last().map(|p| p.value),
+            HistoryAggregation::Count => (!samples.is_empty()).then_some(samples.len() as f64),
+            HistoryAggregation::TimeWeightedAvg => {
+                time_weighted_average(samples, held, bucket_start, bucket_end)
+            }
+        };
+
+        if let Some(value) = value {
+            result.push(HistoryPoint {
+                time: bucket_start,
+                value,
+            });
+        }
+        if let Some(last) = samples.last() {
+            held = Some(last.value);
+        }
+        bucket_start = bucket_end;
+    }
+    result
+}
+
+// Step interpolation: a sample keeps its value until the next one arrives.
+fn time_weighted_average(
+    samples: &[HistoryPoint],
+    held: Option<f64>,
+    bucket_start: DateTime<Utc>,
+    bucket_end: DateTime<Utc>,
+) -> Option<f64> {
+    let mut area = 0.0;
+    let mut covered = 0.0;
+    let mut current = held.map(|value| (bucket_start, value));
+
+    for sample in samples {
+        if let Some((time, value)) = current {
+            let dt = (sample.time - time).num_milliseconds() as f64;
+            area += value * dt;
+            covered += dt;
+        }
+        current = Some((sample.time, sample.value));
+    }
+    let (time, value) = current?;
+    let dt = (bucket_end - time).num_milliseconds() as f64;
+    area += value * dt;
+    covered += dt;
+
+    if covered > 0.0 {
+        Some(area / covered)
+    } else {
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(aggregation: HistoryAggregation, bucket_secs: Option<u64>) -> HistoryQuery {
+        HistoryQuery {
+            tks: vec!["IN:0".to_owned()],
+            start: time(0),
+            end: time(30),
+            aggregation,
+            bucket_secs,
+            logger_id: None,
+        }
+    }
+
+    fn time(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn point(secs: i64, value: f64) -> HistoryPoint {
+        HistoryPoint {
+            time: time(secs),
+            value,
+        }
+    }
+
+    // Two samples in the first 10 s bucket, none in the second, one in the third.
+    fn points() -> Vec<HistoryPoint> {
+        vec![point(0, 1.0), point(5, 3.0), point(25, 10.0)]
+    }
+
+    fn values(aggregation: HistoryAggregation) -> Vec<(i64, f64)> {
+        aggregate(&points(), &query(aggregation, Some(10)))
+            .iter()
+            .map(|p| (p.time.timestamp() - time(0).timestamp(), p.value))
+            .collect()
+    }
+
+    #[test]
+    fn aggregations_per_bucket() {
+        assert_eq!(values(HistoryAggregation::Raw).len(), 3);
+        assert_eq!(values(HistoryAggregation::Avg), [(0, 2.0), (20, 10.0)]);
+        assert_eq!(values(HistoryAggregation::Min), [(0, 1.0), (20, 10.0)]);
+        assert_eq!(values(HistoryAggregation::Max), [(0, 3.0), (20, 10.0)]);
+        assert_eq!(values(HistoryAggregation::First), [(0, 1.0), (20, 10.0)]);
+        assert_eq!(values(HistoryAggregation::Last), [(0, 3.0), (20, 10.0)]);
+        assert_eq!(values(HistoryAggregation::Count), [(0, 2.0), (20, 1.0)]);
+    }
+
+    #[test]
+    fn time_weighted_average_holds_values_across_buckets() {
+        // 1.0 for 5 s and 3.0 for 5 s, then 3.0 held, then 3.0 for 5 s and 10.0 for 5 s.
+        assert_eq!(
+            values(HistoryAggregation::TimeWeightedAvg),
+            [(0, 2.0), (10, 3.0), (20, 6.5)]
+        );
+    }
+
+    #[test]
+    fn without_a_bucket_the_range_is_one_bucket() {
+        let query = query(HistoryAggregation::Max, None);
+        assert_eq!(query.bucket_count(), Some(1));
+        assert_eq!(aggregate(&points(), &query), [point(0, 10.0)]);
+    }
+
+    #[test]
+    fn bucket_count_rounds_up() {
+        assert_eq!(
+            query(HistoryAggregation::Avg, Some(10)).bucket_count(),
+            Some(3)
+        );
+        assert_eq!(
+            query(HistoryAggregation::Avg, Some(7)).bucket_count(),
+            Some(5)
+        );
+        assert_eq!(
+            query(HistoryAggregation::Avg, Some(60)).bucket_count(),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn invalid_buckets_and_ranges_are_rejected() {
+        for secs in [0, i64::MAX as u64 / 1000 + 1, 1 << 63, u64::MAX] {
+            let query = query(HistoryAggregation::Count, Some(secs));
+            assert_eq!(query.bucket(), None, "{secs}");
+            assert_eq!(query.bucket_count(), None, "{secs}");
+            assert!(aggregate(&points(), &query).is_empty());
+        }
+
+        let mut empty = query(HistoryAggregation::Count, None);
+        empty.end = empty.start;
+        assert_eq!(empty.bucket_count(), None);
+        assert!(aggregate(&points(), &empty).is_empty());
+        let mut reversed = query(HistoryAggregation::Count, Some(10));
+        reversed.end = time(-30);
+        assert_eq!(reversed.bucket_count(), None);
+        assert!(aggregate(&points(), &reversed).is_empty());
+    }
+}
@@ -1,6 +1,7 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use crate::TagValue;
+use crate::{TagStatus, TagValue};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Input {
@@ -12,6 +13,11 @@ pub struct Input {
     pub enabled: bool,
     //#[serde(skip_deserializing)]
     pub value: TagValue,
+    #[serde(skip_deserializing)]
+    pub status: TagStatus,
+    // When the value last changed.
+    #[serde(default)]
+    pub changed_at: Option<DateTime<Utc>>,
 }
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct InputsLink {
@@ -33,6 +39,8 @@ impl Input {
             description: String::from("Input tag."),
             enabled: true,
             value: TagValue::Real(0.0),
+            status: TagStatus::Normal,
+            changed_at: None,
         }
     }
 }
@@ -0,0 +1,199 @@
+use anyhow::Result;
+use log::info;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, mpsc};
+
+/*
+ * Append-only journal stored as JSON lines.
+ * The active file is `<name>.jsonl`. When it grows past `max_bytes` it is
+ * rotated to `<name>.1.jsonl`, older files shift up and anything beyond
+ * `max_files` is deleted.
+ * Appends are handed to a writer thread, so callers holding the state lock
+ * never wait on file IO.
+ */
+#[derive(Debug)]
+pub struct Journal {
+    files: Arc<JournalFiles>,
+    writer: mpsc::Sender<JournalCommand>,
+}
+
+#[derive(Debug)]
+struct JournalFiles {
+    dir: PathBuf,
+    name: String,
+    max_bytes: u64,
+    max_files: usize,
+    // Serializes appends, rotations and reads.
+    lock: Mutex<()>,
+}
+
+#[derive(Debug)]
+enum JournalCommand {
+    Append(String),
+    // Answered once every line sent before it is written.
+    Flush(mpsc::Sender<()>),
+}
+
+impl Journal {
+    pub fn new(dir: &str, name: &str, max_bytes: u64, max_files: usize) -> Self {
+        let files = Arc::new(JournalFiles {
+            dir: PathBuf::from(dir),
+            name: name.to_owned(),
+            max_bytes,
+            max_files: max_files.max(1),
+            lock: Mutex::new(()),
+        });
+        let (writer, commands) = mpsc::channel();
+        let thread_files = files.clone();
+        // The thread ends when the journal is dropped.
+        std::thread::Builder::new()
+            .name(format!("journal-{name}"))
+            .spawn(move || {
+                for command in commands {
+                    match command {
+                        JournalCommand::Append(line) => {
+                            if let Err(e) = thread_files.append_line(&line) {
+                                info!("Could not write to the {} journal: {e}", thread_files.name);
+                            }
+                        }
+                        JournalCommand::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })
+            .expect("Could not start the journal writer");
+        Self { files, writer }
+    }
+
+    // Queue an entry for the writer thread. Doesn't block.
+    pub fn append<T: Serialize>(&self, entry: &T) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.writer.send(JournalCommand::Append(line))?;
+        Ok(())
+    }
+
+    // Wait until every entry appended so far is on disk. Blocking.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.writer.send(JournalCommand::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+
+    // All entries, oldest first. Lines that fail to parse are skipped. Blocking.
+    pub fn read_all<T: DeserializeOwned>(&self) -> Result<Vec<T>> {
+        self.flush();
+        self.files.read_all()
+    }
+}
+
+impl JournalFiles {
+    fn file_path(&self, index: usize) -> PathBuf {
+        if index == 0 {
+            self.dir.join(format!("{}.jsonl", self.name))
+        } else {
+            self.dir.join(format!("{}.{index}.jsonl", self.name))
+        }
+    }
+
+    fn append_line(&self, line: &str) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        fs::create_dir_all(&self.dir)?;
+        let path = self.file_path(0);
+        if let Ok(metadata) = fs::metadata(&path)
+            && metadata.len() + line.len() as u64 > self.max_bytes
+        {
+            self.rotate()?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    fn rotate(&self) -> Result<()> {
+        let _ = fs::remove_file(self.file_path(self.max_files - 1));
+        for index in (0..self.max_files - 1).rev() {
+            let from = self.file_path(index);
+            if from.exists() {
+                fs::rename(from, self.file_path(index + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_all<T: DeserializeOwned>(&self) -> Result<Vec<T>> {
+        let _guard = self.lock.lock().unwrap();
+        let mut entries = Vec::new();
+        for index in (0..self.max_files).rev() {
+            let Ok(file) = File::open(self.file_path(index)) else {
+                continue;
+            };
+            for line in BufReader::new(file).lines() {
+                if let Ok(entry) = serde_json::from_str(&line?) {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("sentinel-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn reads_back_entries_in_order() {
+        let dir = temp_dir("journal-order");
+        let journal = Journal::new(&dir, "test", 1 << 20, 3);
+        for i in 0..10 {
+            journal.append(&i).unwrap();
+        }
+        assert_eq!(
+            journal.read_all::<i32>().unwrap(),
+            (0..10).collect::<Vec<_>>()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_drops_the_oldest_files() {
+        let dir = temp_dir("journal-rotation");
+        // Every entry is "nnn\n", so each file takes two of them.
+        let journal = Journal::new(&dir, "test", 8, 3);
+        for i in 100..110 {
+            journal.append(&i).unwrap();
+        }
+        assert_eq!(
+            journal.read_all::<i32>().unwrap(),
+            vec![104, 105, 106, 107, 108, 109]
+        );
+        assert!(Path::new(&dir).join("test.2.jsonl").exists());
+        assert!(!Path::new(&dir).join("test.3.jsonl").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_lines_that_do_not_parse() {
+        let dir = temp_dir("journal-skip");
+        let journal = Journal::new(&dir, "test", 1 << 20, 2);
+        journal.append(&1).unwrap();
+        journal.append(&"not a number").unwrap();
+        journal.append(&2).unwrap();
+        assert_eq!(journal.read_all::<i32>().unwrap(), vec![1, 2]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
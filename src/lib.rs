@@ -1,17 +1,49 @@
+pub mod alarms;
 pub mod api;
+pub mod audit;
+pub mod auth;
 pub mod device_link;
+pub mod error;
+pub mod eval_functions;
+pub mod eval_graph;
 pub mod eval_link;
+pub mod flow;
+pub mod history;
 pub mod inputs_link;
+pub mod journal;
 pub mod link;
 pub mod logger_link;
+pub mod metrics;
+pub mod notifications;
+pub mod postgres_tls;
+pub mod script_modules;
 pub mod state;
+pub mod streaming;
+pub mod tag_events;
 pub mod task;
+#[cfg(test)]
+pub mod test_support;
 
+pub use alarms::*;
 pub use api::*;
+pub use audit::*;
+pub use auth::*;
 pub use device_link::*;
+pub use error::*;
+pub use eval_functions::*;
+pub use eval_graph::*;
 pub use eval_link::*;
+pub use flow::*;
+pub use history::*;
 pub use inputs_link::*;
+pub use journal::*;
 pub use link::*;
 pub use logger_link::*;
+pub use metrics::*;
+pub use notifications::*;
+pub use postgres_tls::*;
+pub use script_modules::*;
 pub use state::*;
+pub use streaming::*;
+pub use tag_events::*;
 pub use task::*;
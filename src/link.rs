@@ -1,7 +1,12 @@
 use crate::{Input, InputsLink, LoggerLink, device_link::*, eval_link::*};
+use chrono::{DateTime, Utc};
+use log::info;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 pub const MAX_NUM_LINKS: usize = 5;
+// Logger links have their own limit, e.g. one local log and one cloud log per site.
+pub const MAX_NUM_LOGGER_LINKS: usize = 8;
 
 // Helper type to use for the logger tag list.
 #[derive(Clone, Debug, Serialize, PartialEq, Deserialize)]
@@ -10,6 +15,53 @@ pub enum AbstractTag {
     InputTag(Input),
     EvalTag(Eval),
 }
+
+// Borrowed view of a tag, avoids cloning when only reading values.
+// Serializes like the tag itself.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(untagged)]
+pub enum TagView<'a> {
+    DeviceTag(&'a Tag),
+    InputTag(&'a Input),
+    EvalTag(&'a Eval),
+}
+
+// Position of a tag in the links list, valid until the links are reconfigured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagRef {
+    pub link_index: usize,
+    pub tag_index: usize,
+}
+
+// Lookup of tag positions by (link_id, tag_id) and by tk.
+#[derive(Clone, Debug, Default)]
+pub struct TagIndex {
+    by_id: HashMap<(usize, usize), TagRef>,
+    by_tk: HashMap<String, TagRef>,
+}
+
+// A tag given either by its tk or by its link and tag ids.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TagKey {
+    Tk(String),
+    Ids { link_id: usize, tag_id: usize },
+}
+
+// Current value of a device, input or eval tag. The status is its quality.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TagReading {
+    pub link_id: usize,
+    pub tag_id: usize,
+    pub tk: String,
+    pub name: String,
+    pub unit: String,
+    pub enabled: bool,
+    pub value: TagValue,
+    pub status: TagStatus,
+    pub changed_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Clone, Debug, Serialize, PartialEq, Deserialize)]
 pub enum Link {
     Device(DeviceLink),
@@ -32,6 +84,442 @@ impl Default for LinkStatus {
     }
 }
 
-// TODO
-// define the link interfaces here.
-impl Link {}
+impl AbstractTag {
+    pub fn id(&self) -> usize {
+        match self {
+            AbstractTag::DeviceTag(tag) => tag.id,
+            AbstractTag::InputTag(tag) => tag.id,
+            AbstractTag::EvalTag(tag) => tag.id,
+        }
+    }
+
+    pub fn tk(&self) -> &str {
+        match self {
+            AbstractTag::DeviceTag(tag) => &tag.tk,
+            AbstractTag::InputTag(tag) => &tag.tk,
+            AbstractTag::EvalTag(tag) => &tag.tk,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            AbstractTag::DeviceTag(tag) => &tag.name,
+            AbstractTag::InputTag(tag) => &tag.name,
+            AbstractTag::EvalTag(tag) => &tag.name,
+        }
+    }
+
+    pub fn unit(&self) -> &str {
+        match self {
+            AbstractTag::DeviceTag(tag) => &tag.unit,
+            AbstractTag::InputTag(tag) => &tag.unit,
+            AbstractTag::EvalTag(tag) => &tag.unit,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        match self {
+            AbstractTag::DeviceTag(tag) => tag.enabled,
+            AbstractTag::InputTag(tag) => tag.enabled,
+            AbstractTag::EvalTag(tag) => tag.enabled,
+        }
+    }
+
+    pub fn value(&self) -> &TagValue {
+        match self {
+            AbstractTag::DeviceTag(tag) => &tag.value,
+            AbstractTag::InputTag(tag) => &tag.value,
+            AbstractTag::EvalTag(tag) => &tag.value,
+        }
+    }
+}
+
+impl<'a> TagView<'a> {
+    pub fn id(&self) -> usize {
+        match self {
+            TagView::DeviceTag(tag) => tag.id,
+            TagView::InputTag(tag) => tag.id,
+            TagView::EvalTag(tag) => tag.id,
+        }
+    }
+
+    pub fn tk(&self) -> &'a str {
+        match self {
+            TagView::DeviceTag(tag) => &tag.tk,
+            TagView::InputTag(tag) => &tag.tk,
+            TagView::EvalTag(tag) => &tag.tk,
+        }
+    }
+
+    pub fn name(&self) -> &'a str {
+        match self {
+            TagView::DeviceTag(tag) => &tag.name,
+            TagView::InputTag(tag) => &tag.name,
+            TagView::EvalTag(tag) => &tag.name,
+        }
+    }
+
+    pub fn unit(&self) -> &'a str {
+        match self {
+            TagView::DeviceTag(tag) => &tag.unit,
+            TagView::InputTag(tag) => &tag.unit,
+            TagView::EvalTag(tag) => &tag.unit,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        match self {
+            TagView::DeviceTag(tag) => tag.enabled,
+            TagView::InputTag(tag) => tag.enabled,
+            TagView::EvalTag(tag) => tag.enabled,
+        }
+    }
+
+    pub fn value(&self) -> &'a TagValue {
+        match self {
+            TagView::DeviceTag(tag) => &tag.value,
+            TagView::InputTag(tag) => &tag.value,
+            TagView::EvalTag(tag) => &tag.value,
+        }
+    }
+
+    pub fn changed_at(&self) -> Option<DateTime<Utc>> {
+        match self {
+            TagView::DeviceTag(tag) => tag.changed_at,
+            TagView::InputTag(tag) => tag.changed_at,
+            TagView::EvalTag(tag) => tag.changed_at,
+        }
+    }
+
+    pub fn status(&self) -> &'a TagStatus {
+        match self {
+            TagView::DeviceTag(tag) => &tag.status,
+            TagView::InputTag(tag) => &tag.status,
+            TagView::EvalTag(tag) => &tag.status,
+        }
+    }
+
+    pub fn reading(&self, link_id: usize) -> TagReading {
+        TagReading {
+            link_id,
+            tag_id: self.id(),
+            tk: self.tk().to_owned(),
+            name: self.name().to_owned(),
+            unit: self.unit().to_owned(),
+            enabled: self.enabled(),
+            value: self.value().clone(),
+            status: self.status().clone(),
+            changed_at: self.changed_at(),
+        }
+    }
+
+    pub fn to_abstract(&self) -> AbstractTag {
+        match self {
+            TagView::DeviceTag(tag) => AbstractTag::DeviceTag((*tag).clone()),
+            TagView::InputTag(tag) => AbstractTag::InputTag((*tag).clone()),
+            TagView::EvalTag(tag) => AbstractTag::EvalTag((*tag).clone()),
+        }
+    }
+}
+
+impl TagIndex {
+    pub fn build(links: &[Link]) -> Self {
+        let mut index = Self::default();
+        for (link_index, link) in links.iter().enumerate() {
+            let Some(link_id) = link.id() else {
+                continue;
+            };
+            for tag_index in 0..link.tag_count() {
+                if let Some(tag) = link.tag_at(tag_index) {
+                    let tag_ref = TagRef {
+                        link_index,
+                        tag_index,
+                    };
+                    index.by_id.insert((link_id, tag.id()), tag_ref);
+                    index.by_tk.insert(tag.tk().to_owned(), tag_ref);
+                }
+            }
+        }
+        index
+    }
+
+    pub fn by_id(&self, link_id: usize, tag_id: usize) -> Option<TagRef> {
+        self.by_id.get(&(link_id, tag_id)).copied()
+    }
+
+    pub fn by_tk(&self, tk: &str) -> Option<TagRef> {
+        self.by_tk.get(tk).copied()
+    }
+
+    pub fn by_key(&self, key: &TagKey) -> Option<TagRef> {
+        match key {
+            TagKey::Tk(tk) => self.by_tk(tk),
+            TagKey::Ids { link_id, tag_id } => self.by_id(*link_id, *tag_id),
+        }
+    }
+}
+
+impl TagRef {
+    pub fn get<'a>(&self, links: &'a [Link]) -> Option<TagView<'a>> {
+        links.get(self.link_index)?.tag_at(self.tag_index)
+    }
+
+    // (link_id, tag_id) of the referenced tag.
+    pub fn ids(&self, links: &[Link]) -> Option<(usize, usize)> {
+        let link_id = links.get(self.link_index)?.id()?;
+        Some((link_id, self.get(links)?.id()))
+    }
+}
+
+impl Link {
+    // Links that don't hold tags (MbServer) have no id.
+    pub fn id(&self) -> Option<usize> {
+        match self {
+            Link::Device(link) => Some(link.id),
+            Link::Eval(link) => Some(link.id),
+            Link::Inputs(link) => Some(link.id),
+            Link::Logger(link) => Some(link.id),
+            Link::MbServer => None,
+        }
+    }
+
+    pub fn tag_count(&self) -> usize {
+        match self {
+            Link::Device(link) => link.tags.len(),
+            Link::Inputs(link) => link.tags.len(),
+            Link::Eval(link) => link.tags.len(),
+            _ => 0,
+        }
+    }
+
+    // Keep only the tags whose tk passes `keep`.
+    pub fn retain_tags(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        match self {
+            Link::Device(link) => link.tags.retain(|tag| keep(&tag.tk)),
+            Link::Inputs(link) => link.tags.retain(|tag| keep(&tag.tk)),
+            Link::Eval(link) => link.tags.retain(|tag| keep(&tag.tk)),
+            _ => {}
+        }
+    }
+
+    // Tag at the given position in the link's tag list.
+    pub fn tag_at(&self, tag_index: usize) -> Option<TagView<'_>> {
+        match self {
+            Link::Device(link) => link.tags.get(tag_index).map(TagView::DeviceTag),
+            Link::Inputs(link) => link.tags.get(tag_index).map(TagView::InputTag),
+            Link::Eval(link) => link.tags.get(tag_index).map(TagView::EvalTag),
+            _ => None,
+        }
+    }
+
+    pub fn tag_status_mut(&mut self, tag_index: usize) -> Option<&mut TagStatus> {
+        match self {
+            Link::Device(link) => link.tags.get_mut(tag_index).map(|tag| &mut tag.status),
+            Link::Inputs(link) => link.tags.get_mut(tag_index).map(|tag| &mut tag.status),
+            Link::Eval(link) => link.tags.get_mut(tag_index).map(|tag| &mut tag.status),
+            _ => None,
+        }
+    }
+
+    pub fn tag(&self, tag_id: usize) -> Option<TagView<'_>> {
+        (0..self.tag_count())
+            .filter_map(|tag_index| self.tag_at(tag_index))
+            .find(|tag| tag.id() == tag_id)
+    }
+
+    /*
+     * Whether write_tag() can take this value for the tag at tag_index. Device
+     * tags need the poll to pick up the write, so they must be enabled.
+     */
+    pub fn check_write(&self, tag_index: usize, value: &TagValue) -> Result<(), String> {
+        match self {
+            Link::Device(link) => {
+                let tag = link.tags.get(tag_index).ok_or("Unknown tag.")?;
+                if !tag.enabled {
+                    return Err("Tag is disabled.".to_owned());
+                }
+                if !value.same_type(&tag.value) {
+                    return Err(format!("Expected a value like {:?}.", tag.value));
+                }
+                match (&tag.address, value) {
+                    (_, TagValue::Bit(_)) => Err("Bit tags can't be written yet.".to_owned()),
+                    (TagAddress::ModbusAddr(ModbusRegister::Holding(_)), _) => Ok(()),
+                    _ => Err("Only Modbus holding register tags can be written.".to_owned()),
+                }
+            }
+            Link::Inputs(link) if tag_index < link.tags.len() => Ok(()),
+            Link::Eval(_) => Err("Eval tags are calculated and can't be written.".to_owned()),
+            _ => Err("Unknown tag.".to_owned()),
+        }
+    }
+
+    // Returns a copy of the tag with the given id, if this link holds tags.
+    pub fn get_tag(&self, tag_id: usize) -> Option<AbstractTag> {
+        match self {
+            Link::Device(link) => link
+                .tags
+                .iter()
+                .find(|tag| tag.id == tag_id)
+                .map(|tag| AbstractTag::DeviceTag(tag.clone())),
+            Link::Inputs(link) => link
+                .tags
+                .iter()
+                .find(|tag| tag.id == tag_id)
+                .map(|tag| AbstractTag::InputTag(tag.clone())),
+            Link::Eval(link) => link
+                .tags
+                .iter()
+                .find(|tag| tag.id == tag_id)
+                .map(|tag| AbstractTag::EvalTag(tag.clone())),
+            _ => None,
+        }
+    }
+
+    // Returns the id of the tag with the given tk, if this link holds it.
+    pub fn find_tag_id(&self, tk: &str) -> Option<usize> {
+        match self {
+            Link::Device(link) => link.tags.iter().find(|tag| tag.tk == tk).map(|tag| tag.id),
+            Link::Inputs(link) => link.tags.iter().find(|tag| tag.tk == tk).map(|tag| tag.id),
+            Link::Eval(link) => link.tags.iter().find(|tag| tag.tk == tk).map(|tag| tag.id),
+            _ => None,
+        }
+    }
+}
+
+// Look up a tag by its link and tag ids.
+pub fn find_tag(links: &[Link], link_id: usize, tag_id: usize) -> Option<AbstractTag> {
+    links
+        .iter()
+        .filter(|link| link.id() == Some(link_id))
+        .find_map(|link| link.get_tag(tag_id))
+}
+
+// Look up a device, input or eval tag by tk or ids, without building an index.
+pub fn read_tag(links: &[Link], key: &TagKey) -> Option<TagReading> {
+    let (link_id, tag_id) = match key {
+        TagKey::Tk(tk) => locate_tk(links, tk)?,
+        TagKey::Ids { link_id, tag_id } => (*link_id, *tag_id),
+    };
+    links
+        .iter()
+        .filter(|link| link.id() == Some(link_id))
+        .find_map(|link| link.tag(tag_id))
+        .map(|tag| tag.reading(link_id))
+}
+
+// Resolve a tag key to its (link_id, tag_id) pair.
+pub fn locate_tk(links: &[Link], tk: &str) -> Option<(usize, usize)> {
+    links.iter().find_map(|link| {
+        let tag_id = link.find_tag_id(tk)?;
+        Some((link.id()?, tag_id))
+    })
+}
+
+// Warn and Alarm are set by the alarm engine, a Normal poll or evaluation keeps them.
+pub fn keep_alarm_status(new: &mut TagStatus, current: &TagStatus) {
+    if *new == TagStatus::Normal && matches!(current, TagStatus::Warn | TagStatus::Alarm) {
+        *new = current.clone();
+    }
+}
+
+/*
+ * Write a value to a device or input tag. Device tags get a pending write
+ * that the link task sends on its next poll. Returns the previous value, or
+ * None when there is no writable tag with these ids.
+ */
+pub fn write_tag(
+    links: &mut [Link],
+    link_id: usize,
+    tag_id: usize,
+    value: TagValue,
+) -> Option<TagValue> {
+    for link in links.iter_mut() {
+        match link {
+            Link::Device(link) if link.id == link_id => {
+                let tag = link.tags.iter_mut().find(|tag| tag.id == tag_id)?;
+                info!("Found tag to write. {:?}", tag.pending_write);
+                tag.pending_write = Some(value);
+                link.status = LinkStatus::PendingTagReconfig;
+                return Some(tag.value.clone());
+            }
+            Link::Inputs(link) if link.id == link_id => {
+                let tag = link.tags.iter_mut().find(|tag| tag.id == tag_id)?;
+                info!("Found tag to write. Value: {:?}", &value);
+                if tag.value != value {
+                    tag.changed_at = Some(chrono::Utc::now());
+                }
+                return Some(std::mem::replace(&mut tag.value, value));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_links;
+
+    #[test]
+    fn only_writable_tags_pass_the_write_check() {
+        let links = test_links();
+        let device = &links[0];
+        assert_eq!(device.check_write(0, &TagValue::Real(1.5)), Ok(()));
+        assert_eq!(device.check_write(1, &TagValue::Int(2)), Ok(()));
+        // Wrong type, coil, disabled tag and unknown index.
+        assert!(device.check_write(0, &TagValue::Int(1)).is_err());
+        assert!(device.check_write(2, &TagValue::Bit(true)).is_err());
+        assert!(device.check_write(3, &TagValue::Real(1.0)).is_err());
+        assert!(device.check_write(9, &TagValue::Real(1.0)).is_err());
+        assert_eq!(links[1].check_write(0, &TagValue::Real(1.0)), Ok(()));
+        assert!(links[2].check_write(0, &TagValue::Real(1.0)).is_err());
+    }
+
+    #[test]
+    fn input_registers_are_read_only() {
+        let mut links = test_links();
+        let Link::Device(device) = &mut links[0] else {
+            unreachable!()
+        };
+        device.tags[0].address = TagAddress::ModbusAddr(ModbusRegister::Input(0));
+        assert!(links[0].check_write(0, &TagValue::Real(1.5)).is_err());
+    }
+
+    #[test]
+    fn device_writes_are_queued_and_input_writes_applied() {
+        let mut links = test_links();
+        assert_eq!(
+            write_tag(&mut links, 0, 0, TagValue::Real(4.0)),
+            Some(TagValue::Real(0.0))
+        );
+        assert_eq!(
+            write_tag(&mut links, 1, 1, TagValue::Real(2.0)),
+            Some(TagValue::Real(0.0))
+        );
+        assert_eq!(write_tag(&mut links, 2, 0, TagValue::Real(1.0)), None);
+
+        let Link::Device(device) = &links[0] else {
+            unreachable!()
+        };
+        assert_eq!(device.tags[0].pending_write, Some(TagValue::Real(4.0)));
+        assert_eq!(device.tags[0].value, TagValue::Real(0.0));
+        let Link::Inputs(inputs) = &links[1] else {
+            unreachable!()
+        };
+        assert_eq!(inputs.tags[1].value, TagValue::Real(2.0));
+        assert!(inputs.tags[1].changed_at.is_some());
+    }
+
+    #[test]
+    fn tags_are_indexed_by_ids_and_tk() {
+        let links = test_links();
+        let index = TagIndex::build(&links);
+        let tag_ref = index.by_tk("IN:1").unwrap();
+        assert_eq!((tag_ref.link_index, tag_ref.tag_index), (1, 1));
+        assert_eq!(index.by_id(1, 1), Some(tag_ref));
+        assert_eq!(tag_ref.ids(&links), Some((1, 1)));
+        assert_eq!(locate_tk(&links, "EVAL2:000"), Some((2, 0)));
+        assert!(index.by_tk("LK9:000").is_none());
+    }
+}
@@ -1,20 +1,144 @@
 use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::info;
+use prost::Message;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
 
-use crate::AbstractTag;
+use crate::HistoryPoint;
+use crate::HistorySeries;
 use crate::Link;
 use crate::LinkStatus;
+use crate::PostgresTls;
+use crate::TagIndex;
+
+// Table the logged values live in. Every row carries the tag key
+// as `tk` and the numeric value as `value`.
+pub const LOG_MEASUREMENT: &str = "sentinel_tags";
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct InfluxDbInfo {
     pub url: String,
     pub token: String,
+    #[serde(default)]
+    pub database: String,
+}
+
+// Row returned by the InfluxDB SQL endpoint.
+#[derive(Deserialize)]
+struct InfluxRow {
+    time: String,
+    tk: String,
+    value: f64,
+}
+
+// PostgreSQL, optionally with the TimescaleDB extension.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PostgresInfo {
+    // libpq style connection string, e.g. "host=localhost user=sentinel".
+    pub connection: String,
+    // Defaults to LOG_MEASUREMENT. Created on first write.
+    #[serde(default)]
+    pub table: String,
+    // Require TLS, whatever the sslmode of the connection string.
+    #[serde(default)]
+    pub tls: bool,
+    // PEM file with the CA certificates to trust instead of the webpki roots.
+    #[serde(default)]
+    pub ca_file: String,
+}
+
+// Prometheus compatible store (Prometheus, VictoriaMetrics, Mimir).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RemoteWriteInfo {
+    // Remote-write endpoint, e.g. "http://localhost:8428/api/v1/write".
+    pub url: String,
+    #[serde(default)]
+    pub token: String,
+    // Base URL of the Prometheus HTTP API used for history queries.
+    #[serde(default)]
+    pub query_url: String,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum DataBase {
     InfluxDb(InfluxDbInfo),
+    Postgres(PostgresInfo),
+    PrometheusRemoteWrite(RemoteWriteInfo),
+}
+
+// Remote-write protobuf messages (prometheus/prompb/remote.proto).
+#[derive(Clone, PartialEq, prost::Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+// Response of the Prometheus query_range endpoint.
+#[derive(Deserialize)]
+struct PromResponse {
+    data: PromData,
+}
+
+#[derive(Deserialize)]
+struct PromData {
+    result: Vec<PromSeries>,
+}
+
+#[derive(Deserialize)]
+struct PromSeries {
+    values: Vec<(f64, String)>,
+}
+
+// Connections a logger link keeps between logging cycles and history queries.
+#[derive(Debug, Default)]
+pub struct LoggerSession {
+    http: reqwest::Client,
+    // Kept with the settings it was opened with, a reconfig reconnects.
+    postgres: Option<(PostgresInfo, tokio_postgres::Client)>,
+}
+
+// Sessions of the logger links by id, shared by the logging tasks and the history API.
+#[derive(Debug, Default)]
+pub struct LoggerSessions {
+    sessions: std::sync::Mutex<HashMap<usize, Arc<tokio::sync::Mutex<LoggerSession>>>>,
+}
+
+impl LoggerSessions {
+    pub fn get(&self, logger_id: usize) -> Arc<tokio::sync::Mutex<LoggerSession>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .entry(logger_id)
+            .or_default()
+            .clone()
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -23,6 +147,13 @@ pub struct LogTagInfo {
     pub tag_id: usize,
 }
 
+// Value of a logged tag at the time of a logging cycle.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogSample {
+    pub tk: String,
+    pub value: f64,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct LoggerLink {
     pub name: String,
@@ -33,6 +164,362 @@ pub struct LoggerLink {
     pub log_delay_millis: usize,
 }
 
+impl DataBase {
+    // Write one logging cycle to the database.
+    pub async fn write_samples(
+        &self,
+        session: &mut LoggerSession,
+        samples: &[LogSample],
+        time: DateTime<Utc>,
+    ) -> Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        match self {
+            DataBase::InfluxDb(info) => {
+                // Line protocol, one line per tag.
+                let timestamp = time.timestamp_millis();
+                let lines = samples
+                    .iter()
+                    .filter(|sample| sample.value.is_finite())
+                    .map(|sample| {
+                        format!(
+                            "{LOG_MEASUREMENT},tk={} value={} {timestamp}",
+                            escape_line_protocol(&sample.tk),
+                            sample.value
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                session
+                    .http
+                    .post(format!(
+                        "{}/api/v3/write_lp",
+                        info.url.trim_end_matches('/')
+                    ))
+                    .query(&[("db", info.database.as_str()), ("precision", "millisecond")])
+                    .bearer_auth(&info.token)
+                    .body(lines)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(())
+            }
+            DataBase::Postgres(info) => {
+                let statement = info.insert_sql()?;
+                let client = session.postgres_client(info).await?;
+                // One statement per cycle: the tag keys and values are sent as arrays.
+                let tks: Vec<&str> = samples.iter().map(|sample| sample.tk.as_str()).collect();
+                let values: Vec<f64> = samples.iter().map(|sample| sample.value).collect();
+                if let Err(e) = client.execute(&statement, &[&time, &tks, &values]).await {
+                    // Drop the connection so the next cycle reconnects.
+                    session.postgres = None;
+                    return Err(e.into());
+                }
+                Ok(())
+            }
+            DataBase::PrometheusRemoteWrite(info) => {
+                let body = remote_write_body(samples, time)?;
+                let mut builder = session
+                    .http
+                    .post(&info.url)
+                    .header("Content-Encoding", "snappy")
+                    .header("Content-Type", "application/x-protobuf")
+                    .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                    .body(body);
+                if !info.token.is_empty() {
+                    builder = builder.bearer_auth(&info.token);
+                }
+                builder.send().await?.error_for_status()?;
+                Ok(())
+            }
+        }
+    }
+
+    // Read back the raw values of the given tags, sorted by time.
+    pub async fn query_history(
+        &self,
+        session: &mut LoggerSession,
+        tks: &[String],
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<HistorySeries>> {
+        match self {
+            DataBase::InfluxDb(info) => {
+                let tk_list = tks
+                    .iter()
+                    .map(|tk| format!("'{}'", tk.replace('\'', "''")))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                let sql = format!(
+                    "SELECT time, tk, value FROM {LOG_MEASUREMENT} \
+                     WHERE tk IN ({tk_list}) AND time >= '{}' AND time < '{}' \
+                     ORDER BY time",
+                    start.to_rfc3339(),
+                    end.to_rfc3339()
+                );
+                let body = serde_json::json!({
+                    "db": info.database,
+                    "q": sql,
+                    "format": "json",
+                });
+                let rows: Vec<InfluxRow> = session
+                    .http
+                    .post(format!(
+                        "{}/api/v3/query_sql",
+                        info.url.trim_end_matches('/')
+                    ))
+                    .bearer_auth(&info.token)
+                    .json(&body)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+
+                let mut series: Vec<HistorySeries> = tks
+                    .iter()
+                    .map(|tk| HistorySeries {
+                        tk: tk.clone(),
+                        points: Vec::new(),
+                    })
+                    .collect();
+                for row in rows {
+                    let time = parse_influx_time(&row.time)?;
+                    if let Some(s) = series.iter_mut().find(|s| s.tk == row.tk) {
+                        s.points.push(HistoryPoint {
+                            time,
+                            value: row.value,
+                        });
+                    }
+                }
+                Ok(series)
+            }
+            DataBase::Postgres(info) => {
+                let table = info.table_name()?;
+                let client = session.postgres_client(info).await?;
+                let rows = client
+                    .query(
+                        &format!(
+                            "SELECT time, tk, value FROM {table} \
+                             WHERE tk = ANY($1) AND time >= $2 AND time < $3 \
+                             ORDER BY time"
+                        ),
+                        &[&tks, &start, &end],
+                    )
+                    .await;
+                let rows = match rows {
+                    Ok(rows) => rows,
+                    Err(e) => {
+                        session.postgres = None;
+                        return Err(e.into());
+                    }
+                };
+
+                let mut series: Vec<HistorySeries> = tks
+                    .iter()
+                    .map(|tk| HistorySeries {
+                        tk: tk.clone(),
+                        points: Vec::new(),
+                    })
+                    .collect();
+                for row in rows {
+                    let tk: String = row.try_get(1)?;
+                    if let Some(s) = series.iter_mut().find(|s| s.tk == tk) {
+                        s.points.push(HistoryPoint {
+                            time: row.try_get(0)?,
+                            value: row.try_get(2)?,
+                        });
+                    }
+                }
+                Ok(series)
+            }
+            DataBase::PrometheusRemoteWrite(info) => {
+                if info.query_url.is_empty() {
+                    anyhow::bail!("No query URL configured for the remote-write logger.");
+                }
+                // Remote-write stores have no raw export in the standard API,
+                // so sample at the finest step the query limits allow.
+                let range = (end - start).num_seconds().max(1);
+                let step = (range / 10_000).max(1);
+                let client = &session.http;
+                let mut series = Vec::with_capacity(tks.len());
+                for tk in tks {
+                    let query = format!(
+                        "{LOG_MEASUREMENT}{{tk=\"{}\"}}",
+                        tk.replace('\\', "\\\\").replace('"', "\\\"")
+                    );
+                    let mut builder = client
+                        .get(format!(
+                            "{}/api/v1/query_range",
+                            info.query_url.trim_end_matches('/')
+                        ))
+                        .query(&[
+                            ("query", query),
+                            ("start", start.timestamp().to_string()),
+                            ("end", end.timestamp().to_string()),
+                            ("step", step.to_string()),
+                        ]);
+                    if !info.token.is_empty() {
+                        builder = builder.bearer_auth(&info.token);
+                    }
+                    let response: PromResponse =
+                        builder.send().await?.error_for_status()?.json().await?;
+
+                    let mut points = Vec::new();
+                    for result in response.data.result {
+                        for (timestamp, value) in result.values {
+                            let Some(time) =
+                                DateTime::from_timestamp_millis((timestamp * 1000.0) as i64)
+                            else {
+                                continue;
+                            };
+                            points.push(HistoryPoint {
+                                time,
+                                value: value.parse()?,
+                            });
+                        }
+                    }
+                    // Samples at `end` are outside the requested range.
+                    points.retain(|point| point.time < end);
+                    series.push(HistorySeries {
+                        tk: tk.clone(),
+                        points,
+                    });
+                }
+                Ok(series)
+            }
+        }
+    }
+}
+
+impl PostgresInfo {
+    // The table name is interpolated into SQL, so only plain identifiers are allowed.
+    fn table_name(&self) -> Result<&str> {
+        let table = if self.table.is_empty() {
+            LOG_MEASUREMENT
+        } else {
+            self.table.as_str()
+        };
+        if table.is_empty() || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            anyhow::bail!("Invalid table name: {table}");
+        }
+        Ok(table)
+    }
+
+    fn schema_sql(&self) -> Result<String> {
+        let table = self.table_name()?;
+        Ok(format!(
+            "CREATE TABLE IF NOT EXISTS {table} (\
+                 time TIMESTAMPTZ NOT NULL, \
+                 tk TEXT NOT NULL, \
+                 value DOUBLE PRECISION); \
+             CREATE INDEX IF NOT EXISTS {table}_tk_time ON {table} (tk, time DESC);"
+        ))
+    }
+
+    // Only works with the TimescaleDB extension; plain PostgreSQL keeps a regular table.
+    fn hypertable_sql(&self) -> Result<String> {
+        let table = self.table_name()?;
+        Ok(format!(
+            "SELECT create_hypertable('{table}', 'time', if_not_exists => TRUE);"
+        ))
+    }
+
+    async fn connect(&self) -> Result<tokio_postgres::Client> {
+        let mut config: tokio_postgres::Config = self.connection.parse()?;
+        if !self.tls {
+            let (client, connection) = config.connect(tokio_postgres::NoTls).await?;
+            tokio::spawn(connection);
+            return Ok(client);
+        }
+        config.ssl_mode(tokio_postgres::config::SslMode::Require);
+        let (client, connection) = config.connect(PostgresTls::new(&self.ca_file)?).await?;
+        tokio::spawn(connection);
+        Ok(client)
+    }
+
+    // Takes the time, the tag keys and the values as $1, $2 and $3.
+    fn insert_sql(&self) -> Result<String> {
+        let table = self.table_name()?;
+        Ok(format!(
+            "INSERT INTO {table} (time, tk, value) \
+             SELECT $1::timestamptz, * FROM UNNEST($2::text[], $3::float8[])"
+        ))
+    }
+}
+
+impl LoggerSession {
+    /*
+     * Connect on first use, after an error or when the settings changed, and
+     * create the schema if it doesn't exist.
+     */
+    async fn postgres_client(&mut self, info: &PostgresInfo) -> Result<&tokio_postgres::Client> {
+        if self
+            .postgres
+            .as_ref()
+            .is_none_or(|(connected, client)| connected != info || client.is_closed())
+        {
+            self.postgres = None;
+            let table = info.table_name()?;
+            let client = info.connect().await?;
+            client.batch_execute(&info.schema_sql()?).await?;
+            if let Err(e) = client.batch_execute(&info.hypertable_sql()?).await {
+                info!("Table {table} is not a hypertable: {e}");
+            }
+            self.postgres = Some((info.clone(), client));
+        }
+        match &self.postgres {
+            Some((_, client)) => Ok(client),
+            None => anyhow::bail!("Not connected."),
+        }
+    }
+}
+
+// Snappy compressed WriteRequest with one series per tag.
+fn remote_write_body(samples: &[LogSample], time: DateTime<Utc>) -> Result<Vec<u8>> {
+    let timestamp = time.timestamp_millis();
+    let request = WriteRequest {
+        timeseries: samples
+            .iter()
+            .map(|sample| TimeSeries {
+                // Labels must be sorted by name.
+                labels: vec![
+                    Label {
+                        name: "__name__".to_owned(),
+                        value: LOG_MEASUREMENT.to_owned(),
+                    },
+                    Label {
+                        name: "tk".to_owned(),
+                        value: sample.tk.clone(),
+                    },
+                ],
+                samples: vec![Sample {
+                    value: sample.value,
+                    timestamp,
+                }],
+            })
+            .collect(),
+    };
+    Ok(snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?)
+}
+
+fn escape_line_protocol(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+// InfluxDB returns timestamps without an offset; they are always UTC.
+fn parse_influx_time(time: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f")?;
+    Ok(time.and_utc())
+}
+
 impl LoggerLink {
     pub fn new(name: String, id: usize, num_tags: usize) -> Self {
         let mut tags: Vec<LogTagInfo> = Vec::new();
@@ -47,6 +534,7 @@ impl LoggerLink {
         let influx_info = InfluxDbInfo {
             url: String::new(),
             token: String::new(),
+            database: String::new(),
         };
 
         Self {
@@ -58,43 +546,343 @@ impl LoggerLink {
             log_delay_millis: 1000,
         }
     }
-    pub fn log(&self, links: &Vec<Link>) {
-        let mut tags_to_log: Vec<AbstractTag> = Vec::new();
-        // Logged tags lookup.
+    // Whether this logger records the given tag.
+    pub fn logs_tag(&self, link_id: usize, tag_id: usize) -> bool {
+        self.tags
+            .iter()
+            .any(|tag| tag.link_id == link_id && tag.tag_id == tag_id)
+    }
+
+    /*
+     * Read the current values of the logged tags through the cached index.
+     * Returns None if a cached position no longer points at the expected tag,
+     * meaning the links were reconfigured and the index has to be rebuilt.
+     * Tags that don't exist at all are skipped.
+     */
+    pub fn collect(&self, links: &[Link], index: &TagIndex) -> Option<Vec<LogSample>> {
+        let mut samples = Vec::with_capacity(self.tags.len());
         for tag in &self.tags {
-            for link in links {
-                match link {
-                    Link::Device(link) => {
-                        if tag.link_id == link.id {
-                            for link_tag in &link.tags {
-                                if link_tag.id == tag.tag_id {
-                                    tags_to_log.push(AbstractTag::DeviceTag(link_tag.clone()));
-                                }
-                            }
-                        }
-                    }
-                    Link::Eval(link) => {
-                        if tag.link_id == link.id {
-                            for link_tag in &link.tags {
-                                if link_tag.id == tag.tag_id {
-                                    tags_to_log.push(AbstractTag::EvalTag(link_tag.clone()));
-                                }
-                            }
-                        }
-                    }
-                    Link::Inputs(link) => {
-                        if tag.link_id == link.id {
-                            for link_tag in &link.tags {
-                                if link_tag.id == tag.tag_id {
-                                    tags_to_log.push(AbstractTag::InputTag(link_tag.clone()));
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
-                }
+            let Some(tag_ref) = index.by_id(tag.link_id, tag.tag_id) else {
+                continue;
+            };
+            let view = tag_ref.get(links)?;
+            if links[tag_ref.link_index].id() != Some(tag.link_id) || view.id() != tag.tag_id {
+                return None;
+            }
+            if view.enabled() {
+                samples.push(LogSample {
+                    tk: view.tk().to_owned(),
+                    value: view.value().as_f64(),
+                });
             }
         }
-        
+        Some(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TagValue;
+    use crate::test_support::{http_stand_in, test_links};
+    use chrono::TimeZone;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn postgres(table: &str) -> PostgresInfo {
+        PostgresInfo {
+            connection: "host=localhost".to_owned(),
+            table: table.to_owned(),
+            tls: false,
+            ca_file: String::new(),
+        }
+    }
+
+    fn samples() -> Vec<LogSample> {
+        vec![
+            LogSample {
+                tk: "LK0:000".to_owned(),
+                value: 1.5,
+            },
+            LogSample {
+                tk: "IN:1".to_owned(),
+                value: -2.0,
+            },
+        ]
+    }
+
+    fn logger(tags: &[(usize, usize)]) -> LoggerLink {
+        LoggerLink {
+            name: "Log".to_owned(),
+            id: 3,
+            database: DataBase::PrometheusRemoteWrite(RemoteWriteInfo {
+                url: String::new(),
+                token: String::new(),
+                query_url: String::new(),
+            }),
+            status: LinkStatus::Normal,
+            tags: tags
+                .iter()
+                .map(|&(link_id, tag_id)| LogTagInfo { link_id, tag_id })
+                .collect(),
+            log_delay_millis: 1000,
+        }
+    }
+
+    fn decode_remote_write(body: &[u8]) -> WriteRequest {
+        let raw = snap::raw::Decoder::new().decompress_vec(body).unwrap();
+        WriteRequest::decode(raw.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn collect_reads_the_enabled_tags() {
+        let mut links = test_links();
+        let Link::Inputs(inputs) = &mut links[1] else {
+            unreachable!()
+        };
+        inputs.tags[1].value = TagValue::Real(4.5);
+        let index = TagIndex::build(&links);
+        // LK0:003 is disabled and link 7 doesn't exist.
+        let logger = logger(&[(1, 1), (0, 3), (7, 0), (0, 1)]);
+
+        let samples = logger.collect(&links, &index).unwrap();
+        assert_eq!(
+            samples,
+            vec![
+                LogSample {
+                    tk: "IN:1".to_owned(),
+                    value: 4.5,
+                },
+                LogSample {
+                    tk: "LK0:001".to_owned(),
+                    value: 0.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn collect_notices_a_stale_index() {
+        let mut links = test_links();
+        let index = TagIndex::build(&links);
+        links.swap(0, 1);
+
+        let logger = logger(&[(0, 0)]);
+        assert_eq!(logger.collect(&links, &index), None);
+        let samples = logger.collect(&links, &TagIndex::build(&links)).unwrap();
+        assert_eq!(samples[0].tk, "LK0:000");
+    }
+
+    #[test]
+    fn postgres_table_defaults_to_the_measurement() {
+        let info = postgres("");
+        assert_eq!(info.table_name().unwrap(), LOG_MEASUREMENT);
+        let schema = info.schema_sql().unwrap();
+        assert!(schema.starts_with("CREATE TABLE IF NOT EXISTS sentinel_tags ("));
+        assert!(schema.contains("time TIMESTAMPTZ NOT NULL"));
+        assert!(schema.contains("tk TEXT NOT NULL"));
+        assert!(schema.contains("value DOUBLE PRECISION"));
+        assert!(schema.contains(
+            "CREATE INDEX IF NOT EXISTS sentinel_tags_tk_time ON sentinel_tags (tk, time DESC);"
+        ));
+        assert_eq!(
+            info.hypertable_sql().unwrap(),
+            "SELECT create_hypertable('sentinel_tags', 'time', if_not_exists => TRUE);"
+        );
+    }
+
+    #[test]
+    fn postgres_batch_insert_is_one_statement() {
+        let sql = postgres("plant_log").insert_sql().unwrap();
+        assert_eq!(
+            sql,
+            "INSERT INTO plant_log (time, tk, value) \
+             SELECT $1::timestamptz, * FROM UNNEST($2::text[], $3::float8[])"
+        );
+    }
+
+    #[test]
+    fn postgres_rejects_table_names_that_are_not_identifiers() {
+        for table in ["log; DROP TABLE users", "log-1", "\"log\"", "log table"] {
+            let info = postgres(table);
+            assert!(info.table_name().is_err(), "{table}");
+            assert!(info.schema_sql().is_err(), "{table}");
+            assert!(info.insert_sql().is_err(), "{table}");
+        }
+    }
+
+    /*
+     * Accept one connection, answer an SSLRequest with "no TLS" and return
+     * the protocol code of the first message the client sent.
+     */
+    async fn postgres_first_message(info: &mut PostgresInfo) -> (u32, Result<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        info.connection = format!(
+            "host=127.0.0.1 port={} user=sentinel connect_timeout=5",
+            listener.local_addr().unwrap().port()
+        );
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = [0u8; 8];
+            stream.read_exact(&mut head).await.unwrap();
+            let code = u32::from_be_bytes(head[4..].try_into().unwrap());
+            if code == 80877103 {
+                stream.write_all(b"N").await.unwrap();
+            }
+            code
+        });
+        let result = info.connect().await.map(|_| ());
+        (server.await.unwrap(), result)
+    }
+
+    #[tokio::test]
+    async fn postgres_tls_is_required_when_enabled() {
+        let mut info = postgres("");
+        let (code, result) = postgres_first_message(&mut info).await;
+        // Protocol 3.0 startup message, no SSLRequest.
+        assert_eq!(code, 196608);
+        assert!(result.is_err());
+
+        info.tls = true;
+        let (code, result) = postgres_first_message(&mut info).await;
+        assert_eq!(code, 80877103);
+        // The server refused TLS, so there is no plain text fallback.
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn postgres_tls_reads_the_ca_file() {
+        assert!(PostgresTls::new("").is_ok());
+        assert!(PostgresTls::new("/nonexistent/ca.pem").is_err());
+        let path = std::env::temp_dir().join(format!("sentinel-ca-{}.pem", std::process::id()));
+        std::fs::write(&path, "not a certificate").unwrap();
+        assert!(PostgresTls::new(&path.to_string_lossy()).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn logger_sessions_are_shared_by_id() {
+        let sessions = LoggerSessions::default();
+        assert!(Arc::ptr_eq(&sessions.get(3), &sessions.get(3)));
+        assert!(!Arc::ptr_eq(&sessions.get(3), &sessions.get(4)));
+    }
+
+    #[test]
+    fn remote_write_body_is_snappy_protobuf() {
+        let time = Utc.with_ymd_and_hms(2026, 1, 2, 3, 4, 5).unwrap();
+        let request = decode_remote_write(&remote_write_body(&samples(), time).unwrap());
+
+        assert_eq!(request.timeseries.len(), 2);
+        let series = &request.timeseries[0];
+        assert_eq!(
+            series.labels,
+            vec![
+                Label {
+                    name: "__name__".to_owned(),
+                    value: LOG_MEASUREMENT.to_owned(),
+                },
+                Label {
+                    name: "tk".to_owned(),
+                    value: "LK0:000".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            series.samples,
+            vec![Sample {
+                value: 1.5,
+                timestamp: time.timestamp_millis(),
+            }]
+        );
+        assert_eq!(request.timeseries[1].labels[1].value, "IN:1");
+        assert_eq!(request.timeseries[1].samples[0].value, -2.0);
+    }
+
+    #[tokio::test]
+    async fn remote_write_posts_to_the_endpoint() {
+        let (url, mut requests) = http_stand_in(204, "").await;
+        let database = DataBase::PrometheusRemoteWrite(RemoteWriteInfo {
+            url: format!("{url}/api/v1/write"),
+            token: "secret".to_owned(),
+            query_url: String::new(),
+        });
+        let time = Utc::now();
+        database
+            .write_samples(&mut LoggerSession::default(), &samples(), time)
+            .await
+            .unwrap();
+
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/v1/write");
+        assert_eq!(request.headers["content-encoding"], "snappy");
+        assert_eq!(request.headers["content-type"], "application/x-protobuf");
+        assert_eq!(
+            request.headers["x-prometheus-remote-write-version"],
+            "0.1.0"
+        );
+        assert_eq!(request.headers["authorization"], "Bearer secret");
+        let written = decode_remote_write(&request.body);
+        assert_eq!(written.timeseries.len(), 2);
+        assert_eq!(
+            written.timeseries[0].samples[0].timestamp,
+            time.timestamp_millis()
+        );
+    }
+
+    #[tokio::test]
+    async fn remote_write_reports_http_errors() {
+        let (url, _requests) = http_stand_in(500, "{}").await;
+        let database = DataBase::PrometheusRemoteWrite(RemoteWriteInfo {
+            url,
+            token: String::new(),
+            query_url: String::new(),
+        });
+        let result = database
+            .write_samples(&mut LoggerSession::default(), &samples(), Utc::now())
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn remote_write_history_reads_query_range() {
+        let start = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+        let end = start + chrono::Duration::seconds(20);
+        // The last sample sits on `end` and is left out.
+        let body = format!(
+            r#"{{"status":"success","data":{{"resultType":"matrix","result":[
+                {{"metric":{{"tk":"LK0:000"}},"values":[[{0},"1.5"],[{1},"2.5"],[{2},"3.5"]]}}
+            ]}}}}"#,
+            start.timestamp(),
+            start.timestamp() + 10,
+            end.timestamp()
+        );
+        let (url, mut requests) = http_stand_in(200, &body).await;
+        let database = DataBase::PrometheusRemoteWrite(RemoteWriteInfo {
+            url: String::new(),
+            token: String::new(),
+            query_url: url,
+        });
+        let series = database
+            .query_history(
+                &mut LoggerSession::default(),
+                &["LK0:000".to_owned()],
+                start,
+                end,
+            )
+            .await
+            .unwrap();
+
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.method, "GET");
+        assert!(request.path.starts_with("/api/v1/query_range?"));
+        assert!(request.path.contains("step=1"));
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].tk, "LK0:000");
+        let values: Vec<f64> = series[0].points.iter().map(|p| p.value).collect();
+        assert_eq!(values, vec![1.5, 2.5]);
+        assert_eq!(
+            series[0].points[1].time,
+            start + chrono::Duration::seconds(10)
+        );
     }
 }
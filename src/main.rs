@@ -2,7 +2,12 @@ use anyhow::Result;
 use axum::routing::post;
 use axum::{Router, routing::get};
 use sentinel::state::GlobalState;
-use sentinel::{DeviceLink, EvalLink, InputsLink, Link, ModbusTcpConfig, Protocol, Task, api::*};
+use sentinel::{
+    ALARMS_CONFIG_PATH, AUTH_CONFIG_PATH, AuthConfig, DeviceLink, EvalLink, InputsLink, Link,
+    METRICS_CONFIG_PATH, ModbusTcpConfig, NOTIFICATIONS_CONFIG_PATH, Protocol, SCRIPT_MODULES_PATH,
+    Task, api::*, bootstrap_auth_config, require_auth, streaming::*, track_requests,
+};
+use std::net::SocketAddr;
 use tokio::fs;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::EnvFilter;
@@ -60,6 +65,30 @@ async fn main() -> Result<()> {
 
     let state = GlobalState::new(links.clone());
 
+    if let Ok(config_string) = fs::read_to_string(METRICS_CONFIG_PATH).await
+        && let Ok(config) = serde_json::from_str(config_string.as_str())
+    {
+        state.metrics.set_config(config);
+    }
+
+    if let Ok(modules_string) = fs::read_to_string(SCRIPT_MODULES_PATH).await
+        && let Ok(modules) = serde_json::from_str(modules_string.as_str())
+    {
+        state.script_modules.load(modules);
+    }
+
+    if let Ok(config_string) = fs::read_to_string(ALARMS_CONFIG_PATH).await
+        && let Ok(config) = serde_json::from_str(config_string.as_str())
+    {
+        state.alarms.lock().unwrap().set_config(config);
+    }
+
+    if let Ok(config_string) = fs::read_to_string(NOTIFICATIONS_CONFIG_PATH).await
+        && let Ok(config) = serde_json::from_str(config_string.as_str())
+    {
+        *state.notifications.lock().unwrap() = config;
+    }
+
     // Spawn a task for each link.
     for link in links.iter() {
         let state_for_link = state.clone();
@@ -76,9 +105,19 @@ async fn main() -> Result<()> {
                 let task = Task::new(sentinel::TaskType::Eval, state_for_link, link.id);
                 sentinel::task::spawn(task)?;
             }
+            Link::Logger(link) => {
+                let task = Task::new(sentinel::TaskType::Logging, state_for_link, link.id);
+                sentinel::task::spawn(task)?;
+            }
             _ => {}
         };
     }
+    sentinel::task::spawn(Task::new(sentinel::TaskType::Alarms, state.clone(), 0))?;
+    sentinel::task::spawn(Task::new(
+        sentinel::TaskType::Notifications,
+        state.clone(),
+        0,
+    ))?;
     tracing_subscriber::fmt()
         .with_env_filter(
             EnvFilter::try_from_default_env()
@@ -89,10 +128,20 @@ async fn main() -> Result<()> {
         .pretty()
         .init();
 
+    // A newly created admin password goes to stderr, never to the logger.
+    let mut auth_config: AuthConfig = match fs::read_to_string(AUTH_CONFIG_PATH).await {
+        Ok(config_string) => serde_json::from_str(config_string.as_str())?,
+        Err(_) => AuthConfig::default(),
+    };
+    bootstrap_auth_config(&mut auth_config)?;
+    *state.auth.lock().unwrap() = auth_config;
+
     let app = Router::new()
         .route("/api/get_links_config", get(get_links_config))
         .route("/api/get_device_link_config", post(get_device_link_config))
         .route("/api/get_tag_config", post(get_tag_config))
+        .route("/api/tags/read", post(read_tag_value))
+        .route("/api/tags/read_batch", post(read_tag_values))
         .route("/api/reconfigure_device_link", post(reconfig_device_link))
         .route("/api/reconfigure_device_tag", post(reconfig_device_tag))
         .route("/api/reconfigure_eval", post(reconfig_eval))
@@ -101,13 +150,66 @@ async fn main() -> Result<()> {
             post(reconfig_device_protocol),
         )
         .route("/api/write_tag", post(write_link_tag))
+        .route("/api/write_tags", post(write_tags))
         .route("/api/reconfig_links", post(reconfig_links))
+        .route("/api/history", post(get_history))
+        .route("/api/reconfigure_logger", post(reconfig_logger))
+        .route("/api/reconfigure_metrics", post(reconfig_metrics))
+        .route("/api/audit", post(get_audit))
+        .route("/api/eval/graph", get(get_eval_graph))
+        .route("/api/eval/validate", post(validate_eval))
+        .route("/api/eval/test", post(test_eval))
+        .route("/api/script_modules", get(get_script_modules))
+        .route(
+            "/api/reconfigure_script_module",
+            post(reconfig_script_module),
+        )
+        .route("/api/delete_script_module", post(delete_script_module))
+        .route("/api/alarms", get(get_alarms))
+        .route("/api/alarms/ack", post(ack_alarms))
+        .route("/api/alarms/shelve", post(shelve_alarms))
+        .route("/api/alarms/unshelve", post(unshelve_alarms))
+        .route("/api/alarms/shelved", get(get_shelved_alarms))
+        .route("/api/alarms/history", post(get_alarm_history))
+        .route("/api/alarms/maintenance", post(set_link_maintenance))
+        .route("/api/alarms/config", get(get_alarms_config))
+        .route("/api/reconfigure_alarms", post(reconfig_alarms))
+        .route("/api/notifications/config", get(get_notifications_config))
+        .route(
+            "/api/reconfigure_notifications",
+            post(reconfig_notifications),
+        )
+        .route("/api/stream/ws", get(stream_ws))
+        .route("/api/stream/sse", get(stream_sse))
+        .route("/api/stream/clients", get(get_stream_clients))
+        .route("/api/users", get(get_users))
+        .route("/api/users/set", post(set_user))
+        .route("/api/users/delete", post(delete_user))
+        .route("/api/users/permissions", post(set_user_permissions))
+        .route("/api/auth/settings", get(get_auth_settings))
+        .route("/api/reconfigure_auth", post(reconfig_auth))
+        // Everything above needs a valid access token.
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_auth,
+        ))
+        .route("/api/login", post(login))
+        .route("/api/refresh", post(refresh_token))
+        .route("/metrics", get(get_metrics))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            track_requests,
+        ))
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
     println!("Listening on: {}", listener.local_addr()?);
 
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
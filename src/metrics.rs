@@ -0,0 +1,406 @@
+use crate::{GlobalState, Link, LinkStatus, REDACTED_SECRET, TagStatus};
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const METRICS_CONFIG_PATH: &str = "./CurrentConfig/metrics_config.json";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    // Tags exported as gauges on /metrics, by tk.
+    pub tags: Vec<String>,
+    /*
+     * Bearer token the scraper has to send. Tag values are process data, so
+     * they are only exported once a token is set; without one /metrics only
+     * serves the link and API counters.
+     */
+    #[serde(default)]
+    pub token: String,
+}
+
+impl MetricsConfig {
+    // Compares digests, so the time taken doesn't tell how much of the token matched.
+    pub fn allows(&self, authorization: Option<&str>) -> bool {
+        if self.token.is_empty() {
+            return true;
+        }
+        let Some(token) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+            return false;
+        };
+        let digest = |value: &str| ring::digest::digest(&ring::digest::SHA256, value.as_bytes());
+        digest(token).as_ref() == digest(&self.token).as_ref()
+    }
+
+    // A copy without the token, for the audit log.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        if !config.token.is_empty() {
+            config.token = REDACTED_SECRET.to_owned();
+        }
+        config
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct RequestStats {
+    count: u64,
+    duration_sum: f64,
+}
+
+/*
+ * Counters that can't be derived from the links list at scrape time.
+ * Uses std mutexes because they are never held across an await.
+ */
+#[derive(Debug, Default)]
+pub struct Metrics {
+    config: Mutex<MetricsConfig>,
+    link_errors: Mutex<HashMap<usize, u64>>,
+    eval_errors: Mutex<HashMap<usize, u64>>,
+    // Keyed by (matched path, status code).
+    requests: Mutex<HashMap<(String, u16), RequestStats>>,
+}
+
+impl Metrics {
+    pub fn config(&self) -> MetricsConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: MetricsConfig) {
+        *self.config.lock().unwrap() = config;
+    }
+
+    pub fn record_link_error(&self, link_id: usize) {
+        *self.link_errors.lock().unwrap().entry(link_id).or_default() += 1;
+    }
+
+    pub fn record_eval_errors(&self, link_id: usize, count: u64) {
+        if count > 0 {
+            *self.eval_errors.lock().unwrap().entry(link_id).or_default() += count;
+        }
+    }
+
+    pub fn record_request(&self, path: &str, status: u16, duration: Duration) {
+        let mut requests = self.requests.lock().unwrap();
+        let stats = requests.entry((path.to_owned(), status)).or_default();
+        stats.count += 1;
+        stats.duration_sum += duration.as_secs_f64();
+    }
+
+    // Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self, links: &[Link]) -> String {
+        let mut out = String::new();
+        let config = self.config();
+        let exported: HashSet<String> = if config.token.is_empty() {
+            HashSet::new()
+        } else {
+            config.tags.into_iter().collect()
+        };
+
+        header(
+            &mut out,
+            "sentinel_tag_value",
+            "gauge",
+            "Current value of exported tags.",
+        );
+        for link in links {
+            let Some(link_tk) = link_tk(link) else {
+                continue;
+            };
+            for tag_index in 0..link.tag_count() {
+                let Some(tag) = link.tag_at(tag_index) else {
+                    continue;
+                };
+                if !exported.contains(tag.tk()) {
+                    continue;
+                }
+                let tag = tag.to_abstract();
+                let _ = writeln!(
+                    out,
+                    "sentinel_tag_value{{link=\"{}\",tk=\"{}\",name=\"{}\",unit=\"{}\"}} {}",
+                    escape(link_tk),
+                    escape(tag.tk()),
+                    escape(tag.name()),
+                    escape(tag.unit()),
+                    tag.value().as_f64()
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "sentinel_link_connected",
+            "gauge",
+            "1 if the device link is connected.",
+        );
+        for link in links {
+            if let Link::Device(link) = link {
+                let connected = matches!(link.status, LinkStatus::Normal);
+                let _ = writeln!(
+                    out,
+                    "sentinel_link_connected{{link=\"{}\"}} {}",
+                    escape(&link.tk),
+                    connected as u8
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "sentinel_link_scan_time_ms",
+            "gauge",
+            "Duration of the last scan.",
+        );
+        for link in links {
+            match link {
+                Link::Device(link) => {
+                    let _ = writeln!(
+                        out,
+                        "sentinel_link_scan_time_ms{{link=\"{}\"}} {}",
+                        escape(&link.tk),
+                        link.scan_time
+                    );
+                }
+                Link::Eval(link) => {
+                    let _ = writeln!(
+                        out,
+                        "sentinel_link_scan_time_ms{{link=\"{}\"}} {}",
+                        escape(&link.tk),
+                        link.scan_time_us as f64 / 1000.0
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        header(
+            &mut out,
+            "sentinel_link_errors_total",
+            "counter",
+            "Connection and polling errors.",
+        );
+        for (link_id, count) in self.link_errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "sentinel_link_errors_total{{link_id=\"{link_id}\"}} {count}"
+            );
+        }
+
+        header(
+            &mut out,
+            "sentinel_eval_tags_in_error",
+            "gauge",
+            "Eval tags currently failing.",
+        );
+        for link in links {
+            if let Link::Eval(link) = link {
+                let failing = link
+                    .tags
+                    .iter()
+                    .filter(|tag| matches!(tag.status, TagStatus::Error(_)))
+                    .count();
+                let _ = writeln!(
+                    out,
+                    "sentinel_eval_tags_in_error{{link=\"{}\"}} {failing}",
+                    escape(&link.tk)
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "sentinel_eval_errors_total",
+            "counter",
+            "Failed evaluations.",
+        );
+        for (link_id, count) in self.eval_errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "sentinel_eval_errors_total{{link_id=\"{link_id}\"}} {count}"
+            );
+        }
+
+        let requests = self.requests.lock().unwrap();
+        header(
+            &mut out,
+            "sentinel_api_requests_total",
+            "counter",
+            "Handled API requests.",
+        );
+        for ((path, status), stats) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "sentinel_api_requests_total{{path=\"{}\",status=\"{status}\"}} {}",
+                escape(path),
+                stats.count
+            );
+        }
+        header(
+            &mut out,
+            "sentinel_api_request_duration_seconds",
+            "summary",
+            "Time spent handling API requests.",
+        );
+        for ((path, status), stats) in requests.iter() {
+            let labels = format!("path=\"{}\",status=\"{status}\"", escape(path));
+            let _ = writeln!(
+                out,
+                "sentinel_api_request_duration_seconds_sum{{{labels}}} {}",
+                stats.duration_sum
+            );
+            let _ = writeln!(
+                out,
+                "sentinel_api_request_duration_seconds_count{{{labels}}} {}",
+                stats.count
+            );
+        }
+        out
+    }
+}
+
+fn link_tk(link: &Link) -> Option<&str> {
+    match link {
+        Link::Device(link) => Some(&link.tk),
+        Link::Inputs(link) => Some(&link.tk),
+        Link::Eval(link) => Some(&link.tk),
+        _ => None,
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Middleware counting requests per route and status.
+pub async fn track_requests(
+    State(state): State<GlobalState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let start = Instant::now();
+    let response = next.run(request).await;
+    state
+        .metrics
+        .record_request(&path, response.status().as_u16(), start.elapsed());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TagValue;
+    use crate::test_support::{test_links, test_state};
+    use axum::Router;
+    use axum::routing::get;
+
+    fn config(tags: &[&str], token: &str) -> MetricsConfig {
+        MetricsConfig {
+            tags: tags.iter().map(|tk| tk.to_string()).collect(),
+            token: token.to_owned(),
+        }
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape(r#"a\b"c"#), r#"a\\b\"c"#);
+        assert_eq!(escape("one\ntwo"), r"one\ntwo");
+    }
+
+    #[test]
+    fn tag_values_need_a_token() {
+        let mut links = test_links();
+        let Link::Inputs(inputs) = &mut links[1] else {
+            unreachable!()
+        };
+        inputs.tags[0].name = "Flow \"A\"".to_owned();
+        inputs.tags[0].value = TagValue::Real(2.5);
+        let metrics = Metrics::default();
+
+        metrics.set_config(config(&["IN:0"], ""));
+        let text = metrics.render(&links);
+        assert!(text.contains("# TYPE sentinel_tag_value gauge"));
+        assert!(!text.contains("sentinel_tag_value{"));
+
+        metrics.set_config(config(&["IN:0"], "secret"));
+        let text = metrics.render(&links);
+        let line = text
+            .lines()
+            .find(|line| line.starts_with("sentinel_tag_value{"))
+            .unwrap();
+        assert!(line.contains(r#"tk="IN:0",name="Flow \"A\"""#), "{line}");
+        assert!(line.ends_with(" 2.5"), "{line}");
+        assert_eq!(text.matches("sentinel_tag_value{").count(), 1);
+    }
+
+    #[test]
+    fn the_token_is_checked() {
+        assert!(config(&[], "").allows(None));
+        let config = config(&[], "secret");
+        assert!(config.allows(Some("Bearer secret")));
+        assert!(!config.allows(None));
+        assert!(!config.allows(Some("Bearer secre")));
+        assert!(!config.allows(Some("secret")));
+        assert_eq!(config.redacted().token, REDACTED_SECRET);
+    }
+
+    #[test]
+    fn request_durations_are_a_summary() {
+        let metrics = Metrics::default();
+        metrics.record_request("/api/tags", 200, Duration::from_millis(250));
+        metrics.record_request("/api/tags", 200, Duration::from_millis(500));
+        metrics.record_request("/api/tags", 404, Duration::from_millis(100));
+
+        let text = metrics.render(&[]);
+        assert!(text.contains("# TYPE sentinel_api_request_duration_seconds summary"));
+        assert!(text.contains(
+            "sentinel_api_request_duration_seconds_sum{path=\"/api/tags\",status=\"200\"} 0.75"
+        ));
+        assert!(text.contains(
+            "sentinel_api_request_duration_seconds_count{path=\"/api/tags\",status=\"200\"} 2"
+        ));
+        assert!(text.contains("sentinel_api_requests_total{path=\"/api/tags\",status=\"404\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn requests_are_counted_per_route() {
+        let state = test_state("track-requests");
+        let router = Router::new()
+            .route("/api/tags/{tk}", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                track_requests,
+            ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let client = reqwest::Client::new();
+        for path in ["/api/tags/IN:0", "/api/tags/IN:1", "/nowhere"] {
+            client.get(format!("{url}{path}")).send().await.unwrap();
+        }
+
+        let text = state.metrics.render(&[]);
+        assert!(
+            text.contains("sentinel_api_requests_total{path=\"/api/tags/{tk}\",status=\"200\"} 2"),
+            "{text}"
+        );
+        assert!(text.contains("sentinel_api_requests_total{path=\"unmatched\",status=\"404\"} 1"));
+    }
+}
@@ -0,0 +1,846 @@
+use crate::{Alarm, AlarmEvent, AlarmPriority, AlarmTransition};
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, NaiveTime, Utc};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+
+pub const NOTIFICATIONS_CONFIG_PATH: &str = "./CurrentConfig/notifications_config.json";
+// How often unacknowledged alarms are checked for escalation.
+pub const ESCALATION_CHECK_MS: u64 = 5000;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+// Shown instead of SMTP passwords and webhook header values. Sending it back keeps the stored value.
+pub const REDACTED_SECRET: &str = "********";
+
+/*
+ * Templates take {{field}} placeholders with the fields id, tk, kind,
+ * priority, area, message, value, limit, transition, user, timestamp and
+ * escalation (0 for the first notice).
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebhookChannel {
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // JSON body, placeholders are filled in its strings. The event itself when missing.
+    #[serde(default)]
+    pub body: Option<Value>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SmtpSecurity {
+    #[default]
+    None,
+    StartTls,
+    Tls,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SmtpChannel {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    #[serde(default = "default_subject")]
+    pub subject: String,
+    #[serde(default = "default_email_body")]
+    pub body: String,
+}
+
+// A local program, run with the templated arguments and the event as JSON on stdin.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CommandChannel {
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default = "default_command_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ChannelKind {
+    Webhook(WebhookChannel),
+    Smtp(SmtpChannel),
+    Command(CommandChannel),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NotificationChannel {
+    pub name: String,
+    pub kind: ChannelKind,
+}
+
+// Local time of day. An end before the start wraps past midnight.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+// Notify more channels when an alarm is still unacknowledged after a while.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Escalation {
+    pub after_minutes: f64,
+    pub channels: Vec<String>,
+}
+
+// Which alarm events go to which channels. Empty filters match everything.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NotificationRoute {
+    pub channels: Vec<String>,
+    #[serde(default)]
+    pub min_priority: Option<AlarmPriority>,
+    #[serde(default)]
+    pub areas: Vec<String>,
+    #[serde(default)]
+    pub hours: Option<TimeWindow>,
+    #[serde(default = "default_transitions")]
+    pub transitions: Vec<AlarmTransition>,
+    #[serde(default)]
+    pub escalations: Vec<Escalation>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NotificationConfig {
+    #[serde(default)]
+    pub channels: Vec<NotificationChannel>,
+    #[serde(default)]
+    pub routes: Vec<NotificationRoute>,
+    // The same transition of an alarm goes to a channel at most once per window.
+    #[serde(default = "default_dedup_secs")]
+    pub dedup_secs: u64,
+}
+
+// One message to send.
+#[derive(Clone, Debug)]
+pub struct Dispatch {
+    pub channel: NotificationChannel,
+    pub event: AlarmEvent,
+    pub escalation: usize,
+}
+
+/*
+ * Decides which events go where. Keeps the deduplication window and the
+ * escalation steps already sent, send_notification() does the sending.
+ */
+#[derive(Debug, Default)]
+pub struct NotificationRouter {
+    // Last send by (channel, alarm id, transition).
+    sent: HashMap<(String, String, AlarmTransition), Instant>,
+    // Sent escalations by (alarm id, activation time, route, step).
+    escalated: HashSet<(String, Option<DateTime<Utc>>, usize, usize)>,
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+fn default_subject() -> String {
+    "[{{priority}}] {{tk}} {{kind}} {{transition}}".to_owned()
+}
+
+fn default_email_body() -> String {
+    "{{message}}\nTag: {{tk}}\nValue: {{value}}\nLimit: {{limit}}\nTime: {{timestamp}}".to_owned()
+}
+
+fn default_command_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_transitions() -> Vec<AlarmTransition> {
+    vec![AlarmTransition::Activated]
+}
+
+fn default_dedup_secs() -> u64 {
+    300
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            channels: Vec::new(),
+            routes: Vec::new(),
+            dedup_secs: default_dedup_secs(),
+        }
+    }
+}
+
+impl NotificationConfig {
+    // Names used by the routes that don't match a channel.
+    pub fn unknown_channels(&self) -> Vec<String> {
+        let names: HashSet<&str> = self.channels.iter().map(|c| c.name.as_str()).collect();
+        let mut unknown: Vec<String> = self
+            .routes
+            .iter()
+            .flat_map(|route| {
+                route.channels.iter().chain(
+                    route
+                        .escalations
+                        .iter()
+                        .flat_map(|escalation| escalation.channels.iter()),
+                )
+            })
+            .filter(|name| !names.contains(name.as_str()))
+            .cloned()
+            .collect();
+        unknown.sort();
+        unknown.dedup();
+        unknown
+    }
+
+    // A copy without the SMTP passwords and webhook header values, for the API and the audit log.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        for channel in config.channels.iter_mut() {
+            match &mut channel.kind {
+                ChannelKind::Webhook(webhook) => {
+                    for value in webhook.headers.values_mut() {
+                        *value = REDACTED_SECRET.to_owned();
+                    }
+                }
+                ChannelKind::Smtp(smtp) => {
+                    if let Some(password) = &mut smtp.password {
+                        *password = REDACTED_SECRET.to_owned();
+                    }
+                }
+                ChannelKind::Command(_) => {}
+            }
+        }
+        config
+    }
+
+    // Put back the secrets of `old` where this config still has the redacted placeholder.
+    pub fn restore_secrets(&mut self, old: &NotificationConfig) {
+        for channel in self.channels.iter_mut() {
+            let Some(old) = old.channel(&channel.name) else {
+                continue;
+            };
+            match (&mut channel.kind, &old.kind) {
+                (ChannelKind::Webhook(webhook), ChannelKind::Webhook(old)) => {
+                    for (name, value) in webhook.headers.iter_mut() {
+                        if value == REDACTED_SECRET
+                            && let Some(old_value) = old.headers.get(name)
+                        {
+                            *value = old_value.clone();
+                        }
+                    }
+                }
+                (ChannelKind::Smtp(smtp), ChannelKind::Smtp(old))
+                    if smtp.password.as_deref() == Some(REDACTED_SECRET) =>
+                {
+                    smtp.password = old.password.clone();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn channel(&self, name: &str) -> Option<&NotificationChannel> {
+        self.channels.iter().find(|channel| channel.name == name)
+    }
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl NotificationRoute {
+    // Priority, area and time of day, the transition is checked separately.
+    fn matches(&self, alarm: &Alarm, time: NaiveTime) -> bool {
+        self.min_priority
+            .is_none_or(|priority| alarm.priority >= priority)
+            && (self.areas.is_empty() || self.areas.contains(&alarm.area))
+            && self.hours.as_ref().is_none_or(|hours| hours.contains(time))
+    }
+}
+
+impl NotificationRouter {
+    // Channels for one alarm event. `time` is the local time of day for the route hours.
+    pub fn route(
+        &mut self,
+        config: &NotificationConfig,
+        event: &AlarmEvent,
+        now: Instant,
+        time: NaiveTime,
+    ) -> Vec<Dispatch> {
+        let window = Duration::from_secs(config.dedup_secs);
+        self.sent
+            .retain(|_, sent| now.duration_since(*sent) < window);
+
+        let mut channels: Vec<&str> = config
+            .routes
+            .iter()
+            .filter(|route| route.transitions.contains(&event.transition))
+            .filter(|route| route.matches(&event.alarm, time))
+            .flat_map(|route| route.channels.iter().map(String::as_str))
+            .collect();
+        // Several routes may name the same channel.
+        channels.sort();
+        channels.dedup();
+
+        let mut dispatches = Vec::new();
+        for name in channels {
+            let Some(channel) = config.channel(name) else {
+                continue;
+            };
+            let key = (name.to_owned(), event.alarm.id.clone(), event.transition);
+            if self.sent.contains_key(&key) {
+                continue;
+            }
+            self.sent.insert(key, now);
+            dispatches.push(Dispatch {
+                channel: channel.clone(),
+                event: event.clone(),
+                escalation: 0,
+            });
+        }
+        dispatches
+    }
+
+    // Escalation steps due for the open alarms at `now`, local time of day `time`.
+    pub fn escalate(
+        &mut self,
+        config: &NotificationConfig,
+        alarms: &[Alarm],
+        now: DateTime<Utc>,
+        time: NaiveTime,
+    ) -> Vec<Dispatch> {
+        let unacked: Vec<&Alarm> = alarms
+            .iter()
+            .filter(|alarm| alarm.active && !alarm.acked)
+            .collect();
+        // Forget alarms that were acknowledged, cleared or raised again.
+        self.escalated.retain(|(id, activated_at, _, _)| {
+            unacked
+                .iter()
+                .any(|alarm| alarm.id == *id && alarm.activated_at == *activated_at)
+        });
+
+        let mut dispatches = Vec::new();
+        for alarm in unacked {
+            let Some(activated_at) = alarm.activated_at else {
+                continue;
+            };
+            let minutes = (now - activated_at).num_milliseconds() as f64 / 60_000.0;
+            for (route_index, route) in config.routes.iter().enumerate() {
+                if !route.matches(alarm, time) {
+                    continue;
+                }
+                for (step, escalation) in route.escalations.iter().enumerate() {
+                    let key = (alarm.id.clone(), alarm.activated_at, route_index, step);
+                    if minutes < escalation.after_minutes || self.escalated.contains(&key) {
+                        continue;
+                    }
+                    self.escalated.insert(key);
+                    let event = AlarmEvent {
+                        timestamp: now,
+                        transition: AlarmTransition::Activated,
+                        user: None,
+                        alarm: alarm.clone(),
+                    };
+                    for name in escalation.channels.iter() {
+                        if let Some(channel) = config.channel(name) {
+                            dispatches.push(Dispatch {
+                                channel: channel.clone(),
+                                event: event.clone(),
+                                escalation: step + 1,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        dispatches
+    }
+}
+
+fn template_fields(dispatch: &Dispatch) -> HashMap<&'static str, String> {
+    let event = &dispatch.event;
+    let alarm = &event.alarm;
+    HashMap::from([
+        ("id", alarm.id.clone()),
+        ("tk", alarm.tk.clone()),
+        ("kind", alarm.kind.code().to_owned()),
+        ("priority", format!("{:?}", alarm.priority)),
+        ("area", alarm.area.clone()),
+        ("message", alarm.message.clone()),
+        ("value", alarm.value.to_string()),
+        ("limit", alarm.limit.to_string()),
+        ("transition", format!("{:?}", event.transition)),
+        ("user", event.user.clone().unwrap_or_default()),
+        ("timestamp", event.timestamp.to_rfc3339()),
+        ("escalation", dispatch.escalation.to_string()),
+    ])
+}
+
+// Fill in the {{field}} placeholders. Unknown placeholders are left as they are.
+pub fn render(template: &str, fields: &HashMap<&'static str, String>) -> String {
+    let mut out = template.to_owned();
+    for (name, value) in fields {
+        out = out.replace(&format!("{{{{{name}}}}}"), value);
+    }
+    out
+}
+
+fn render_json(template: &Value, fields: &HashMap<&'static str, String>) -> Value {
+    match template {
+        Value::String(s) => Value::String(render(s, fields)),
+        Value::Array(list) => Value::Array(list.iter().map(|v| render_json(v, fields)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, v)| (key.clone(), render_json(v, fields)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+// Send one notification.
+pub async fn send_notification(http: &reqwest::Client, dispatch: &Dispatch) -> Result<()> {
+    let fields = template_fields(dispatch);
+    match &dispatch.channel.kind {
+        ChannelKind::Webhook(webhook) => {
+            let body = match &webhook.body {
+                Some(body) => render_json(body, &fields),
+                None => serde_json::to_value(&dispatch.event)?,
+            };
+            let mut request = http.post(&webhook.url).timeout(WEBHOOK_TIMEOUT).json(&body);
+            for (name, value) in webhook.headers.iter() {
+                request = request.header(name, render(value, &fields));
+            }
+            request.send().await?.error_for_status()?;
+        }
+        ChannelKind::Smtp(smtp) => {
+            let builder = match smtp.security {
+                SmtpSecurity::None => {
+                    AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host)
+                }
+                SmtpSecurity::StartTls => {
+                    AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?
+                }
+                SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+            };
+            let mut builder = builder.port(smtp.port).timeout(Some(SMTP_TIMEOUT));
+            if let Some(username) = &smtp.username {
+                builder = builder.credentials(Credentials::new(
+                    username.clone(),
+                    smtp.password.clone().unwrap_or_default(),
+                ));
+            }
+            let mut message = Message::builder()
+                .from(smtp.from.parse()?)
+                .subject(render(&smtp.subject, &fields));
+            for to in smtp.to.iter() {
+                message = message.to(to.parse()?);
+            }
+            let message = message.body(render(&smtp.body, &fields))?;
+            builder.build().send(message).await?;
+        }
+        ChannelKind::Command(command) => {
+            let args: Vec<String> = command.args.iter().map(|a| render(a, &fields)).collect();
+            let mut child = tokio::process::Command::new(&command.program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .kill_on_drop(true)
+                .spawn()?;
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin
+                    .write_all(&serde_json::to_vec(&dispatch.event)?)
+                    .await?;
+            }
+            let timeout = Duration::from_millis(command.timeout_ms);
+            let status = tokio::time::timeout(timeout, child.wait())
+                .await
+                .map_err(|_| anyhow!("{} timed out", command.program))??;
+            if !status.success() {
+                return Err(anyhow!("{} exited with {status}", command.program));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{http_stand_in, smtp_stand_in};
+    use serde_json::json;
+
+    fn alarm(id: &str, priority: &str, area: &str, minutes_ago: i64) -> Alarm {
+        serde_json::from_value(json!({
+            "id": id,
+            "tk": "LK0:000",
+            "link_id": 0,
+            "tag_id": 0,
+            "kind": "High",
+            "priority": priority,
+            "area": area,
+            "message": "Tank level high",
+            "limit": 80.0,
+            "value": 85.5,
+            "active": true,
+            "acked": false,
+            "activated_at": Utc::now() - chrono::Duration::minutes(minutes_ago),
+            "cleared_at": null,
+            "acked_at": null,
+            "acked_by": null,
+            "shelved_until": null,
+            "shelved_by": null,
+            "suppressed": false,
+        }))
+        .unwrap()
+    }
+
+    fn event(alarm: Alarm, transition: AlarmTransition) -> AlarmEvent {
+        AlarmEvent {
+            timestamp: Utc::now(),
+            transition,
+            user: None,
+            alarm,
+        }
+    }
+
+    fn webhook(name: &str, url: &str) -> NotificationChannel {
+        NotificationChannel {
+            name: name.to_owned(),
+            kind: ChannelKind::Webhook(WebhookChannel {
+                url: url.to_owned(),
+                headers: HashMap::new(),
+                body: None,
+            }),
+        }
+    }
+
+    fn route(channels: &[&str]) -> NotificationRoute {
+        NotificationRoute {
+            channels: channels.iter().map(|c| c.to_string()).collect(),
+            min_priority: None,
+            areas: Vec::new(),
+            hours: None,
+            transitions: default_transitions(),
+            escalations: Vec::new(),
+        }
+    }
+
+    fn config(routes: Vec<NotificationRoute>) -> NotificationConfig {
+        NotificationConfig {
+            channels: ["ops", "oncall", "manager"]
+                .iter()
+                .map(|name| webhook(name, "http://127.0.0.1:1"))
+                .collect(),
+            routes,
+            dedup_secs: 60,
+        }
+    }
+
+    fn at(hour: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    fn channel_names(dispatches: &[Dispatch]) -> Vec<&str> {
+        dispatches
+            .iter()
+            .map(|dispatch| dispatch.channel.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn routes_by_priority_and_area() {
+        let config = config(vec![
+            NotificationRoute {
+                min_priority: Some(AlarmPriority::High),
+                ..route(&["oncall"])
+            },
+            NotificationRoute {
+                areas: vec!["tank_farm".to_owned()],
+                ..route(&["ops"])
+            },
+        ]);
+        let now = Instant::now();
+        let mut router = NotificationRouter::default();
+
+        let low = event(
+            alarm("a", "Low", "tank_farm", 0),
+            AlarmTransition::Activated,
+        );
+        assert_eq!(
+            channel_names(&router.route(&config, &low, now, at(12))),
+            vec!["ops"]
+        );
+        let critical = event(
+            alarm("b", "Critical", "tank_farm", 0),
+            AlarmTransition::Activated,
+        );
+        assert_eq!(
+            channel_names(&router.route(&config, &critical, now, at(12))),
+            vec!["oncall", "ops"]
+        );
+        let elsewhere = event(
+            alarm("c", "Medium", "compressor", 0),
+            AlarmTransition::Activated,
+        );
+        assert!(router.route(&config, &elsewhere, now, at(12)).is_empty());
+        // Only activations by default.
+        let cleared = event(
+            alarm("d", "Critical", "tank_farm", 0),
+            AlarmTransition::Cleared,
+        );
+        assert!(router.route(&config, &cleared, now, at(12)).is_empty());
+    }
+
+    #[test]
+    fn routes_by_time_of_day() {
+        let night = TimeWindow {
+            start: at(22),
+            end: at(6),
+        };
+        assert!(night.contains(at(23)) && night.contains(at(2)));
+        assert!(!night.contains(at(6)) && !night.contains(at(12)));
+
+        let config = config(vec![
+            NotificationRoute {
+                hours: Some(night),
+                ..route(&["oncall"])
+            },
+            NotificationRoute {
+                hours: Some(TimeWindow {
+                    start: at(6),
+                    end: at(22),
+                }),
+                ..route(&["ops"])
+            },
+        ]);
+        let now = Instant::now();
+        let mut router = NotificationRouter::default();
+        let first = event(alarm("a", "High", "", 0), AlarmTransition::Activated);
+        assert_eq!(
+            channel_names(&router.route(&config, &first, now, at(3))),
+            vec!["oncall"]
+        );
+        let second = event(alarm("b", "High", "", 0), AlarmTransition::Activated);
+        assert_eq!(
+            channel_names(&router.route(&config, &second, now, at(9))),
+            vec!["ops"]
+        );
+    }
+
+    #[test]
+    fn repeats_are_dropped_within_the_dedup_window() {
+        let config = config(vec![NotificationRoute {
+            transitions: vec![AlarmTransition::Activated, AlarmTransition::Cleared],
+            ..route(&["ops"])
+        }]);
+        let start = Instant::now();
+        let mut router = NotificationRouter::default();
+        let activated = event(alarm("a", "High", "", 0), AlarmTransition::Activated);
+        let cleared = event(alarm("a", "High", "", 0), AlarmTransition::Cleared);
+
+        assert_eq!(router.route(&config, &activated, start, at(12)).len(), 1);
+        assert!(
+            router
+                .route(&config, &activated, start + Duration::from_secs(30), at(12))
+                .is_empty()
+        );
+        // Another transition of the same alarm is not a repeat.
+        assert_eq!(
+            router
+                .route(&config, &cleared, start + Duration::from_secs(30), at(12))
+                .len(),
+            1
+        );
+        assert_eq!(
+            router
+                .route(&config, &activated, start + Duration::from_secs(60), at(12))
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn escalates_unacknowledged_alarms_once_per_step() {
+        let config = config(vec![NotificationRoute {
+            escalations: vec![
+                Escalation {
+                    after_minutes: 5.0,
+                    channels: vec!["oncall".to_owned()],
+                },
+                Escalation {
+                    after_minutes: 30.0,
+                    channels: vec!["manager".to_owned()],
+                },
+            ],
+            ..route(&["ops"])
+        }]);
+        let now = Utc::now();
+        let mut router = NotificationRouter::default();
+        let mut alarms = vec![alarm("a", "High", "", 10), alarm("b", "High", "", 1)];
+
+        let dispatches = router.escalate(&config, &alarms, now, at(12));
+        assert_eq!(channel_names(&dispatches), vec!["oncall"]);
+        assert_eq!(dispatches[0].event.alarm.id, "a");
+        assert_eq!(dispatches[0].escalation, 1);
+        assert!(router.escalate(&config, &alarms, now, at(12)).is_empty());
+
+        let later = now + chrono::Duration::minutes(25);
+        let dispatches = router.escalate(&config, &alarms, later, at(12));
+        assert_eq!(channel_names(&dispatches), vec!["manager", "oncall"]);
+        assert_eq!(dispatches[0].escalation, 2);
+        assert_eq!(dispatches[1].event.alarm.id, "b");
+
+        // Acknowledged alarms stop escalating.
+        alarms[0].acked = true;
+        alarms[1].acked = true;
+        let even_later = now + chrono::Duration::minutes(60);
+        assert!(
+            router
+                .escalate(&config, &alarms, even_later, at(12))
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn webhooks_post_the_rendered_body() {
+        let (url, mut requests) = http_stand_in(200, "{}").await;
+        let channel = NotificationChannel {
+            name: "ops".to_owned(),
+            kind: ChannelKind::Webhook(WebhookChannel {
+                url: format!("{url}/hook"),
+                headers: HashMap::from([("X-Alarm".to_owned(), "{{id}}".to_owned())]),
+                body: Some(json!({"text": "{{priority}} {{tk}}: {{message}} ({{value}})"})),
+            }),
+        };
+        let dispatch = Dispatch {
+            channel,
+            event: event(alarm("a", "High", "", 0), AlarmTransition::Activated),
+            escalation: 0,
+        };
+        send_notification(&reqwest::Client::new(), &dispatch)
+            .await
+            .unwrap();
+
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/hook");
+        assert_eq!(request.headers["x-alarm"], "a");
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            body,
+            json!({"text": "High LK0:000: Tank level high (85.5)"})
+        );
+    }
+
+    #[tokio::test]
+    async fn webhook_errors_are_reported() {
+        let (url, _requests) = http_stand_in(500, "{}").await;
+        let dispatch = Dispatch {
+            channel: webhook("ops", &url),
+            event: event(alarm("a", "High", "", 0), AlarmTransition::Activated),
+            escalation: 0,
+        };
+        assert!(
+            send_notification(&reqwest::Client::new(), &dispatch)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn smtp_sends_the_rendered_mail() {
+        let (port, mut mails) = smtp_stand_in().await;
+        let channel = NotificationChannel {
+            name: "mail".to_owned(),
+            kind: ChannelKind::Smtp(SmtpChannel {
+                host: "127.0.0.1".to_owned(),
+                port,
+                security: SmtpSecurity::None,
+                username: None,
+                password: None,
+                from: "sentinel@example.com".to_owned(),
+                to: vec!["ops@example.com".to_owned()],
+                subject: default_subject(),
+                body: default_email_body(),
+            }),
+        };
+        let dispatch = Dispatch {
+            channel,
+            event: event(alarm("a", "Critical", "", 0), AlarmTransition::Activated),
+            escalation: 0,
+        };
+        send_notification(&reqwest::Client::new(), &dispatch)
+            .await
+            .unwrap();
+
+        let mail = mails.recv().await.unwrap();
+        assert_eq!(mail.from, "<sentinel@example.com>");
+        assert_eq!(mail.to, vec!["<ops@example.com>"]);
+        assert!(
+            mail.data
+                .contains("Subject: [Critical] LK0:000 H Activated")
+        );
+        assert!(mail.data.contains("Tank level high"));
+    }
+
+    #[test]
+    fn secrets_are_redacted_and_restored() {
+        let mut stored = config(Vec::new());
+        stored.channels.push(NotificationChannel {
+            name: "mail".to_owned(),
+            kind: ChannelKind::Smtp(SmtpChannel {
+                host: "mail.example.com".to_owned(),
+                port: 25,
+                security: SmtpSecurity::StartTls,
+                username: Some("sentinel".to_owned()),
+                password: Some("hunter2".to_owned()),
+                from: "sentinel@example.com".to_owned(),
+                to: Vec::new(),
+                subject: default_subject(),
+                body: default_email_body(),
+            }),
+        });
+        if let ChannelKind::Webhook(webhook) = &mut stored.channels[0].kind {
+            webhook
+                .headers
+                .insert("Authorization".to_owned(), "Bearer abc".to_owned());
+        }
+
+        let redacted = stored.redacted();
+        let text = serde_json::to_string(&redacted).unwrap();
+        assert!(!text.contains("hunter2") && !text.contains("Bearer abc"));
+
+        // Sending the redacted config back keeps the stored secrets.
+        let mut update = redacted.clone();
+        update.restore_secrets(&stored);
+        assert_eq!(update, stored);
+
+        // A new value replaces the old one.
+        let mut update = redacted;
+        if let ChannelKind::Smtp(smtp) = &mut update.channels[3].kind {
+            smtp.password = Some("correct horse".to_owned());
+        }
+        update.restore_secrets(&stored);
+        let ChannelKind::Smtp(smtp) = &update.channels[3].kind else {
+            unreachable!()
+        };
+        assert_eq!(smtp.password.as_deref(), Some("correct horse"));
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, InvalidDnsNameError, ServerName};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::tls::{ChannelBinding, MakeTlsConnect, TlsConnect, TlsStream};
+use tokio_rustls::TlsConnector;
+
+/*
+ * TLS for the Postgres logger connections, on rustls with the ring provider.
+ * The server certificate is checked against the webpki roots, or against the
+ * CA certificates of a PEM file for servers with a private CA.
+ */
+#[derive(Clone)]
+pub struct PostgresTls {
+    config: Arc<rustls::ClientConfig>,
+}
+
+impl PostgresTls {
+    pub fn new(ca_file: &str) -> Result<Self> {
+        let mut roots = rustls::RootCertStore::empty();
+        if ca_file.is_empty() {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        } else {
+            for cert in CertificateDer::pem_file_iter(ca_file)? {
+                roots.add(cert?)?;
+            }
+            if roots.is_empty() {
+                anyhow::bail!("No certificates in {ca_file}.");
+            }
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+}
+
+impl<S> MakeTlsConnect<S> for PostgresTls
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = PostgresTlsStream<S>;
+    type TlsConnect = PostgresTlsConnect;
+    type Error = InvalidDnsNameError;
+
+    fn make_tls_connect(&mut self, domain: &str) -> Result<PostgresTlsConnect, Self::Error> {
+        Ok(PostgresTlsConnect {
+            connector: TlsConnector::from(self.config.clone()),
+            domain: ServerName::try_from(domain.to_owned())?,
+        })
+    }
+}
+
+pub struct PostgresTlsConnect {
+    connector: TlsConnector,
+    domain: ServerName<'static>,
+}
+
+impl<S> TlsConnect<S> for PostgresTlsConnect
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = PostgresTlsStream<S>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<PostgresTlsStream<S>>> + Send>>;
+
+    fn connect(self, stream: S) -> Self::Future {
+        Box::pin(async move {
+            let stream = self.connector.connect(self.domain, stream).await?;
+            Ok(PostgresTlsStream(stream))
+        })
+    }
+}
+
+pub struct PostgresTlsStream<S>(tokio_rustls::client::TlsStream<S>);
+
+impl<S> TlsStream for PostgresTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Without channel binding the login uses plain SCRAM-SHA-256.
+    fn channel_binding(&self) -> ChannelBinding {
+        ChannelBinding::none()
+    }
+}
+
+impl<S> AsyncRead for PostgresTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for PostgresTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,248 @@
+use crate::{EvalLimits, FormulaError, apply_limits, stop_at_deadline};
+use log::info;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Engine, EvalAltResult, Module, ModuleResolver, Position, Scope, Shared};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+pub const SCRIPT_MODULES_PATH: &str = "./CurrentConfig/script_modules.json";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScriptModule {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub source: String,
+}
+
+/*
+ * Rhai modules shared by all eval formulas, imported with
+ * `import "name" as name;`. A module is compiled once when it is saved.
+ * Imports are resolved every time a formula runs, so a saved module is
+ * picked up on the next cycle without recompiling the evals.
+ * Modules can't import each other.
+ */
+#[derive(Debug, Default)]
+pub struct ScriptModules {
+    modules: RwLock<Vec<ScriptModule>>,
+    compiled: RwLock<HashMap<String, Shared<Module>>>,
+}
+
+impl ScriptModules {
+    pub fn list(&self) -> Vec<ScriptModule> {
+        self.modules.read().unwrap().clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<ScriptModule> {
+        self.modules
+            .read()
+            .unwrap()
+            .iter()
+            .find(|module| module.name == name)
+            .cloned()
+    }
+
+    // Replace all modules, e.g. from the config file. Modules that fail to compile are skipped.
+    pub fn load(&self, modules: Vec<ScriptModule>) {
+        for module in modules {
+            if let Err(e) = self.set(module) {
+                info!("Could not load script module: {}", e.message);
+            }
+        }
+    }
+
+    // Add or replace a module. It is only stored if it compiles.
+    pub fn set(&self, module: ScriptModule) -> Result<(), FormulaError> {
+        let compiled = compile_module(&module)?;
+        self.compiled
+            .write()
+            .unwrap()
+            .insert(module.name.clone(), compiled);
+        let mut modules = self.modules.write().unwrap();
+        match modules.iter_mut().find(|m| m.name == module.name) {
+            Some(existing) => *existing = module,
+            None => modules.push(module),
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> Option<ScriptModule> {
+        self.compiled.write().unwrap().remove(name);
+        let mut modules = self.modules.write().unwrap();
+        let position = modules.iter().position(|module| module.name == name)?;
+        Some(modules.remove(position))
+    }
+}
+
+fn compile_module(module: &ScriptModule) -> Result<Shared<Module>, FormulaError> {
+    let valid_name = !module.name.is_empty()
+        && module
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name {
+        return Err(FormulaError {
+            message: format!("Invalid module name: {:?}", module.name),
+            line: None,
+            column: None,
+        });
+    }
+    // No resolver, so an import inside a module (including a cycle) fails to compile.
+    let mut engine = Engine::new();
+    engine.set_module_resolver(DummyModuleResolver::new());
+    // The top-level code runs once here, with the same limits as a formula.
+    let started = Instant::now();
+    let deadline_us: Arc<AtomicU64> = Arc::default();
+    stop_at_deadline(&mut engine, started, deadline_us.clone());
+    apply_limits(&mut engine, &deadline_us, started, &EvalLimits::default());
+    let ast = engine.compile(&module.source).map_err(|e| FormulaError {
+        message: e.err_type().to_string(),
+        line: e.position().line(),
+        column: e.position().position(),
+    })?;
+    let compiled =
+        Module::eval_ast_as_new(Scope::new(), &ast, &engine).map_err(|e| FormulaError {
+            message: e.to_string(),
+            line: e.position().line(),
+            column: e.position().position(),
+        })?;
+    Ok(compiled.into())
+}
+
+// Resolves `import` statements of eval formulas against the shared modules.
+pub struct ScriptModuleResolver {
+    modules: Arc<ScriptModules>,
+}
+
+impl ScriptModuleResolver {
+    pub fn new(modules: Arc<ScriptModules>) -> Self {
+        Self { modules }
+    }
+}
+
+impl ModuleResolver for ScriptModuleResolver {
+    fn resolve(
+        &self,
+        _engine: &Engine,
+        _source: Option<&str>,
+        path: &str,
+        pos: Position,
+    ) -> Result<Shared<Module>, Box<EvalAltResult>> {
+        self.modules
+            .compiled
+            .read()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| EvalAltResult::ErrorModuleNotFound(path.to_owned(), pos).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rhai::FLOAT;
+
+    fn module(name: &str, source: &str) -> ScriptModule {
+        ScriptModule {
+            name: name.to_owned(),
+            description: String::new(),
+            source: source.to_owned(),
+        }
+    }
+
+    fn engine(modules: &Arc<ScriptModules>) -> Engine {
+        let mut engine = Engine::new();
+        engine.set_module_resolver(ScriptModuleResolver::new(modules.clone()));
+        engine
+    }
+
+    #[test]
+    fn resolves_imports() {
+        let modules = Arc::new(ScriptModules::default());
+        modules
+            .set(module("units", "fn c_to_f(c) { c * 1.8 + 32.0 }"))
+            .unwrap();
+        let result = engine(&modules)
+            .eval::<FLOAT>(r#"import "units" as u; u::c_to_f(100.0)"#)
+            .unwrap();
+        assert_eq!(result, 212.0);
+    }
+
+    #[test]
+    fn missing_modules_fail_to_resolve() {
+        let modules = Arc::new(ScriptModules::default());
+        let error = engine(&modules)
+            .eval::<FLOAT>(r#"import "nope" as n; n::f()"#)
+            .unwrap_err();
+        assert!(
+            matches!(*error, EvalAltResult::ErrorModuleNotFound(ref name, _) if name == "nope")
+        );
+
+        modules.set(module("gone", "fn f() { 1.0 }")).unwrap();
+        assert!(modules.remove("gone").is_some());
+        assert!(
+            engine(&modules)
+                .eval::<FLOAT>(r#"import "gone" as g; g::f()"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn module_bodies_run_within_the_limits() {
+        let modules = ScriptModules::default();
+        let error = modules.set(module("spin", "loop {}")).unwrap_err();
+        assert!(
+            error.message.contains("Too many operations"),
+            "{}",
+            error.message
+        );
+        let error = modules
+            .set(module("deep", "fn f(n) { f(n + 1) } f(0);"))
+            .unwrap_err();
+        assert!(modules.get("deep").is_none(), "{}", error.message);
+    }
+
+    #[test]
+    fn modules_cant_import_each_other() {
+        let modules = ScriptModules::default();
+        modules.set(module("a", "fn f() { 1.0 }")).unwrap();
+        assert!(
+            modules
+                .set(module("b", r#"import "a" as a; fn g() { a::f() }"#))
+                .is_err()
+        );
+        assert!(
+            modules
+                .set(module("a", r#"import "a" as a; fn f() { a::f() }"#))
+                .is_err()
+        );
+        // The failed update keeps the previous version.
+        assert_eq!(modules.get("a").unwrap().source, "fn f() { 1.0 }");
+        assert!(modules.get("b").is_none());
+    }
+
+    #[test]
+    fn updates_are_picked_up_by_the_next_run() {
+        let modules = Arc::new(ScriptModules::default());
+        let engine = engine(&modules);
+        let ast = engine
+            .compile(r#"import "gain" as g; g::k() * 2.0"#)
+            .unwrap();
+        modules.set(module("gain", "fn k() { 1.5 }")).unwrap();
+        assert_eq!(engine.eval_ast::<FLOAT>(&ast).unwrap(), 3.0);
+        modules.set(module("gain", "fn k() { 4.0 }")).unwrap();
+        assert_eq!(engine.eval_ast::<FLOAT>(&ast).unwrap(), 8.0);
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        let modules = ScriptModules::default();
+        assert!(modules.set(module("../etc", "fn f() { 1.0 }")).is_err());
+        assert!(modules.set(module("", "fn f() { 1.0 }")).is_err());
+        assert!(modules.list().is_empty());
+    }
+}
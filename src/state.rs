@@ -1,6 +1,12 @@
-use crate::Link;
+use crate::{
+    ALARM_EVENT_CAPACITY, ALARM_JOURNAL_DIR, ALARM_JOURNAL_MAX_FILE_BYTES, ALARM_JOURNAL_MAX_FILES,
+    AUDIT_DIR, AUDIT_MAX_FILE_BYTES, AUDIT_MAX_FILES, AlarmEngine, AlarmEvent, AuthConfig, Journal,
+    Link, LoggerSessions, Metrics, NotificationConfig, ScriptModules, StreamRegistry,
+    TAG_CHANGE_CAPACITY, TagChange, TagValue, TagView,
+};
+use log::info;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, broadcast};
 pub type StateDb = Arc<Mutex<Vec<Link>>>;
 pub type ConfigHash = Arc<Mutex<String>>;
 
@@ -10,6 +16,18 @@ pub type ConfigHash = Arc<Mutex<String>>;
 pub struct GlobalState {
     pub state_db: StateDb,
     pub current_config_hash: ConfigHash,
+    pub metrics: Arc<Metrics>,
+    pub audit: Arc<Journal>,
+    pub tag_changes: broadcast::Sender<TagChange>,
+    pub script_modules: Arc<ScriptModules>,
+    // Never locked across an await, so a std mutex.
+    pub alarms: Arc<std::sync::Mutex<AlarmEngine>>,
+    pub alarm_events: broadcast::Sender<AlarmEvent>,
+    pub alarm_journal: Arc<Journal>,
+    pub notifications: Arc<std::sync::Mutex<NotificationConfig>>,
+    pub streams: Arc<StreamRegistry>,
+    pub auth: Arc<std::sync::Mutex<AuthConfig>>,
+    pub logger_sessions: Arc<LoggerSessions>,
 }
 
 impl GlobalState {
@@ -17,6 +35,72 @@ impl GlobalState {
         Self {
             state_db: Arc::new(Mutex::new(links)),
             current_config_hash: Arc::new(Mutex::new(String::new())),
+            metrics: Arc::new(Metrics::default()),
+            audit: Arc::new(Journal::new(
+                AUDIT_DIR,
+                "audit",
+                AUDIT_MAX_FILE_BYTES,
+                AUDIT_MAX_FILES,
+            )),
+            tag_changes: broadcast::channel(TAG_CHANGE_CAPACITY).0,
+            script_modules: Arc::new(ScriptModules::default()),
+            alarms: Arc::new(std::sync::Mutex::new(AlarmEngine::default())),
+            alarm_events: broadcast::channel(ALARM_EVENT_CAPACITY).0,
+            alarm_journal: Arc::new(Journal::new(
+                ALARM_JOURNAL_DIR,
+                "alarms",
+                ALARM_JOURNAL_MAX_FILE_BYTES,
+                ALARM_JOURNAL_MAX_FILES,
+            )),
+            notifications: Arc::new(std::sync::Mutex::new(NotificationConfig::default())),
+            streams: Arc::new(StreamRegistry::default()),
+            auth: Arc::new(std::sync::Mutex::new(AuthConfig::default())),
+            logger_sessions: Arc::new(LoggerSessions::default()),
         }
     }
+
+    pub fn publish_tag_change(&self, link_id: usize, tag: TagView) {
+        // Sending only fails when nobody is subscribed.
+        let _ = self.tag_changes.send(TagChange {
+            link_id,
+            tag_id: tag.id(),
+            tk: tag.tk().to_owned(),
+            value: tag.value().clone(),
+            status: tag.status().clone(),
+            timestamp: chrono::Utc::now(),
+        });
+    }
+
+    // Write an alarm event to the journal and send it to the subscribers.
+    pub fn record_alarm_event(&self, event: AlarmEvent) {
+        if let Err(e) = self.alarm_journal.append(&event) {
+            info!("Could not write alarm journal entry: {e}");
+        }
+        // Sending only fails when nobody is subscribed.
+        let _ = self.alarm_events.send(event);
+    }
+
+    /*
+     * Write a tag through link::write_tag. Input tags change right away and
+     * are published here, device tags are published once polled back.
+     */
+    pub fn write_tag(
+        &self,
+        links: &mut [Link],
+        link_id: usize,
+        tag_id: usize,
+        value: TagValue,
+    ) -> Option<TagValue> {
+        let old_value = crate::write_tag(links, link_id, tag_id, value)?;
+        let input = links.iter().find_map(|link| match link {
+            Link::Inputs(link) if link.id == link_id => {
+                link.tags.iter().find(|tag| tag.id == tag_id)
+            }
+            _ => None,
+        });
+        if let Some(tag) = input {
+            self.publish_tag_change(link_id, TagView::InputTag(tag));
+        }
+        Some(old_value)
+    }
 }
@@ -0,0 +1,456 @@
+use crate::{ApiError, AuthUser, GlobalState, Link, Requester, Role, TagChange};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::response::IntoResponse;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use chrono::{DateTime, Utc};
+use futures_util::Stream;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time;
+
+// Changes arriving within this window go out as one batch, latest value wins.
+const STREAM_FLUSH: Duration = Duration::from_millis(100);
+
+// Tags by key and whole links by id.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TagSubscription {
+    #[serde(default)]
+    pub tks: Vec<String>,
+    #[serde(default)]
+    pub links: Vec<usize>,
+}
+
+// Sent by WebSocket clients as JSON text messages.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StreamRequest {
+    Subscribe(TagSubscription),
+    Unsubscribe(TagSubscription),
+}
+
+// Sent to the clients. Snapshot holds the current values after subscribing
+// and after the client fell too far behind.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StreamMessage {
+    Snapshot(Vec<TagChange>),
+    Changes(Vec<TagChange>),
+    Error(String),
+}
+
+// Comma separated lists, e.g. /api/stream/sse?tks=LK0:000,IN:1&links=2
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SseQuery {
+    #[serde(default)]
+    pub tks: String,
+    #[serde(default)]
+    pub links: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StreamClient {
+    pub id: u64,
+    pub transport: String,
+    pub requester: Requester,
+    pub connected_at: DateTime<Utc>,
+    pub subscription: TagSubscription,
+    pub sent: u64,
+    pub resyncs: u64,
+}
+
+// Connected streaming clients and what they are subscribed to.
+#[derive(Debug, Default)]
+pub struct StreamRegistry {
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<u64, StreamClient>>,
+}
+
+// Removes the client from the registry when the connection ends.
+struct StreamGuard {
+    registry: Arc<StreamRegistry>,
+    id: u64,
+}
+
+/*
+ * The changes one client is subscribed to. Keeps the batch being collected
+ * in the struct, so next_batch() can be cancelled by a select without losing
+ * changes. Tags outside the user's scope are never sent.
+ */
+struct TagStream {
+    user: AuthUser,
+    changes: broadcast::Receiver<TagChange>,
+    tks: HashSet<String>,
+    links: HashSet<usize>,
+    pending: BTreeMap<(usize, usize), TagChange>,
+    lagged: bool,
+}
+
+impl StreamRegistry {
+    fn register(
+        registry: &Arc<StreamRegistry>,
+        transport: &str,
+        requester: &Requester,
+    ) -> StreamGuard {
+        let id = registry.next_id.fetch_add(1, Ordering::Relaxed);
+        registry.clients.lock().unwrap().insert(
+            id,
+            StreamClient {
+                id,
+                transport: transport.to_owned(),
+                requester: requester.clone(),
+                connected_at: Utc::now(),
+                subscription: TagSubscription::default(),
+                sent: 0,
+                resyncs: 0,
+            },
+        );
+        StreamGuard {
+            registry: registry.clone(),
+            id,
+        }
+    }
+
+    pub fn clients(&self) -> Vec<StreamClient> {
+        let mut clients: Vec<StreamClient> =
+            self.clients.lock().unwrap().values().cloned().collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut StreamClient)) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            f(client);
+        }
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.registry.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl TagSubscription {
+    fn from_query(query: &SseQuery) -> Self {
+        let items = |list: &str| -> Vec<String> {
+            list.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_owned)
+                .collect()
+        };
+        Self {
+            tks: items(&query.tks),
+            links: items(&query.links)
+                .iter()
+                .filter_map(|id| id.parse().ok())
+                .collect(),
+        }
+    }
+
+    // Current values of the subscribed tags the user may see.
+    fn snapshot(&self, links: &[Link], user: &AuthUser) -> Vec<TagChange> {
+        let mut snapshot = Vec::new();
+        for link in links {
+            let Some(link_id) = link.id() else {
+                continue;
+            };
+            let whole_link = self.links.contains(&link_id);
+            for tag_index in 0..link.tag_count() {
+                let Some(tag) = link.tag_at(tag_index) else {
+                    continue;
+                };
+                if !whole_link && !self.tks.iter().any(|tk| tk == tag.tk()) {
+                    continue;
+                }
+                if !user.allows_tag(link_id, tag.tk()) {
+                    continue;
+                }
+                snapshot.push(TagChange {
+                    link_id,
+                    tag_id: tag.id(),
+                    tk: tag.tk().to_owned(),
+                    value: tag.value().clone(),
+                    status: tag.status().clone(),
+                    // The time of the snapshot when the value never changed.
+                    timestamp: tag.changed_at().unwrap_or_else(Utc::now),
+                });
+            }
+        }
+        snapshot
+    }
+}
+
+impl TagStream {
+    fn new(user: AuthUser, changes: broadcast::Receiver<TagChange>) -> Self {
+        Self {
+            user,
+            changes,
+            tks: HashSet::new(),
+            links: HashSet::new(),
+            pending: BTreeMap::new(),
+            lagged: false,
+        }
+    }
+
+    fn subscribe(&mut self, subscription: &TagSubscription) {
+        self.tks.extend(subscription.tks.iter().cloned());
+        self.links.extend(subscription.links.iter().copied());
+    }
+
+    fn unsubscribe(&mut self, subscription: &TagSubscription) {
+        for tk in subscription.tks.iter() {
+            self.tks.remove(tk);
+        }
+        for id in subscription.links.iter() {
+            self.links.remove(id);
+        }
+        self.pending.retain(|_, change| {
+            self.tks.contains(&change.tk) || self.links.contains(&change.link_id)
+        });
+    }
+
+    fn subscription(&self) -> TagSubscription {
+        let mut tks: Vec<String> = self.tks.iter().cloned().collect();
+        let mut links: Vec<usize> = self.links.iter().copied().collect();
+        tks.sort();
+        links.sort();
+        TagSubscription { tks, links }
+    }
+
+    fn push(&mut self, change: TagChange) {
+        let subscribed = self.tks.contains(&change.tk) || self.links.contains(&change.link_id);
+        if subscribed && self.user.allows_tag(change.link_id, &change.tk) {
+            self.pending.insert((change.link_id, change.tag_id), change);
+        }
+    }
+
+    /*
+     * Wait for changes and collect them for STREAM_FLUSH. A client that
+     * falls behind the broadcast channel gets a snapshot instead, so its
+     * values are right again without us buffering for it.
+     */
+    async fn next_batch(&mut self, state: &GlobalState) -> StreamMessage {
+        while self.pending.is_empty() && !self.lagged {
+            match self.changes.recv().await {
+                Ok(change) => self.push(change),
+                Err(RecvError::Lagged(_)) => self.lagged = true,
+                Err(RecvError::Closed) => std::future::pending::<()>().await,
+            }
+        }
+        let deadline = time::Instant::now() + STREAM_FLUSH;
+        while !self.lagged {
+            match time::timeout_at(deadline, self.changes.recv()).await {
+                Ok(Ok(change)) => self.push(change),
+                Ok(Err(RecvError::Lagged(_))) => self.lagged = true,
+                Ok(Err(RecvError::Closed)) | Err(_) => break,
+            }
+        }
+        if self.lagged {
+            let snapshot = self
+                .subscription()
+                .snapshot(&state.state_db.lock().await, &self.user);
+            self.lagged = false;
+            self.pending.clear();
+            return StreamMessage::Snapshot(snapshot);
+        }
+        StreamMessage::Changes(std::mem::take(&mut self.pending).into_values().collect())
+    }
+}
+
+impl StreamMessage {
+    fn len(&self) -> u64 {
+        match self {
+            StreamMessage::Snapshot(changes) | StreamMessage::Changes(changes) => {
+                changes.len() as u64
+            }
+            StreamMessage::Error(_) => 0,
+        }
+    }
+}
+
+pub async fn stream_ws(
+    ws: WebSocketUpgrade,
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Viewer)?;
+    Ok(ws.on_upgrade(move |socket| handle_ws(socket, state, user, requester)))
+}
+
+async fn send_ws(socket: &mut WebSocket, message: &StreamMessage) -> bool {
+    let Ok(text) = serde_json::to_string(message) else {
+        return false;
+    };
+    socket.send(Message::Text(text.into())).await.is_ok()
+}
+
+async fn handle_ws(
+    mut socket: WebSocket,
+    state: GlobalState,
+    user: AuthUser,
+    requester: Requester,
+) {
+    let guard = StreamRegistry::register(&state.streams, "ws", &requester);
+    let mut stream = TagStream::new(user.clone(), state.tag_changes.subscribe());
+
+    loop {
+        let message = tokio::select! {
+            received = socket.recv() => match received {
+                Some(Ok(Message::Text(text))) => {
+                    match serde_json::from_str::<StreamRequest>(text.as_str()) {
+                        Ok(StreamRequest::Subscribe(subscription)) => {
+                            stream.subscribe(&subscription);
+                            let snapshot =
+                                subscription.snapshot(&state.state_db.lock().await, &user);
+                            StreamMessage::Snapshot(snapshot)
+                        }
+                        Ok(StreamRequest::Unsubscribe(subscription)) => {
+                            stream.unsubscribe(&subscription);
+                            state
+                                .streams
+                                .update(guard.id, |client| client.subscription = stream.subscription());
+                            continue;
+                        }
+                        Err(e) => StreamMessage::Error(e.to_string()),
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum.
+                Some(Ok(_)) => continue,
+            },
+            message = stream.next_batch(&state) => message,
+        };
+
+        let resync = matches!(message, StreamMessage::Snapshot(_));
+        let sent = message.len();
+        if !send_ws(&mut socket, &message).await {
+            break;
+        }
+        state.streams.update(guard.id, |client| {
+            client.subscription = stream.subscription();
+            client.sent += sent;
+            if resync {
+                client.resyncs += 1;
+            }
+        });
+    }
+    info!("Stream client {} disconnected.", guard.id);
+}
+
+// Server-Sent Events fallback. The subscription is fixed by the query string.
+pub async fn stream_sse(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+    requester: Requester,
+    Query(query): Query<SseQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    user.require(Role::Viewer)?;
+    let subscription = TagSubscription::from_query(&query);
+    let guard = StreamRegistry::register(&state.streams, "sse", &requester);
+    state.streams.update(guard.id, |client| {
+        client.subscription = subscription.clone()
+    });
+    let snapshot = subscription.snapshot(&state.state_db.lock().await, &user);
+    let mut stream = TagStream::new(user, state.tag_changes.subscribe());
+    stream.subscribe(&subscription);
+    let snapshot = StreamMessage::Snapshot(snapshot);
+
+    let events = futures_util::stream::unfold(
+        (stream, state, guard, Some(snapshot)),
+        |(mut stream, state, guard, first)| async move {
+            let message = match first {
+                Some(message) => message,
+                None => stream.next_batch(&state).await,
+            };
+            let sent = message.len();
+            state.streams.update(guard.id, |client| client.sent += sent);
+            let event = Event::default()
+                .json_data(&message)
+                .unwrap_or_else(|_| Event::default().comment("unserializable"));
+            Some((Ok(event), (stream, state, guard, None)))
+        },
+    );
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+pub async fn get_stream_clients(
+    State(state): State<GlobalState>,
+    user: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    user.require(Role::Admin)?;
+    Ok(axum::Json(state.streams.clients()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_links;
+    use crate::{AccessScope, TagStatus, TagValue};
+
+    fn scoped_viewer() -> AuthUser {
+        AuthUser {
+            username: "viewer".to_owned(),
+            role: Role::Viewer,
+            scope: Some(AccessScope {
+                links: vec![1],
+                tks: vec!["LK0:001".to_owned()],
+            }),
+        }
+    }
+
+    fn change(link_id: usize, tag_id: usize, tk: &str) -> TagChange {
+        TagChange {
+            link_id,
+            tag_id,
+            tk: tk.to_owned(),
+            value: TagValue::Real(1.0),
+            status: TagStatus::Normal,
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn tks(changes: &[TagChange]) -> Vec<&str> {
+        changes.iter().map(|change| change.tk.as_str()).collect()
+    }
+
+    #[test]
+    fn snapshots_leave_out_tags_outside_the_scope() {
+        let links = test_links();
+        let subscription = TagSubscription {
+            tks: vec!["LK0:000".to_owned(), "LK0:001".to_owned()],
+            links: vec![1, 2],
+        };
+        assert_eq!(
+            tks(&subscription.snapshot(&links, &scoped_viewer())),
+            vec!["LK0:001", "IN:0", "IN:1"]
+        );
+    }
+
+    #[test]
+    fn streams_drop_changes_outside_the_scope() {
+        let (_sender, receiver) = broadcast::channel(16);
+        let mut stream = TagStream::new(scoped_viewer(), receiver);
+        stream.subscribe(&TagSubscription {
+            tks: vec!["LK0:000".to_owned(), "LK0:001".to_owned()],
+            links: vec![1, 2],
+        });
+        for change in [
+            change(0, 0, "LK0:000"),
+            change(0, 1, "LK0:001"),
+            change(1, 0, "IN:0"),
+            change(2, 0, "EVAL2:000"),
+            change(0, 2, "LK0:002"),
+        ] {
+            stream.push(change);
+        }
+        let pending: Vec<TagChange> = stream.pending.into_values().collect();
+        assert_eq!(tks(&pending), vec!["LK0:001", "IN:0"]);
+    }
+}
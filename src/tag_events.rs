@@ -0,0 +1,17 @@
+use crate::{TagStatus, TagValue};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// Changes a slow subscriber can fall behind by before it starts missing them.
+pub const TAG_CHANGE_CAPACITY: usize = 4096;
+
+// A tag got a new value or status. Published by device polls, input writes and evals.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TagChange {
+    pub link_id: usize,
+    pub tag_id: usize,
+    pub tk: String,
+    pub value: TagValue,
+    pub status: TagStatus,
+    pub timestamp: DateTime<Utc>,
+}
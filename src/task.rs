@@ -1,8 +1,17 @@
 use log::info;
+use std::collections::HashSet;
 use std::time::Duration;
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
 use tokio::time::{self};
 
-use crate::{DeviceLink, EvalLink, Link, LinkStatus, ModbusTcpConfig, Protocol, device_link::Tag};
+use crate::{
+    ALARM_SCAN_MS, AuditEntry, DeviceLink, ESCALATION_CHECK_MS, EvalLink, EvalRuntime, EvalTrigger,
+    EvalWrite, Link, LinkStatus, ModbusTcpConfig, NotificationRouter, Protocol, Requester,
+    TagChange, TagIndex, TagView, device_link::Tag, keep_alarm_status, send_notification,
+};
 use anyhow::Result;
 
 use crate::GlobalState;
@@ -13,6 +22,8 @@ pub enum TaskType {
     Logging,
     Eval,
     ConfigHash,
+    Alarms,
+    Notifications,
 }
 
 pub enum TaskMessage {
@@ -80,7 +91,11 @@ pub async fn handle_link_task(task: Task) {
 
                 loop {
                     // Poll the device.
+                    let had_error = matches!(default_link.status, LinkStatus::Error(_));
                     default_link.poll(&mut link_context).await;
+                    if !had_error && matches!(default_link.status, LinkStatus::Error(_)) {
+                        task.state.metrics.record_link_error(task.id);
+                    }
 
                     // Lock the state and update the link with the polled values.
                     {
@@ -88,6 +103,20 @@ pub async fn handle_link_task(task: Task) {
                         match locked_state {
                             Link::Device(link) => match link.status {
                                 LinkStatus::Normal => {
+                                    for (old, new) in
+                                        link.tags.iter().zip(default_link.tags.iter_mut())
+                                    {
+                                        keep_alarm_status(&mut new.status, &old.status);
+                                        if old.value != new.value {
+                                            new.changed_at = Some(chrono::Utc::now());
+                                        }
+                                        if old.value != new.value || old.status != new.status {
+                                            task.state.publish_tag_change(
+                                                link.id,
+                                                TagView::DeviceTag(new),
+                                            );
+                                        }
+                                    }
                                     *link = default_link.clone();
                                 }
                                 LinkStatus::PendingTagReconfig => {
@@ -119,6 +148,7 @@ pub async fn handle_link_task(task: Task) {
             }
             Err(e) => {
                 info!("Failed to connect: {e}. Task: {}", task.id);
+                task.state.metrics.record_link_error(task.id);
                 let mut locked_state = task.state.state_db.lock().await;
                 match &mut locked_state[task.id] {
                     Link::Device(link) => {
@@ -131,9 +161,71 @@ pub async fn handle_link_task(task: Task) {
         tokio::time::sleep(Duration::from_millis(2000)).await;
     }
 }
-pub async fn handle_logging_task(_task: Task) {
+pub async fn handle_logging_task(task: Task) {
+    let mut default_logger;
+    let mut index = TagIndex::default();
+    let mut needs_index = true;
+    let session = task.state.logger_sessions.get(task.id);
+
     loop {
-        unimplemented!()
+        // Lock the state, pick up the logger config and read the logged values.
+        let samples = {
+            let mut locked_state = task.state.state_db.lock().await;
+            match locked_state.get_mut(task.id) {
+                Some(Link::Logger(config)) => {
+                    if config.status == LinkStatus::PendingTagReconfig {
+                        config.status = LinkStatus::Normal;
+                        needs_index = true;
+                    }
+                    default_logger = config.clone();
+                }
+                _ => {
+                    drop(locked_state);
+                    tokio::time::sleep(Duration::from_millis(2000)).await;
+                    continue;
+                }
+            }
+
+            if needs_index {
+                index = TagIndex::build(&locked_state);
+                needs_index = false;
+            }
+            match default_logger.collect(&locked_state, &index) {
+                Some(samples) => samples,
+                None => {
+                    // The links changed under us, rebuild the index once.
+                    index = TagIndex::build(&locked_state);
+                    default_logger
+                        .collect(&locked_state, &index)
+                        .unwrap_or_default()
+                }
+            }
+        };
+
+        let status = match default_logger
+            .database
+            .write_samples(&mut *session.lock().await, &samples, chrono::Utc::now())
+            .await
+        {
+            Ok(_) => LinkStatus::Normal,
+            Err(e) => {
+                info!("Logger {} failed to write: {e}", default_logger.id);
+                LinkStatus::Error(e.to_string())
+            }
+        };
+        {
+            let mut locked_state = task.state.state_db.lock().await;
+            if let Some(Link::Logger(link)) = locked_state.get_mut(task.id)
+                && link.status != LinkStatus::PendingTagReconfig
+            {
+                link.status = status;
+            }
+        }
+
+        tokio::time::sleep(Duration::from_millis(
+            default_logger.log_delay_millis.max(100) as u64,
+        ))
+        .await;
     }
 }
 pub async fn handle_inputs_task(_task: Task) {
@@ -142,52 +234,261 @@ pub async fn handle_inputs_task(_task: Task) {
 pub async fn handle_hash_task(_task: Task) {
     loop {}
 }
+// What an eval task woke up for.
+enum EvalWake {
+    // Run every eval.
+    All,
+    // Run the evals reading one of these tags.
+    Changed(HashSet<(usize, usize)>),
+    // Nothing changed, only pick up reconfigs.
+    Idle,
+}
+
+/*
+ * Wait until one of the inputs changes or the timeout passes, then take the
+ * changes already queued behind it. Changes of the link's own evals are
+ * ignored, evaluate() handles those within the cycle.
+ */
+async fn wait_for_changes(
+    changes: &mut broadcast::Receiver<TagChange>,
+    link_id: usize,
+    inputs: &HashSet<(usize, usize)>,
+    timeout: Duration,
+) -> EvalWake {
+    let deadline = time::Instant::now() + timeout;
+    let relevant = |change: &TagChange| {
+        change.link_id != link_id && inputs.contains(&(change.link_id, change.tag_id))
+    };
+    let mut changed = HashSet::new();
+    loop {
+        match time::timeout_at(deadline, changes.recv()).await {
+            Ok(Ok(change)) if relevant(&change) => {
+                changed.insert((change.link_id, change.tag_id));
+                break;
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(RecvError::Lagged(_))) => return EvalWake::All,
+            Ok(Err(RecvError::Closed)) | Err(_) => return EvalWake::Idle,
+        }
+    }
+    loop {
+        match changes.try_recv() {
+            Ok(change) if relevant(&change) => {
+                changed.insert((change.link_id, change.tag_id));
+            }
+            Ok(_) => {}
+            Err(TryRecvError::Lagged(_)) => return EvalWake::All,
+            Err(_) => return EvalWake::Changed(changed),
+        }
+    }
+}
+
 pub async fn handle_eval_task(task: Task) {
-    // Store all the eval ASTs here and only update them if triggered by PendingTagReconfig.
-    let mut default_link = EvalLink::new(task.id, "EVAL".to_owned(), 1000);
-    let mut links_list = Vec::new();
-    let mut interval = time::interval(Duration::from_millis(500));
+    // The compiled ASTs live in the runtime and are only rebuilt on PendingTagReconfig.
+    let mut default_link = EvalLink::new(task.id, "EVAL".to_owned(), 0);
+    let mut runtime = EvalRuntime::new();
+    runtime.set_modules(task.state.script_modules.clone());
+    let mut needs_compile = true;
+    let mut changes = task.state.tag_changes.subscribe();
+    let mut inputs: HashSet<(usize, usize)> = HashSet::new();
+    let mut cycle_time_ms = default_link.cycle_time_ms;
+    let mut interval = eval_interval(cycle_time_ms);
 
     interval.tick().await;
 
     loop {
-        // Lock the mutex and update.
+        let cycle = Duration::from_millis(cycle_time_ms);
+        let mut wake = match default_link.trigger {
+            EvalTrigger::Cyclic => {
+                interval.tick().await;
+                EvalWake::All
+            }
+            EvalTrigger::OnChange => wait_for_changes(&mut changes, task.id, &inputs, cycle).await,
+        };
         let now = std::time::Instant::now();
-        interval.tick().await;
+        // Lock the mutex, pick up the config and bind the variable values.
         {
             let mut locked_state = task.state.state_db.lock().await;
-            match &mut locked_state[task.id] {
-                Link::Eval(config) => {
+            match locked_state.get_mut(task.id) {
+                Some(Link::Eval(config)) => {
+                    if config.status == LinkStatus::PendingTagReconfig {
+                        needs_compile = true;
+                    }
                     config.status = LinkStatus::Normal;
                     default_link = config.clone();
                 }
-                _ => {}
+                _ => continue,
             }
 
-            links_list = locked_state.clone();
+            if needs_compile {
+                runtime.compile(&default_link, &locked_state);
+                needs_compile = false;
+                inputs = runtime.inputs();
+                // The formulas may have changed, run everything once.
+                wake = EvalWake::All;
+            }
+            if !runtime.bind(&mut default_link, &locked_state) {
+                // Cached positions went stale, resolve them again.
+                runtime.compile(&default_link, &locked_state);
+                runtime.bind(&mut default_link, &locked_state);
+                inputs = runtime.inputs();
+            }
         }
-
-        for eval in default_link.tags.iter_mut() {
-            eval.evaluate(&links_list);
+        if default_link.cycle_time_ms.max(10) != cycle_time_ms {
+            cycle_time_ms = default_link.cycle_time_ms.max(10);
+            interval = eval_interval(cycle_time_ms);
         }
 
-        let _duration = now.elapsed();
+        match wake {
+            EvalWake::All => runtime.evaluate(&mut default_link, None),
+            EvalWake::Changed(changed) => runtime.evaluate(&mut default_link, Some(changed)),
+            EvalWake::Idle => continue,
+        }
+        let failed = default_link
+            .tags
+            .iter()
+            .filter(|eval| matches!(eval.status, crate::TagStatus::Error(_)))
+            .count();
+        task.state
+            .metrics
+            .record_eval_errors(task.id, failed as u64);
+        default_link.scan_time_us = now.elapsed().as_micros();
 
-        //info!("Evaluation Elapsed time: {}", duration.as_millis());
         {
             // Lock the mutex and update.
-            let locked_link = &mut task.state.state_db.lock().await[task.id];
-            match locked_link {
-                Link::Eval(link) => match link.status {
-                    LinkStatus::PendingTagReconfig => {
-                        continue;
-                    }
-                    _ => {
-                        *locked_link = Link::Eval(default_link.clone());
+            let mut locked_state = task.state.state_db.lock().await;
+            match &locked_state[task.id] {
+                Link::Eval(link) if link.status != LinkStatus::PendingTagReconfig => {
+                    for (old, new) in link.tags.iter().zip(default_link.tags.iter_mut()) {
+                        keep_alarm_status(&mut new.status, &old.status);
+                        if old.value != new.value {
+                            new.changed_at = Some(chrono::Utc::now());
+                        }
+                        if old.value != new.value || old.status != new.status {
+                            task.state
+                                .publish_tag_change(task.id, TagView::EvalTag(new));
+                        }
                     }
-                },
-                _ => {}
+                }
+                _ => continue,
+            }
+            locked_state[task.id] = Link::Eval(default_link.clone());
+            for write in runtime.take_writes() {
+                apply_eval_write(&task.state, &mut locked_state, write);
+            }
+        }
+    }
+}
+
+fn eval_interval(cycle_time_ms: u64) -> time::Interval {
+    let period = Duration::from_millis(cycle_time_ms);
+    let mut interval = time::interval_at(time::Instant::now() + period, period);
+    // Don't run a burst of cycles to catch up after a slow one.
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    interval
+}
+
+/*
+ * Send an eval's write_tag() through the same path as the write API,
+ * including its checks. Rejected writes are logged and dropped.
+ */
+fn apply_eval_write(state: &GlobalState, links: &mut [Link], write: EvalWrite) {
+    let index = TagIndex::build(links);
+    let Some(tag_ref) = index.by_tk(&write.tk) else {
+        info!("Eval {} wrote unknown tag {}.", write.eval_tk, write.tk);
+        return;
+    };
+    let (Some(current), Some((link_id, tag_id))) = (tag_ref.get(links), tag_ref.ids(links)) else {
+        return;
+    };
+    let value = current.value().with_f64(write.value);
+    if let Err(reason) = links[tag_ref.link_index].check_write(tag_ref.tag_index, &value) {
+        info!(
+            "Eval {} can't write tag {}. {reason}",
+            write.eval_tk, write.tk
+        );
+        return;
+    }
+    let Some(old_value) = state.write_tag(links, link_id, tag_id, value.clone()) else {
+        info!("Eval {} can't write tag {}.", write.eval_tk, write.tk);
+        return;
+    };
+    let requester = Requester {
+        user: Some(format!("eval:{}", write.eval_tk)),
+        ip: None,
+    };
+    let mut entry = AuditEntry::new(&requester, "eval_write");
+    entry.link_id = Some(link_id);
+    entry.tag_id = Some(tag_id);
+    entry.old_value = serde_json::to_value(&old_value).ok();
+    entry.new_value = serde_json::to_value(&value).ok();
+    if let Err(e) = state.audit.append(&entry) {
+        info!("Could not write audit entry: {e}");
+    }
+}
+
+// Evaluate the alarm definitions. There is one alarm task, its id is unused.
+pub async fn handle_alarm_task(task: Task) {
+    let mut interval = time::interval(Duration::from_millis(ALARM_SCAN_MS));
+    loop {
+        interval.tick().await;
+        let events = {
+            let mut locked_state = task.state.state_db.lock().await;
+            task.state
+                .alarms
+                .lock()
+                .unwrap()
+                .evaluate(&mut locked_state, std::time::Instant::now())
+        };
+        for event in events {
+            info!(
+                "Alarm {} {:?}. Value: {}",
+                event.alarm.id, event.transition, event.alarm.value
+            );
+            task.state.record_alarm_event(event);
+        }
+    }
+}
+
+// Send alarm events and escalations to the notification channels.
+pub async fn handle_notification_task(task: Task) {
+    let mut events = task.state.alarm_events.subscribe();
+    let mut router = NotificationRouter::default();
+    let mut escalation = time::interval(Duration::from_millis(ESCALATION_CHECK_MS));
+    let http = reqwest::Client::new();
+
+    loop {
+        let dispatches = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let config = task.state.notifications.lock().unwrap().clone();
+                    let time = chrono::Local::now().time();
+                    router.route(&config, &event, std::time::Instant::now(), time)
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    info!("Notifications missed {missed} alarm events.");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = escalation.tick() => {
+                let config = task.state.notifications.lock().unwrap().clone();
+                let alarms = task.state.alarms.lock().unwrap().alarms();
+                let time = chrono::Local::now().time();
+                router.escalate(&config, &alarms, chrono::Utc::now(), time)
             }
+        };
+        for dispatch in dispatches {
+            // A slow mail server must not hold up the other channels.
+            let http = http.clone();
+            tokio::spawn(async move {
+                if let Err(e) = send_notification(&http, &dispatch).await {
+                    info!(
+                        "Could not notify {} about {}: {e}",
+                        dispatch.channel.name, dispatch.event.alarm.id
+                    );
+                }
+            });
         }
     }
 }
@@ -209,6 +510,213 @@ pub fn spawn(task: Task) -> Result<()> {
         TaskType::ConfigHash => {
             tokio::spawn(handle_hash_task(task));
         }
+        TaskType::Alarms => {
+            tokio::spawn(handle_alarm_task(task));
+        }
+        TaskType::Notifications => {
+            tokio::spawn(handle_notification_task(task));
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TagValue;
+    use crate::test_support::{http_stand_in, test_links, test_state};
+    use crate::{DataBase, LogTagInfo, LoggerLink, RemoteWriteInfo};
+
+    fn write(tk: &str, value: f64) -> EvalWrite {
+        EvalWrite {
+            eval_tk: "EVAL2:000".to_owned(),
+            tk: tk.to_owned(),
+            value,
+        }
+    }
+
+    #[test]
+    fn eval_writes_go_through_the_write_checks() {
+        let state = test_state("eval-write");
+        let mut links = test_links();
+
+        for (tk, value) in [
+            ("LK0:000", 2.5),
+            ("LK0:002", 1.0),
+            ("LK0:003", 1.0),
+            ("IN:0", 7.0),
+            ("EVAL2:000", 1.0),
+            ("LK9:000", 1.0),
+        ] {
+            apply_eval_write(&state, &mut links, write(tk, value));
+        }
+
+        let Link::Device(device) = &links[0] else {
+            unreachable!()
+        };
+        let pending: Vec<_> = device
+            .tags
+            .iter()
+            .map(|tag| tag.pending_write.clone())
+            .collect();
+        assert_eq!(pending, vec![Some(TagValue::Real(2.5)), None, None, None]);
+        let Link::Inputs(inputs) = &links[1] else {
+            unreachable!()
+        };
+        assert_eq!(inputs.tags[0].value, TagValue::Real(7.0));
+
+        // Only the two accepted writes are audited.
+        let entries: Vec<AuditEntry> = state.audit.read_all().unwrap();
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn logging_task_writes_samples_and_reports_errors() {
+        let (url, mut requests) = http_stand_in(500, "{}").await;
+        let state = test_state("logging-task");
+        let logger = LoggerLink {
+            name: "Log".to_owned(),
+            id: 3,
+            database: DataBase::PrometheusRemoteWrite(RemoteWriteInfo {
+                url,
+                token: String::new(),
+                query_url: String::new(),
+            }),
+            status: LinkStatus::Normal,
+            tags: vec![LogTagInfo {
+                link_id: 1,
+                tag_id: 0,
+            }],
+            log_delay_millis: 100,
+        };
+        state.state_db.lock().await.push(Link::Logger(logger));
+        let task = tokio::spawn(handle_logging_task(Task::new(
+            TaskType::Logging,
+            state.clone(),
+            3,
+        )));
+
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.method, "POST");
+        assert!(!request.body.is_empty());
+        // The next cycle starts after the failed write has been recorded.
+        requests.recv().await.unwrap();
+        task.abort();
+        let locked_state = state.state_db.lock().await;
+        let Link::Logger(logger) = &locked_state[3] else {
+            unreachable!()
+        };
+        assert!(matches!(logger.status, LinkStatus::Error(_)));
+    }
+
+    fn change(link_id: usize, tag_id: usize) -> TagChange {
+        TagChange {
+            link_id,
+            tag_id,
+            tk: format!("T{link_id}:{tag_id}"),
+            value: TagValue::Real(1.0),
+            status: crate::TagStatus::Normal,
+            timestamp: chrono::Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_input_changes_of_other_links() {
+        let (sender, mut changes) = broadcast::channel(16);
+        let inputs = HashSet::from([(1, 0), (0, 0), (2, 0)]);
+        // The link's own eval, a tag that isn't an input, then two inputs.
+        for (link_id, tag_id) in [(2, 0), (1, 1), (1, 0), (0, 0)] {
+            sender.send(change(link_id, tag_id)).unwrap();
+        }
+
+        let wake = wait_for_changes(&mut changes, 2, &inputs, Duration::from_secs(5)).await;
+        let EvalWake::Changed(changed) = wake else {
+            panic!("expected changed inputs");
+        };
+        assert_eq!(changed, HashSet::from([(1, 0), (0, 0)]));
+    }
+
+    #[tokio::test]
+    async fn waking_up_without_input_changes() {
+        let (sender, mut changes) = broadcast::channel(2);
+        let inputs = HashSet::from([(1, 0)]);
+        let timeout = Duration::from_millis(20);
+
+        // Only the link's own changes: nothing to run when the timeout passes.
+        sender.send(change(2, 0)).unwrap();
+        let started = time::Instant::now();
+        let wake = wait_for_changes(&mut changes, 2, &inputs, timeout).await;
+        assert!(matches!(wake, EvalWake::Idle));
+        assert!(started.elapsed() >= timeout);
+
+        // Missed changes could have been inputs, so everything runs.
+        for _ in 0..3 {
+            sender.send(change(1, 1)).unwrap();
+        }
+        let wake = wait_for_changes(&mut changes, 2, &inputs, timeout).await;
+        assert!(matches!(wake, EvalWake::All));
+
+        drop(sender);
+        let wake = wait_for_changes(&mut changes, 2, &inputs, timeout).await;
+        assert!(matches!(wake, EvalWake::Idle));
+    }
+
+    async fn eval_value(state: &GlobalState) -> TagValue {
+        let links = state.state_db.lock().await;
+        let Link::Eval(link) = &links[2] else {
+            unreachable!()
+        };
+        link.tags[0].value.clone()
+    }
+
+    async fn wait_for_value(state: &GlobalState, value: TagValue) {
+        let waited = time::timeout(Duration::from_secs(5), async {
+            while eval_value(state).await != value {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(waited.is_ok(), "the eval never reached {value:?}");
+    }
+
+    // Set an input without publishing the change, then publish a change of `tag_id`.
+    async fn set_input(state: &GlobalState, value: f32, tag_id: usize) {
+        let mut links = state.state_db.lock().await;
+        crate::write_tag(&mut links, 1, 0, TagValue::Real(value));
+        let Link::Inputs(inputs) = &links[1] else {
+            unreachable!()
+        };
+        state.publish_tag_change(1, TagView::InputTag(&inputs.tags[tag_id]));
+    }
+
+    #[tokio::test]
+    async fn on_change_evals_run_when_an_input_changes() {
+        let state = test_state("on-change");
+        {
+            let mut links = state.state_db.lock().await;
+            let Link::Eval(link) = &mut links[2] else {
+                unreachable!()
+            };
+            link.tags[0].formula = r#"tag("IN:0") * 2.0 + 1.0"#.to_owned();
+            link.trigger = EvalTrigger::OnChange;
+            // Far longer than the test, only changes can wake the eval.
+            link.cycle_time_ms = 60_000;
+        }
+        let task = tokio::spawn(handle_eval_task(Task::new(
+            TaskType::Eval,
+            state.clone(),
+            2,
+        )));
+        // The first cycle after compiling runs every eval.
+        wait_for_value(&state, TagValue::Real(1.0)).await;
+
+        // IN:1 isn't read by the formula.
+        set_input(&state, 3.0, 1).await;
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(eval_value(&state).await, TagValue::Real(1.0));
+
+        set_input(&state, 3.0, 0).await;
+        wait_for_value(&state, TagValue::Real(7.0)).await;
+        task.abort();
+    }
+}
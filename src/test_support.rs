@@ -0,0 +1,215 @@
+// Local stand-ins for the services the tests talk to.
+use crate::{
+    DeviceLink, EvalLink, GlobalState, InputsLink, Journal, Link, ModbusRegister, ModbusTcpConfig,
+    Protocol, TagAddress, TagValue,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+// A request received by the HTTP stand-in. Header names are lowercase.
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/*
+ * Answer every request with the given status and JSON body. Returns the base
+ * URL, e.g. "http://127.0.0.1:41234", and the requests as they come in.
+ */
+pub async fn http_stand_in(
+    status: u16,
+    body: &str,
+) -> (String, mpsc::UnboundedReceiver<HttpRequest>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (requests, received) = mpsc::unbounded_channel();
+    let body = body.to_owned();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let Some(request) = read_http_request(&mut stream).await else {
+                continue;
+            };
+            let _ = requests.send(request);
+            let response = format!(
+                "HTTP/1.1 {status} Stand-in\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    (url, received)
+}
+
+async fn read_http_request(stream: &mut TcpStream) -> Option<HttpRequest> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let header_end = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let n = stream.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buf[..n]);
+    };
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_owned();
+    let path = request_line.next()?.to_owned();
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_owned()))
+        .collect();
+    let length: usize = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = data[header_end..].to_vec();
+    while body.len() < length {
+        let n = stream.read(&mut buf).await.ok()?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+    Some(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+// A mail received by the SMTP stub. `data` is everything between DATA and the final dot.
+#[derive(Clone, Debug)]
+pub struct SmtpMail {
+    pub from: String,
+    pub to: Vec<String>,
+    pub data: String,
+}
+
+/*
+ * Accept mail without authentication or TLS and hand it over on the channel.
+ * Returns the port on 127.0.0.1.
+ */
+pub async fn smtp_stand_in() -> (u16, mpsc::UnboundedReceiver<SmtpMail>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (mails, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mails = mails.clone();
+            tokio::spawn(async move {
+                let _ = smtp_session(stream, mails).await;
+            });
+        }
+    });
+    (port, received)
+}
+
+async fn smtp_session(
+    stream: TcpStream,
+    mails: mpsc::UnboundedSender<SmtpMail>,
+) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let mut mail = SmtpMail {
+        from: String::new(),
+        to: Vec::new(),
+        data: String::new(),
+    };
+    write.write_all(b"220 stand-in ESMTP\r\n").await?;
+    while let Some(line) = lines.next_line().await? {
+        let command = line.to_uppercase();
+        let reply: &[u8] = if command.starts_with("EHLO") || command.starts_with("HELO") {
+            b"250 stand-in\r\n"
+        } else if command.starts_with("MAIL FROM:") {
+            mail.from = line[10..].trim().to_owned();
+            b"250 OK\r\n"
+        } else if command.starts_with("RCPT TO:") {
+            mail.to.push(line[8..].trim().to_owned());
+            b"250 OK\r\n"
+        } else if command == "DATA" {
+            write
+                .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                .await?;
+            while let Some(line) = lines.next_line().await? {
+                if line == "." {
+                    break;
+                }
+                mail.data.push_str(&line);
+                mail.data.push('\n');
+            }
+            let _ = mails.send(mail.clone());
+            b"250 OK\r\n"
+        } else if command == "QUIT" {
+            write.write_all(b"221 Bye\r\n").await?;
+            return Ok(());
+        } else {
+            b"250 OK\r\n"
+        };
+        write.write_all(reply).await?;
+    }
+    Ok(())
+}
+
+/*
+ * Links for tests:
+ * - Device link 0 (tks "LK0:000".."LK0:003"): an enabled Real holding register
+ *   at 0, an enabled Int holding register at 2, an enabled coil and a
+ *   disabled holding register.
+ * - Inputs link 1 with two inputs ("IN:0", "IN:1").
+ * - Eval link 2 with one eval ("EVAL2:000").
+ */
+pub fn test_links() -> Vec<Link> {
+    let protocol = Protocol::ModbusTcp(ModbusTcpConfig::new(String::from("127.0.0.1"), 502));
+    let mut device = DeviceLink::new(
+        String::from("Device"),
+        String::from("LK"),
+        0,
+        protocol,
+        4,
+        100,
+    );
+    let registers = [
+        (ModbusRegister::Holding(0), TagValue::Real(0.0), true),
+        (ModbusRegister::Holding(2), TagValue::Int(0), true),
+        (ModbusRegister::Coil(0), TagValue::Bit(false), true),
+        (ModbusRegister::Holding(3), TagValue::Real(0.0), false),
+    ];
+    for (tag, (register, value, enabled)) in device.tags.iter_mut().zip(registers) {
+        tag.address = TagAddress::ModbusAddr(register);
+        tag.value = value;
+        tag.enabled = enabled;
+    }
+    vec![
+        Link::Device(device),
+        Link::Inputs(InputsLink::new(
+            1,
+            String::from("IN"),
+            String::from("Inputs"),
+            2,
+        )),
+        Link::Eval(EvalLink::new(2, String::from("Evals"), 1)),
+    ]
+}
+
+// State holding test_links(), with the journals in a fresh temporary directory.
+pub fn test_state(name: &str) -> GlobalState {
+    let dir = std::env::temp_dir().join(format!("sentinel-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let dir = dir.to_string_lossy();
+    let mut state = GlobalState::new(test_links());
+    state.audit = Arc::new(Journal::new(&dir, "audit", 1 << 20, 2));
+    state.alarm_journal = Arc::new(Journal::new(&dir, "alarms", 1 << 20, 2));
+    state
+}